@@ -0,0 +1,46 @@
+//! Feeds arbitrary bytes through the decoder and, if they decode, the
+//! verifier. Neither should ever panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use scratch_vm_wasm_runtime::{
+    extension::{BlockSignature, BlockType},
+    instruction::decode,
+    verifier::{verify, StoreSizes},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let bytecode = data
+        .chunks_exact(8)
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    if let Ok(instructions) = decode(&bytecode) {
+        let entry_points = [0, instructions.len() / 2];
+        let extensions = [
+            BlockSignature {
+                extension: "fuzz".into(),
+                block: "command".into(),
+                arity: 1,
+                block_type: BlockType::Command,
+            },
+            BlockSignature {
+                extension: "fuzz".into(),
+                block: "reporter".into(),
+                arity: 2,
+                block_type: BlockType::Reporter,
+            },
+        ];
+        let _ = verify(&instructions, &entry_points, None, &[]);
+        let _ = verify(
+            &instructions,
+            &entry_points,
+            Some(StoreSizes {
+                constants: 4,
+                variables: 4,
+                lists: 4,
+            }),
+            &extensions,
+        );
+    }
+});
@@ -0,0 +1,146 @@
+//! Runs arbitrary bytecode against arbitrary stores under an instruction
+//! budget, as a sprite with arbitrary costumes, sounds and position on a stage
+//! with its own. Whatever the input, `run_instructions` should return
+//! (possibly with an error) rather than panic, and memory use should stay
+//! bounded by the budget.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use scratch_vm_wasm_runtime::{
+    host::HostCallResult,
+    instruction::decode,
+    limits::lists_size,
+    looks::Costume,
+    runner::{run_instructions, Budget, StandaloneContext},
+    scratch_value::ScratchValue,
+    sound::Sound,
+    target::Target,
+};
+
+/// Enough to get through loops, but few enough that a string doubling every
+/// instruction can't reach the length limit too many times over.
+const INSTRUCTION_BUDGET: u64 = 2048;
+
+#[derive(Arbitrary, Debug)]
+enum Value {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+}
+
+impl From<Value> for ScratchValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Boolean(value) => ScratchValue::Boolean(value),
+            Value::Number(value) => ScratchValue::Number(value),
+            Value::String(value) => ScratchValue::String(value),
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct TargetInput {
+    x: f64,
+    y: f64,
+    direction: f64,
+    /// Names, widths and heights.
+    costumes: Vec<(String, f64, f64)>,
+    costume: u8,
+    /// Names, sample counts and rates.
+    sounds: Vec<(String, u32, u32)>,
+    pen_down: bool,
+}
+
+impl TargetInput {
+    fn into_target(self, id: u32, is_stage: bool) -> Target {
+        let mut target = Target::new(id, format!("Target{}", id), is_stage);
+        target.motion.x = self.x;
+        target.motion.y = self.y;
+        target.motion.direction = self.direction;
+        let looks = &mut target.looks;
+        looks.costumes = self
+            .costumes
+            .into_iter()
+            .map(|(name, width, height)| Costume {
+                name,
+                width,
+                height,
+            })
+            .collect();
+        // The host never picks a costume that isn't there
+        looks.costume = (self.costume as usize)
+            .checked_rem(looks.costumes.len())
+            .unwrap_or(0);
+        target.sound.sounds = self
+            .sounds
+            .into_iter()
+            .map(|(name, sample_count, rate)| Sound {
+                name,
+                sample_count,
+                rate,
+            })
+            .collect();
+        target.pen.down = self.pen_down;
+        target
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    bytecode: Vec<u64>,
+    constants: Vec<Value>,
+    variables: Vec<Value>,
+    lists: Vec<Vec<Value>>,
+    stack: Vec<Value>,
+    program_counter: u8,
+    /// What the host hands back from every `CALL_HOST`.
+    host_result: Option<Value>,
+    stage: TargetInput,
+    sprite: TargetInput,
+}
+
+fn convert(values: Vec<Value>) -> Vec<ScratchValue> {
+    values.into_iter().map(Into::into).collect()
+}
+
+fuzz_target!(|input: Input| {
+    let instructions = match decode(&input.bytecode) {
+        Ok(instructions) => instructions,
+        Err(_) => return,
+    };
+    let constants = convert(input.constants);
+    let mut variables = convert(input.variables);
+    let mut lists = input.lists.into_iter().map(convert).collect::<Vec<_>>();
+    let mut stack = convert(input.stack);
+    let mut program_counter = input.program_counter as usize;
+    let host_result = input.host_result.map(ScratchValue::from);
+    let mut context = StandaloneContext {
+        list_size: lists_size(&lists),
+        targets: vec![
+            input.stage.into_target(0, true),
+            input.sprite.into_target(1, false),
+        ],
+        current_target: Some(1),
+        ..StandaloneContext::default()
+    };
+    let mut host = |_id: u32, _arguments: Vec<ScratchValue>| {
+        Ok(match &host_result {
+            Some(value) => HostCallResult::Value(value.clone()),
+            None => HostCallResult::Pending,
+        })
+    };
+    let _ = run_instructions(
+        &mut program_counter,
+        &mut stack,
+        &instructions,
+        &constants,
+        &mut variables,
+        &mut lists,
+        &mut host,
+        &mut Budget::new(Some(INSTRUCTION_BUDGET), None),
+        &mut context.context(),
+        None,
+    );
+});
@@ -0,0 +1,178 @@
+use std::{collections::HashMap, fmt};
+
+use crate::instruction::{Instruction, InstructionType};
+
+/// Why some assembly was rejected, and on which (1-based) line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Assembly failed (line {}): {}", self.line, self.message)
+    }
+}
+
+fn parse_integer(argument: &str) -> Option<u32> {
+    let (negative, digits) = match argument.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, argument),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    let value = if negative { -value } else { value };
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return None;
+    }
+    // Negative numbers are stored as their two's complement, like the jumps
+    // expect
+    Some(value as u32)
+}
+
+/// Turns the textual form of bytecode used in the docs into instructions.
+///
+/// Each line holds one instruction, a mnemonic followed by an optional
+/// argument (0 if left out). Arguments are integers (decimal or `0x` hex,
+/// possibly negative), except for `LOAD_CONST_FLOAT`, which takes a float,
+/// and `LOAD_CONST_BOOL`, which takes `true` or `false`. Extension blocks are
+/// `EXTENSION` with the block's index. Anything after a `;` is a comment.
+///
+/// A line like `loop:` labels the next instruction, and `@loop` can then be
+/// used as the argument of a jump to get the relative offset to it.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, AssembleError> {
+    let mnemonics = InstructionType::ALL
+        .iter()
+        .chain(&[InstructionType::Extension])
+        .map(|instruction| (instruction.mnemonic(), *instruction))
+        .collect::<HashMap<_, _>>();
+
+    // Strip comments and find the labels first, so that jumps can go forwards
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    for (line_number, line) in source.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            if labels.insert(label, lines.len()).is_some() {
+                return Err(AssembleError {
+                    line: line_number + 1,
+                    message: format!("label `{}` is defined twice", label),
+                });
+            }
+        } else {
+            lines.push((line_number + 1, line));
+        }
+    }
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(program_counter, (line_number, line))| {
+            let error = |message: String| AssembleError {
+                line: *line_number,
+                message,
+            };
+            let mut parts = line.split_whitespace();
+            let mnemonic = parts.next().unwrap_or_default();
+            let name = *mnemonics
+                .get(mnemonic)
+                .ok_or_else(|| error(format!("unknown instruction `{}`", mnemonic)))?;
+            let argument = match parts.next() {
+                None => 0,
+                Some(argument) => {
+                    let parsed = if let Some(label) = argument.strip_prefix('@') {
+                        // The runner adds one after jumping
+                        labels.get(label).map(|target| {
+                            (*target as i64 - program_counter as i64 - 1) as i32 as u32
+                        })
+                    } else {
+                        match name {
+                            InstructionType::LoadConstFloat => {
+                                argument.parse::<f32>().ok().map(f32::to_bits)
+                            }
+                            InstructionType::LoadConstBool => match argument {
+                                "true" => Some(1),
+                                "false" => Some(0),
+                                _ => None,
+                            },
+                            _ => parse_integer(argument),
+                        }
+                    };
+                    parsed.ok_or_else(|| error(format!("bad argument `{}`", argument)))?
+                }
+            };
+            if let Some(extra) = parts.next() {
+                return Err(error(format!("unexpected `{}`", extra)));
+            }
+            Ok(Instruction::new(name, argument))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments() {
+        let instructions = assemble(
+            "
+            ; comments and blank lines are ignored
+            LOAD_CONST_INT -1
+            LOAD_CONST_FLOAT 1.5
+            LOAD_CONST_BOOL true
+            CALL_HOST 2
+            EXTRA_ARG 0x10001 ; trailing comment
+            NOOP
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::new(InstructionType::LoadConstInt, 0xffffffff),
+                Instruction::new(InstructionType::LoadConstFloat, 1.5f32.to_bits()),
+                Instruction::new(InstructionType::LoadConstBool, 1),
+                Instruction::new(InstructionType::CallHost, 2),
+                Instruction::new(InstructionType::ExtraArg, 0x10001),
+                Instruction::new(InstructionType::Noop, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let instructions = assemble(
+            "
+            NOOP
+            top:
+            JUMP @end
+            JUMP @top
+            end:
+            NOOP
+            ",
+        )
+        .unwrap();
+        assert_eq!(instructions[1].argument, 1);
+        assert_eq!(instructions[2].argument as i32, -2);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("NOOP\nFROBNICATE").unwrap_err(),
+            AssembleError {
+                line: 2,
+                message: "unknown instruction `FROBNICATE`".into()
+            }
+        );
+        assert_eq!(assemble("JUMP @nowhere").unwrap_err().line, 1);
+        assert_eq!(assemble("LOAD_CONST_BOOL yes").unwrap_err().line, 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! Runs a compiled program headlessly, e.g. for testing Scratch projects in
+//! CI without a browser. It's built with the `cli` feature
+//! (`cargo run --features cli --bin scratch-vm-run -- ...`):
+//!
+//! ```sh
+//! scratch-vm-run [--max-ticks N] [--seed N] [--turbo] [--budget N] <program.json | project.sb3>
+//! ```
+//!
+//! A compiled program is a JSON file like this, with the bytecode either as
+//! numbers or in the assembly from the docs:
+//!
+//! ```json
+//! {
+//!   "assembly": ["LOAD_CONST 0", "CALL_HOST 0", "EXTRA_ARG 1"],
+//!   "constants": ["Hello!"],
+//!   "variables": [{ "name": "score", "value": 0 }],
+//!   "lists": [{ "name": "items", "value": [] }],
+//!   "targets": [{ "name": "Sprite1", "scripts": [0] }],
+//!   "host_functions": { "say": 0, "think": 1 }
+//! }
+//! ```
+//!
+//! Every script (a program counter) of every target is started, like when
+//! the green flag is clicked, and the scheduler runs them at 30 ticks a
+//! second on a fake clock until they all finish or `--max-ticks` (a minute's
+//! worth by default) have run. With `--turbo`, the threads keep taking turns
+//! in each tick until they've run `--budget` instructions between them
+//! (100000 by default), rather than stopping at a repaint. Speech and thought bubbles are printed as
+//! they're shown, whether by the looks blocks or by the host functions named
+//! `say` and `think`, as are the values of the variables with visible
+//! monitors whenever they change. The final variables and lists are printed
+//! as JSON at the end.
+//!
+//! `.sb3` projects are compiled first with the command in the
+//! `SCRATCH_VM_COMPILER` environment variable, which is given the project's
+//! path and should print the compiled program.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    process::{Command, ExitCode},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use scratch_vm_wasm_runtime::{
+    assembler::assemble,
+    clock::ManualClock,
+    host::{HostCallHandler, HostCallResult},
+    instruction::{decode, Instruction},
+    random::SeededRandom,
+    runtime::Runtime,
+    scratch_value::{js_number_to_string, ScratchValue},
+    verifier::{verify, StoreSizes},
+};
+
+/// Scratch's frame rate.
+const TICK_MILLISECONDS: f64 = 1000.0 / 30.0;
+
+/// How many instructions a tick runs in turbo mode if `--budget` isn't given,
+/// since the fake clock never runs out of time.
+const TURBO_INSTRUCTION_BUDGET: u64 = 100_000;
+
+const USAGE: &str =
+    "usage: scratch-vm-run [--max-ticks N] [--seed N] [--turbo] [--budget N] <program.json | project.sb3>";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Program {
+    #[serde(default)]
+    bytecode: Option<Vec<u64>>,
+    #[serde(default)]
+    assembly: Option<Vec<String>>,
+    #[serde(default)]
+    constants: Vec<Value>,
+    #[serde(default)]
+    variables: Vec<Store<Value>>,
+    #[serde(default)]
+    lists: Vec<Store<Vec<Value>>>,
+    targets: Vec<ProgramTarget>,
+    #[serde(default)]
+    host_functions: HashMap<String, u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Store<T> {
+    name: String,
+    value: T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProgramTarget {
+    name: String,
+    #[serde(default)]
+    is_stage: bool,
+    #[serde(default)]
+    scripts: Vec<usize>,
+}
+
+struct Options {
+    path: String,
+    max_ticks: u64,
+    seed: u64,
+    turbo_mode: bool,
+    instruction_budget: Option<u64>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut path = None;
+    let mut max_ticks = 60 * 30;
+    let mut seed = 0;
+    let mut turbo_mode = false;
+    let mut instruction_budget = None;
+    let mut arguments = env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        let mut number = |name: &str| -> Result<u64, String> {
+            arguments
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or(format!("{} needs a number\n{}", name, USAGE))
+        };
+        match argument.as_str() {
+            "--max-ticks" => max_ticks = number("--max-ticks")?,
+            "--seed" => seed = number("--seed")?,
+            "--turbo" => turbo_mode = true,
+            "--budget" => instruction_budget = Some(number("--budget")?),
+            "--help" | "-h" => return Err(USAGE.into()),
+            _ if path.is_none() && !argument.starts_with("--") => path = Some(argument),
+            _ => return Err(format!("unexpected argument {}\n{}", argument, USAGE)),
+        }
+    }
+    Ok(Options {
+        path: path.ok_or(USAGE)?,
+        max_ticks,
+        seed,
+        turbo_mode,
+        instruction_budget: instruction_budget
+            .or_else(|| turbo_mode.then_some(TURBO_INSTRUCTION_BUDGET)),
+    })
+}
+
+/// Reads a compiled program, compiling it first if it's a project.
+fn load_program(path: &str) -> Result<Program, String> {
+    let source = if path.ends_with(".sb3") {
+        let compiler = env::var("SCRATCH_VM_COMPILER").map_err(|_| {
+            "compiling .sb3 projects needs a compiler; set SCRATCH_VM_COMPILER to one".to_string()
+        })?;
+        let output = Command::new(&compiler)
+            .arg(path)
+            .output()
+            .map_err(|err| format!("failed to run the compiler: {}", err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "the compiler failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        String::from_utf8(output.stdout)
+            .map_err(|err| format!("the compiler's output isn't UTF-8: {}", err))?
+    } else {
+        fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?
+    };
+    serde_json::from_str(&source).map_err(|err| format!("invalid program: {}", err))
+}
+
+fn to_scratch(value: &Value) -> Result<ScratchValue, String> {
+    match value {
+        Value::Bool(value) => Ok(ScratchValue::Boolean(*value)),
+        Value::Number(value) => Ok(ScratchValue::Number(value.as_f64().unwrap_or(f64::NAN))),
+        Value::String(value) => Ok(ScratchValue::String(value.clone())),
+        _ => Err(format!("{} isn't a Scratch value", value)),
+    }
+}
+
+fn to_json(value: &ScratchValue) -> Value {
+    match value {
+        ScratchValue::Boolean(value) => Value::Bool(*value),
+        // Whole numbers are written without a `.0`, like JS would
+        ScratchValue::Number(value) if value.fract() == 0.0 && value.abs() < 2f64.powi(53) => {
+            Value::from(*value as i64)
+        }
+        // JSON can't express NaN and the infinities, so they're written as
+        // JS would print them
+        ScratchValue::Number(value) => serde_json::Number::from_f64(*value)
+            .map_or_else(|| Value::String(js_number_to_string(*value)), Value::Number),
+        ScratchValue::String(value) => Value::String(value.clone()),
+    }
+}
+
+/// Prints what sprites say and think, and fails any other call.
+struct PrintingHost {
+    say: Option<u32>,
+    think: Option<u32>,
+}
+
+impl HostCallHandler for PrintingHost {
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        let verb = if Some(id) == self.say {
+            "say"
+        } else if Some(id) == self.think {
+            "think"
+        } else {
+            return Err("no host function registered with that id");
+        };
+        let message = arguments
+            .iter()
+            .map(|argument| argument.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("{}: {}", verb, message);
+        Ok(HostCallResult::Value(ScratchValue::EMPTY))
+    }
+}
+
+fn run(options: &Options) -> Result<Value, String> {
+    let program = load_program(&options.path)?;
+    let instructions: Vec<Instruction> = match (&program.bytecode, &program.assembly) {
+        (Some(bytecode), None) => decode(bytecode).map_err(|err| err.to_string())?,
+        (None, Some(assembly)) => assemble(&assembly.join("\n")).map_err(|err| err.to_string())?,
+        _ => return Err("the program needs either `bytecode` or `assembly`".into()),
+    };
+    let constants = program
+        .constants
+        .iter()
+        .map(to_scratch)
+        .collect::<Result<Vec<_>, _>>()?;
+    let variables = program
+        .variables
+        .iter()
+        .map(|variable| to_scratch(&variable.value))
+        .collect::<Result<Vec<_>, _>>()?;
+    let lists = program
+        .lists
+        .iter()
+        .map(|list| list.value.iter().map(to_scratch).collect())
+        .collect::<Result<Vec<_>, _>>()?;
+    let entry_points = program
+        .targets
+        .iter()
+        .flat_map(|target| target.scripts.iter().copied())
+        .collect::<Vec<_>>();
+    verify(
+        &instructions,
+        &entry_points,
+        Some(StoreSizes {
+            constants: constants.len(),
+            variables: variables.len(),
+            lists: lists.len(),
+        }),
+        &[],
+    )
+    .map_err(|err| err.to_string())?;
+
+    let mut runtime = Runtime::new(instructions, constants, variables, lists);
+    let clock = ManualClock::default();
+    runtime.set_clock(Box::new(clock.clone()));
+    runtime.set_random(Box::new(SeededRandom::new(options.seed)));
+    runtime.set_host(Box::new(PrintingHost {
+        say: program.host_functions.get("say").copied(),
+        think: program.host_functions.get("think").copied(),
+    }));
+    runtime.turbo_mode = options.turbo_mode;
+    runtime.instruction_budget = options.instruction_budget;
+    for target in program.targets.iter() {
+        let id = runtime.add_target(target.name.clone(), target.is_stage);
+        for script in target.scripts.iter() {
+            runtime.start_thread(id, *script);
+        }
+    }
+
+    let mut monitored = HashMap::new();
+    let mut ticks = 0;
+    while !runtime.threads.is_empty() && ticks < options.max_ticks {
+        runtime.step()?;
+        ticks += 1;
+        clock.advance(TICK_MILLISECONDS);
+        // Hidden bubbles have nothing to print
+        for bubble in runtime.take_bubble_changes() {
+            if !bubble.text.is_empty() {
+                println!("{}: {}", bubble.kind.name(), bubble.text);
+            }
+        }
+        // Only visible monitors are printed, and only when they change
+        monitored.retain(|index, _| runtime.monitors.variables.contains(index));
+        for index in runtime.monitors.variables.iter() {
+            let value = &runtime.variables[*index as usize];
+            if monitored.get(index) != Some(value) {
+                println!(
+                    "monitor {}: {}",
+                    program.variables[*index as usize].name,
+                    value.as_str()
+                );
+                monitored.insert(*index, value.clone());
+            }
+        }
+    }
+    if !runtime.threads.is_empty() {
+        eprintln!(
+            "stopped after {} ticks with {} threads still running",
+            ticks,
+            runtime.threads.len()
+        );
+    }
+
+    let variables = program
+        .variables
+        .iter()
+        .zip(runtime.variables.iter())
+        .map(|(variable, value)| (variable.name.clone(), to_json(value)))
+        .collect::<Map<_, _>>();
+    let lists = program
+        .lists
+        .iter()
+        .zip(runtime.lists.iter())
+        .map(|(list, items)| {
+            (
+                list.name.clone(),
+                Value::Array(items.iter().map(to_json).collect()),
+            )
+        })
+        .collect::<Map<_, _>>();
+    Ok(json!({ "variables": variables, "lists": lists }))
+}
+
+fn main() -> ExitCode {
+    let result = parse_options().and_then(|options| run(&options));
+    match result {
+        Ok(state) => {
+            println!("{}", state);
+            // Nothing useful can be done if stdout is gone
+            let _ = std::io::stdout().flush();
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
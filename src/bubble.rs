@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+use crate::scratch_value::{js_number_to_string, ScratchValue};
+
+/// How long a bubble's text can be, in UTF-16 code units like JS counts.
+const MAX_BUBBLE_LENGTH: usize = 330;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BubbleKind {
+    Say,
+    Think,
+}
+
+impl BubbleKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            BubbleKind::Say => "say",
+            BubbleKind::Think => "think",
+        }
+    }
+}
+
+/// A target's speech or thought bubble. There's no bubble when the text is
+/// empty.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bubble {
+    pub kind: BubbleKind,
+    pub text: String,
+    /// Goes up every time the bubble changes, so a timed "say" only clears
+    /// the bubble if nothing else has replaced it since (scratch-vm's
+    /// `usageId`).
+    pub usage: u32,
+    /// Set whenever the bubble changes, so the host can be told at the end of
+    /// a tick.
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+impl Default for Bubble {
+    fn default() -> Self {
+        Self {
+            kind: BubbleKind::Say,
+            text: String::new(),
+            usage: 0,
+            changed: false,
+        }
+    }
+}
+
+/// A bubble that changed, for the host to draw. An empty `text` means the
+/// bubble should be hidden.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BubbleEvent {
+    pub target: u32,
+    pub kind: BubbleKind,
+    pub text: String,
+}
+
+/// JS's `Number.prototype.toFixed(2)`, which rounds ties away from zero
+/// where Rust's formatting rounds them to even.
+fn to_fixed_2(value: f64) -> String {
+    if value.abs() >= 1e21 {
+        return js_number_to_string(value);
+    }
+    // Only multiples of 1/8 can be exactly halfway between two hundredths
+    let eighths = value * 8.0;
+    if eighths.fract() == 0.0 && eighths % 2.0 != 0.0 {
+        format!("{:.2}", (value * 100.0).round() / 100.0)
+    } else {
+        format!("{:.2}", value)
+    }
+}
+
+/// Implements scratch-vm's `_formatBubbleText`: fractional numbers are shown
+/// to two decimal places, and long text is cut short.
+pub fn format_bubble_text(message: &ScratchValue) -> String {
+    let text = match message {
+        ScratchValue::Number(value) if value.abs() >= 0.01 && value.fract() != 0.0 => {
+            to_fixed_2(*value)
+        }
+        _ => message.as_str().into_owned(),
+    };
+    let mut length = 0;
+    text.chars()
+        .take_while(|c| {
+            length += c.len_utf16();
+            length <= MAX_BUBBLE_LENGTH
+        })
+        .collect()
+}
+
+impl Bubble {
+    /// Shows `message` in a bubble, or hides the bubble if it's empty.
+    pub fn update(&mut self, kind: BubbleKind, message: &ScratchValue) {
+        self.kind = kind;
+        self.text = format_bubble_text(message);
+        self.usage = self.usage.wrapping_add(1);
+        self.changed = true;
+    }
+
+    /// Hides the bubble, but only if it hasn't changed since `usage`.
+    pub fn clear_if_unchanged(&mut self, usage: u32) {
+        if self.usage == usage {
+            self.update(BubbleKind::Say, &ScratchValue::EMPTY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bubble_text_is_formatted_like_scratch() {
+        for (message, text) in [
+            (ScratchValue::Number(1.0), "1"),
+            (ScratchValue::Number(0.005), "0.005"),
+            (ScratchValue::Number(2.0 / 3.0), "0.67"),
+            (ScratchValue::Number(0.125), "0.13"),
+            (ScratchValue::Number(-2.375), "-2.38"),
+            (ScratchValue::Number(1.005), "1.00"),
+            (ScratchValue::Boolean(true), "true"),
+        ] {
+            assert_eq!(format_bubble_text(&message), text);
+        }
+        let long = ScratchValue::String("é".repeat(400));
+        assert_eq!(format_bubble_text(&long).chars().count(), 330);
+    }
+
+    #[test]
+    fn timed_bubbles_only_clear_themselves() {
+        let mut bubble = Bubble::default();
+        bubble.update(BubbleKind::Say, &ScratchValue::String("Hi".into()));
+        let usage = bubble.usage;
+        bubble.update(BubbleKind::Think, &ScratchValue::String("Hmm".into()));
+        bubble.clear_if_unchanged(usage);
+        assert_eq!(bubble.text, "Hmm");
+        bubble.clear_if_unchanged(bubble.usage);
+        assert_eq!(bubble.text, "");
+    }
+}
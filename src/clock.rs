@@ -0,0 +1,47 @@
+use std::{cell::Cell, rc::Rc};
+
+use chrono::Utc;
+
+/// A source of time for the scheduler, in milliseconds. Injected so that
+/// waits can be tested (and replayed) without actually waiting.
+pub trait Clock {
+    fn now(&self) -> f64;
+}
+
+/// The real wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        Utc::now().timestamp_millis() as f64
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so one
+/// can be handed to the runtime and the other kept to advance it.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    time: Rc<Cell<f64>>,
+}
+
+impl ManualClock {
+    pub fn new(time: f64) -> Self {
+        Self {
+            time: Rc::new(Cell::new(time)),
+        }
+    }
+
+    pub fn set(&self, time: f64) {
+        self.time.set(time);
+    }
+
+    pub fn advance(&self, milliseconds: f64) {
+        self.time.set(self.time.get() + milliseconds);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> f64 {
+        self.time.get()
+    }
+}
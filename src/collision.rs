@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+
+use crate::motion::{RotationStyle, STAGE_HEIGHT, STAGE_WIDTH};
+use crate::pen::PenLayer;
+use crate::target::Target;
+
+/// Answers the "touching" blocks, which need to know what each target looks
+/// like. The runtime has already left out the hidden targets, so a provider
+/// only has to work out the geometry. Targets are passed by their index in
+/// `targets`.
+pub trait CollisionProvider {
+    /// Whether the target at `index` touches any of the targets at `others`.
+    fn touching_targets(&self, targets: &[Target], index: usize, others: &[usize]) -> bool;
+
+    /// Whether the target at `index` covers the point (`x`, `y`) on the
+    /// stage.
+    fn touching_point(&self, targets: &[Target], index: usize, x: f64, y: f64) -> bool;
+
+    /// Whether any of the target at `index` is over `color` (`[r, g, b]`) in
+    /// what's drawn behind and in front of it, which is the targets at
+    /// `others` from the front to the back, with `pen` between the sprites
+    /// and the stage.
+    fn touching_color(
+        &self,
+        targets: &[Target],
+        index: usize,
+        others: &[usize],
+        pen: &PenLayer,
+        color: [u8; 3],
+    ) -> bool;
+
+    /// The target at `index`'s color at the point (`x`, `y`) on the stage,
+    /// for stamping it onto the pen layer, if it covers the point. Providers
+    /// that don't know the costumes' pixels can't stamp.
+    fn pixel(&self, _targets: &[Target], _index: usize, _x: f64, _y: f64) -> Option<[u8; 4]> {
+        None
+    }
+}
+
+/// A box on the stage, in stage coordinates (y up).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rectangle {
+    left: f64,
+    right: f64,
+    bottom: f64,
+    top: f64,
+}
+
+impl Rectangle {
+    /// Like scratch-render's `Rectangle.intersects`, touching edges count.
+    fn intersects(&self, other: &Rectangle) -> bool {
+        self.left <= other.right
+            && other.left <= self.right
+            && self.bottom <= other.top
+            && other.bottom <= self.top
+    }
+
+    fn intersection(&self, other: &Rectangle) -> Rectangle {
+        Rectangle {
+            left: self.left.max(other.left),
+            right: self.right.min(other.right),
+            bottom: self.bottom.max(other.bottom),
+            top: self.top.min(other.top),
+        }
+    }
+
+    fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.left && x <= self.right && y >= self.bottom && y <= self.top
+    }
+
+    /// The whole stage points inside the rectangle, after growing it out to
+    /// whole numbers like scratch-render's `snapToInt`.
+    fn points(&self) -> impl Iterator<Item = (f64, f64)> {
+        let (left, right) = (self.left.floor() as i32, self.right.ceil() as i32);
+        let (bottom, top) = (self.bottom.floor() as i32, self.top.ceil() as i32);
+        (left..=right).flat_map(move |x| (bottom..=top).map(move |y| (x as f64, y as f64)))
+    }
+}
+
+/// How much the target is turned clockwise (in degrees) and whether it's
+/// flipped, going by its rotation style.
+fn rotation(target: &Target) -> (f64, bool) {
+    let motion = &target.motion;
+    if target.is_stage {
+        return (0.0, false);
+    }
+    match motion.rotation_style {
+        RotationStyle::AllAround => (motion.direction - 90.0, false),
+        RotationStyle::LeftRight => (0.0, motion.direction < 0.0),
+        RotationStyle::DontRotate => (0.0, false),
+    }
+}
+
+/// Checks for collisions with the axis-aligned boxes around the targets: the
+/// bounds the renderer gave the runtime, or, until it has, the current
+/// costume's size turned and scaled around the target's position. Colors
+/// can't be checked without the costumes' pixels, so nothing ever touches a
+/// color. This is the runtime's default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoundingBoxCollision;
+
+impl BoundingBoxCollision {
+    fn bounds(target: &Target) -> Option<Rectangle> {
+        let motion = &target.motion;
+        let bounds = motion.bounds;
+        if bounds != Default::default() {
+            return Some(Rectangle {
+                left: motion.x + bounds.left,
+                right: motion.x + bounds.right,
+                bottom: motion.y + bounds.bottom,
+                top: motion.y + bounds.top,
+            });
+        }
+        let costume = target.looks.costumes.get(target.looks.costume)?;
+        if costume.width <= 0.0 || costume.height <= 0.0 {
+            return None;
+        }
+        let scale = target.looks.size / 100.0;
+        let radians = rotation(target).0.to_radians();
+        let (sin, cos) = (radians.sin().abs(), radians.cos().abs());
+        let half_width = (costume.width * cos + costume.height * sin) * scale / 2.0;
+        let half_height = (costume.width * sin + costume.height * cos) * scale / 2.0;
+        Some(Rectangle {
+            left: motion.x - half_width,
+            right: motion.x + half_width,
+            bottom: motion.y - half_height,
+            top: motion.y + half_height,
+        })
+    }
+}
+
+impl CollisionProvider for BoundingBoxCollision {
+    fn touching_targets(&self, targets: &[Target], index: usize, others: &[usize]) -> bool {
+        let bounds = match Self::bounds(&targets[index]) {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        others.iter().any(|other| {
+            Self::bounds(&targets[*other]).is_some_and(|other| bounds.intersects(&other))
+        })
+    }
+
+    fn touching_point(&self, targets: &[Target], index: usize, x: f64, y: f64) -> bool {
+        Self::bounds(&targets[index]).is_some_and(|bounds| bounds.contains(x, y))
+    }
+
+    fn touching_color(
+        &self,
+        _: &[Target],
+        _: usize,
+        _: &[usize],
+        _: &PenLayer,
+        _: [u8; 3],
+    ) -> bool {
+        false
+    }
+}
+
+/// A costume's pixels, for checking collisions with them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CostumeMask {
+    pub width: u32,
+    pub height: u32,
+    /// The point the costume is positioned by and turns around, in pixels
+    /// from its top left.
+    pub rotation_center: (f64, f64),
+    /// How many pixels there are to a stage pixel at 100% size: 2 for
+    /// Scratch's high resolution bitmaps, and 1 otherwise.
+    pub resolution: f64,
+    /// `[r, g, b, a]`, row by row from the top left. Pixels count as part of
+    /// the costume if they aren't completely transparent.
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl CostumeMask {
+    /// The pixel at (`x`, `y`) (from the top left), if it's in the costume.
+    fn pixel(&self, x: f64, y: f64) -> Option<[u8; 4]> {
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return None;
+        }
+        self.pixels
+            .get(y as usize * self.width as usize + x as usize)
+            .copied()
+    }
+}
+
+/// Checks for collisions pixel by pixel using the costumes' masks, which are
+/// loaded once for each sprite (and shared by its clones), like
+/// scratch-render's CPU collision checks. Targets without a mask for their
+/// current costume don't touch anything.
+#[derive(Clone, Debug, Default)]
+pub struct BitmapCollision {
+    /// By sprite ID (clones use the original sprite's) and costume index.
+    masks: HashMap<(u32, usize), CostumeMask>,
+}
+
+/// Whether two colors match as far as "touching color?" is concerned, which
+/// only looks at the top 5 bits of red and green and 4 bits of blue, like
+/// scratch-render.
+fn colors_match(a: [u8; 3], b: [u8; 3]) -> bool {
+    (a[0] & 0b1111_1000) == (b[0] & 0b1111_1000)
+        && (a[1] & 0b1111_1000) == (b[1] & 0b1111_1000)
+        && (a[2] & 0b1111_0000) == (b[2] & 0b1111_0000)
+}
+
+impl BitmapCollision {
+    /// Gives the `costume`th costume of the sprite `sprite` its mask.
+    pub fn add_mask(&mut self, sprite: u32, costume: usize, mask: CostumeMask) {
+        self.masks.insert((sprite, costume), mask);
+    }
+
+    fn mask(&self, target: &Target) -> Option<&CostumeMask> {
+        let sprite = target.clone_of.unwrap_or(target.id);
+        self.masks.get(&(sprite, target.looks.costume))
+    }
+
+    /// How many of the mask's pixels there are to a stage pixel, going by the
+    /// target's size.
+    fn scale(target: &Target, mask: &CostumeMask) -> Option<f64> {
+        let size = if target.is_stage {
+            100.0
+        } else {
+            target.looks.size
+        };
+        (size > 0.0).then(|| mask.resolution * 100.0 / size)
+    }
+
+    /// The box on the stage that the target's costume covers.
+    fn bounds(&self, target: &Target) -> Option<Rectangle> {
+        let mask = self.mask(target)?;
+        let scale = Self::scale(target, mask)?;
+        let (angle, flipped) = rotation(target);
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (center_x, center_y) = mask.rotation_center;
+        let corners = [
+            (0.0, 0.0),
+            (mask.width as f64, 0.0),
+            (0.0, mask.height as f64),
+            (mask.width as f64, mask.height as f64),
+        ];
+        let mut bounds = Rectangle {
+            left: f64::INFINITY,
+            right: f64::NEG_INFINITY,
+            bottom: f64::INFINITY,
+            top: f64::NEG_INFINITY,
+        };
+        for (x, y) in corners {
+            let mut local_x = (x - center_x) / scale;
+            let local_y = (center_y - y) / scale;
+            if flipped {
+                local_x = -local_x;
+            }
+            // Turned clockwise
+            let x = target.motion.x + local_x * cos + local_y * sin;
+            let y = target.motion.y - local_x * sin + local_y * cos;
+            bounds.left = bounds.left.min(x);
+            bounds.right = bounds.right.max(x);
+            bounds.bottom = bounds.bottom.min(y);
+            bounds.top = bounds.top.max(y);
+        }
+        Some(bounds)
+    }
+
+    /// The target's pixel at the point (`x`, `y`) on the stage, if it covers
+    /// it.
+    fn pixel_at(&self, target: &Target, x: f64, y: f64) -> Option<[u8; 4]> {
+        let mask = self.mask(target)?;
+        let scale = Self::scale(target, mask)?;
+        let (angle, flipped) = rotation(target);
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (dx, dy) = (x - target.motion.x, y - target.motion.y);
+        // Turned back anticlockwise
+        let mut local_x = dx * cos - dy * sin;
+        let local_y = dx * sin + dy * cos;
+        if flipped {
+            local_x = -local_x;
+        }
+        let (center_x, center_y) = mask.rotation_center;
+        mask.pixel(center_x + local_x * scale, center_y - local_y * scale)
+            .filter(|pixel| pixel[3] > 0)
+    }
+
+    /// The color at (`x`, `y`) with the targets at `others` (from the front
+    /// to the back) and the pen layer blended over the white behind the
+    /// stage, like scratch-render's `sampleColor3b`.
+    fn color_at(
+        &self,
+        targets: &[Target],
+        others: &[usize],
+        pen: &PenLayer,
+        x: f64,
+        y: f64,
+    ) -> [u8; 3] {
+        let mut color = [0.0; 3];
+        let mut coverage = 1.0;
+        let mut add = |pixel: [u8; 4]| {
+            let alpha = pixel[3] as f64 / 255.0;
+            for (channel, value) in color.iter_mut().zip(pixel) {
+                *channel += value as f64 * alpha * coverage;
+            }
+            coverage *= 1.0 - alpha;
+        };
+        let mut pen = pen.pixel_at(x, y);
+        for other in others {
+            let other = &targets[*other];
+            // The pen layer is just in front of the stage
+            if let Some(pixel) = pen.filter(|_| other.is_stage) {
+                add(pixel);
+                pen = None;
+            }
+            if let Some(pixel) = self.pixel_at(other, x, y) {
+                add(pixel);
+            }
+        }
+        if let Some(pixel) = pen {
+            add(pixel);
+        }
+        color.map(|channel| (channel + coverage * 255.0).round() as u8)
+    }
+}
+
+impl CollisionProvider for BitmapCollision {
+    fn touching_targets(&self, targets: &[Target], index: usize, others: &[usize]) -> bool {
+        let target = &targets[index];
+        let bounds = match self.bounds(target) {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        others.iter().any(|other| {
+            let other = &targets[*other];
+            match self.bounds(other) {
+                Some(other_bounds) if bounds.intersects(&other_bounds) => {
+                    bounds.intersection(&other_bounds).points().any(|(x, y)| {
+                        self.pixel_at(target, x, y).is_some()
+                            && self.pixel_at(other, x, y).is_some()
+                    })
+                }
+                _ => false,
+            }
+        })
+    }
+
+    fn touching_point(&self, targets: &[Target], index: usize, x: f64, y: f64) -> bool {
+        self.pixel_at(&targets[index], x, y).is_some()
+    }
+
+    fn touching_color(
+        &self,
+        targets: &[Target],
+        index: usize,
+        others: &[usize],
+        pen: &PenLayer,
+        color: [u8; 3],
+    ) -> bool {
+        let target = &targets[index];
+        let stage = Rectangle {
+            left: -STAGE_WIDTH / 2.0,
+            right: STAGE_WIDTH / 2.0,
+            bottom: -STAGE_HEIGHT / 2.0,
+            top: STAGE_HEIGHT / 2.0,
+        };
+        let bounds = match self.bounds(target) {
+            Some(bounds) if bounds.intersects(&stage) => bounds.intersection(&stage),
+            _ => return false,
+        };
+        bounds.points().any(|(x, y)| {
+            self.pixel_at(target, x, y).is_some()
+                && colors_match(self.color_at(targets, others, pen, x, y), color)
+        })
+    }
+
+    fn pixel(&self, targets: &[Target], index: usize, x: f64, y: f64) -> Option<[u8; 4]> {
+        self.pixel_at(&targets[index], x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::looks::Costume;
+
+    /// A sprite with a 20x20 costume that's red on the left and transparent
+    /// on the right.
+    fn half_red_sprite(id: u32, x: f64, y: f64) -> Target {
+        let mut target = Target::new(id, format!("Sprite{}", id), false);
+        target.motion.x = x;
+        target.motion.y = y;
+        target.looks.costumes.push(Costume {
+            name: "half".into(),
+            width: 20.0,
+            height: 20.0,
+        });
+        target
+    }
+
+    fn half_red_mask() -> CostumeMask {
+        CostumeMask {
+            width: 20,
+            height: 20,
+            rotation_center: (10.0, 10.0),
+            resolution: 1.0,
+            pixels: (0..400)
+                .map(|index| {
+                    if index % 20 < 10 {
+                        [255, 0, 0, 255]
+                    } else {
+                        [0, 0, 0, 0]
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn bounding_boxes_come_from_the_costume_size() {
+        let mut targets = [half_red_sprite(1, 0.0, 0.0), half_red_sprite(2, 16.0, 0.0)];
+        let collision = BoundingBoxCollision;
+        assert!(collision.touching_targets(&targets, 0, &[1]));
+        assert!(collision.touching_point(&targets, 0, 10.0, -10.0));
+        assert!(!collision.touching_point(&targets, 0, 10.5, 0.0));
+        targets[1].looks.size = 50.0;
+        assert!(!collision.touching_targets(&targets, 0, &[1]));
+        // Bounds from the renderer win
+        targets[0].motion.bounds.right = 20.0;
+        assert!(collision.touching_targets(&targets, 0, &[1]));
+    }
+
+    #[test]
+    fn masks_are_checked_pixel_by_pixel() {
+        let mut collision = BitmapCollision::default();
+        collision.add_mask(1, 0, half_red_mask());
+        collision.add_mask(2, 0, half_red_mask());
+        let mut targets = [half_red_sprite(1, 0.0, 0.0), half_red_sprite(2, 15.0, 0.0)];
+        // The second sprite's red half is over the first one's transparent
+        // half
+        assert!(!collision.touching_targets(&targets, 0, &[1]));
+        assert!(collision.touching_point(&targets, 0, -5.0, 0.0));
+        assert!(!collision.touching_point(&targets, 0, 5.0, 0.0));
+        let pen = PenLayer::default();
+        assert!(!collision.touching_color(&targets, 0, &[1], &pen, [255, 0, 0]));
+
+        // Facing left turns the red half to the right
+        targets[0].motion.direction = -90.0;
+        assert!(collision.touching_targets(&targets, 0, &[1]));
+        assert!(collision.touching_color(&targets, 0, &[1], &pen, [255, 0, 0]));
+        targets[0].motion.rotation_style = RotationStyle::LeftRight;
+        assert!(collision.touching_targets(&targets, 0, &[1]));
+        targets[0].motion.rotation_style = RotationStyle::DontRotate;
+        assert!(!collision.touching_targets(&targets, 0, &[1]));
+
+        // Clones share the original's masks, and there's white behind
+        // everything
+        let mut clone = half_red_sprite(3, 100.0, 0.0);
+        clone.clone_of = Some(1);
+        let targets = [clone];
+        assert!(collision.touching_point(&targets, 0, 95.0, 0.0));
+        assert!(collision.touching_color(&targets, 0, &[], &pen, [255, 255, 255]));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeSet;
+
+use crate::instruction::InstructionType;
+#[cfg(feature = "profiling")]
+use crate::profiler::Profiler;
+use crate::scratch_value::ScratchValue;
+
+/// Everything the trace hook gets told about an instruction after it runs.
+#[derive(Debug)]
+pub struct TraceEvent<'a> {
+    pub program_counter: usize,
+    pub instruction: InstructionType,
+    pub argument: u32,
+    pub stack_before: &'a [ScratchValue],
+    pub stack_after: &'a [ScratchValue],
+}
+
+pub type TraceHook = Box<dyn FnMut(&TraceEvent)>;
+
+/// Breakpoints, single-stepping and tracing for `run_instructions`. When it
+/// decides to pause, the runner returns `DebugPause` with the program counter
+/// left on the instruction that hasn't run yet.
+#[derive(Default)]
+pub struct Debugger {
+    /// Program counters to pause before running.
+    pub breakpoints: BTreeSet<usize>,
+    /// Pause before every instruction. The runtime only does this for one
+    /// thread at a time, `Runtime::stepping_thread`.
+    pub single_step: bool,
+    /// Called after every instruction that runs. Since the stack has to be
+    /// copied for it, it slows things down a lot.
+    pub trace_hook: Option<TraceHook>,
+    /// Counts and times every instruction that runs.
+    #[cfg(feature = "profiling")]
+    pub profiler: Option<Profiler>,
+    skip_next_pause: bool,
+    /// Set by the runtime while it runs the threads that aren't being stepped.
+    pub(crate) ignore_single_step: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the next instruction run even if it would pause, so that execution
+    /// can carry on from a breakpoint (or by one instruction, when
+    /// single-stepping).
+    pub fn resume(&mut self) {
+        self.skip_next_pause = true;
+    }
+
+    #[inline]
+    pub(crate) fn should_pause(&self, program_counter: usize) -> bool {
+        !self.skip_next_pause
+            && ((self.single_step && !self.ignore_single_step)
+                || self.breakpoints.contains(&program_counter))
+    }
+
+    /// Called when an instruction is about to run, so that the ones after it
+    /// can pause again. Until then, `resume` carries over however many times
+    /// the budget runs out first.
+    #[inline]
+    pub(crate) fn running(&mut self) {
+        self.skip_next_pause = false;
+    }
+
+    /// Starts timing an instruction, if profiling.
+    #[cfg(feature = "profiling")]
+    #[inline]
+    pub(crate) fn profile_start(&self) -> Option<f64> {
+        self.profiler.as_ref().map(Profiler::now)
+    }
+
+    #[cfg(feature = "profiling")]
+    #[inline]
+    pub(crate) fn profile_end(
+        &mut self,
+        started: f64,
+        program_counter: usize,
+        instruction: InstructionType,
+        stack_depth: usize,
+    ) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            let time = profiler.now() - started;
+            profiler.record(program_counter, instruction, stack_depth, time);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_tracing(&self) -> bool {
+        self.trace_hook.is_some()
+    }
+
+    #[inline]
+    pub(crate) fn trace(&mut self, event: &TraceEvent) {
+        if let Some(trace_hook) = self.trace_hook.as_mut() {
+            trace_hook(event);
+        }
+    }
+}
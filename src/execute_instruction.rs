@@ -1,16 +1,40 @@
-use std::{convert::TryInto, ops::IndexMut};
+use std::{
+    cmp::Ordering,
+    convert::{TryFrom, TryInto},
+    f64::consts::PI,
+    ops::IndexMut,
+};
 
 use chrono::prelude::*;
 
 use crate::{
-    instruction::{Instruction, InstructionType},
-    scratch_value::ScratchValue,
+    bubble::BubbleKind,
+    clock::Clock,
+    extension::BlockType,
+    host::{
+        HostCallHandler, HostCallResult, HOST_CALL_ARGUMENT_COUNT, HOST_CALL_AWAIT,
+        HOST_CALL_REPORTER,
+    },
+    instruction::{Instruction, InstructionType, ReturnReason},
+    limits::item_size,
+    looks::{restack, GraphicEffect, LayerPosition},
+    motion::{limit_precision, Motion, RotationStyle},
+    pen::{ColorParam, PenLayer},
+    runner::Context,
+    scratch_value::{ListIndex, ScratchValue},
+    sensing::{attribute_of, distance_to, touching_color, touching_object},
+    sound::SoundEffect,
+    target::Target,
 };
 
+/// The longest string V8 allows, in bytes rather than UTF-16 code units.
+#[cfg(feature = "safety_checks")]
+const MAX_STRING_LENGTH: usize = (1 << 29) - 24;
+
 /// Check an index against an array and see if it is out of bounds, but only
 /// when the compiler option is enabled.
 #[inline(always)]
-fn bounds_check<T, I>(vec: &Vec<T>, idx: I) -> Result<(), &'static str>
+fn bounds_check<T, I>(vec: &[T], idx: I) -> Result<(), &'static str>
 where
     I: TryInto<usize>,
 {
@@ -18,7 +42,7 @@ where
     if idx.try_into().map_err(|_| "can't convert index")? >= vec.len() {
         return Err("index out of bounds");
     }
-    return Ok(());
+    Ok(())
 }
 
 #[inline]
@@ -26,13 +50,136 @@ fn pop_stack(stack: &mut Vec<ScratchValue>) -> Result<ScratchValue, &'static str
     stack.pop().ok_or("nothing on the stack to pop")
 }
 
-fn scratch_find(list: &Vec<ScratchValue>, term: &str) -> usize {
+/// Reads the clock as a date and time in the local time zone.
+fn local_time(clock: &dyn Clock) -> Result<DateTime<Local>, &'static str> {
+    Local
+        .timestamp_millis_opt(clock.now() as i64)
+        .single()
+        .ok_or("the clock's time isn't a valid date")
+}
+
+/// Finds the one-based index of the first item equal to `term` by Scratch's
+/// rules, or 0 if there isn't one.
+fn scratch_find(list: &[ScratchValue], term: &ScratchValue) -> usize {
     list.iter()
-        // The call to `clone` may be a bottleneck -- is there any more efficient way?
-        .position(|item| term.eq_ignore_ascii_case(&Into::<String>::into(item.clone())))
+        .position(|item| item.compare(term) == Ordering::Equal)
         .map_or(0, |a| a + 1)
 }
 
+/// Implements the list reporter: items are joined with nothing if they're all
+/// single-character strings, and with spaces otherwise.
+fn list_contents(list: &[ScratchValue]) -> String {
+    let all_single_characters = list.iter().all(|item| match item {
+        // A single UTF-16 code unit, since that's what JS's `length` counts
+        ScratchValue::String(item) => item.encode_utf16().count() == 1,
+        _ => false,
+    });
+    let separator = if all_single_characters { "" } else { " " };
+    let mut contents = String::new();
+    for (index, item) in list.iter().enumerate() {
+        if index > 0 {
+            contents.push_str(separator);
+        }
+        contents.push_str(&item.as_str());
+    }
+    contents
+}
+
+// The trig functions below do their arithmetic in the same order as
+// scratch-vm so the results match to the last bit.
+
+/// Rounds the result of `sin` or `cos` to 10 decimal places like Scratch
+/// does, so that e.g. `sin 180` is 0 instead of 1.2e-16.
+fn round_trig(value: f64) -> f64 {
+    // JS's `Math.round` rounds halves up rather than away from zero
+    (value * 1e10 + 0.5).floor() / 1e10
+}
+
+/// JS's `parseFloat(value.toFixed(10))`, which rounds ties away from zero
+/// where Rust's formatting rounds them to even.
+fn to_fixed_10(value: f64) -> f64 {
+    if !value.is_finite() || value.abs() >= 1e21 {
+        return value;
+    }
+    if value == 0.0 {
+        // `toFixed` drops the sign of -0
+        return 0.0;
+    }
+    // Only odd multiples of 1/2048 can be exactly halfway between two
+    // multiples of 1e-10, and those are rounded in integers to stay exact
+    let halves = value * 2048.0;
+    if halves.fract() == 0.0 && halves % 2.0 != 0.0 {
+        let tenths = (halves as i128 * 5i128.pow(10) + halves.signum() as i128) / 2;
+        format!("{}e-10", tenths).parse().unwrap_or(value)
+    } else {
+        format!("{:.10}", value).parse().unwrap_or(value)
+    }
+}
+
+/// Scratch's tangent, which gives infinities where the tangent is undefined
+/// instead of huge numbers.
+fn scratch_tan(degrees: f64) -> f64 {
+    match degrees % 360.0 {
+        x if x == -270.0 || x == 90.0 => f64::INFINITY,
+        x if x == -90.0 || x == 270.0 => f64::NEG_INFINITY,
+        x => to_fixed_10((PI * x / 180.0).tan()),
+    }
+}
+
+/// Finds the index of the target running the instruction, for instructions
+/// that look at the other targets too.
+#[inline]
+fn current_index(targets: &[Target], current_target: Option<usize>) -> Result<usize, &'static str> {
+    current_target
+        .filter(|index| *index < targets.len())
+        .ok_or("no target to run the instruction on")
+}
+
+/// Finds the target running the instruction, which the motion instructions
+/// act on.
+#[inline]
+fn current(
+    targets: &mut [Target],
+    current_target: Option<usize>,
+) -> Result<&mut Target, &'static str> {
+    current_target
+        .and_then(move |index| targets.get_mut(index))
+        .ok_or("no target to run the instruction on")
+}
+
+/// Updates the current target with `update`, unless it's the stage (which
+/// doesn't move, resize or hide).
+#[inline]
+fn update_sprite(
+    targets: &mut [Target],
+    current_target: Option<usize>,
+    update: impl FnOnce(&mut Target),
+) -> Result<(), &'static str> {
+    let target = current(targets, current_target)?;
+    if !target.is_stage {
+        update(target);
+    }
+    Ok(())
+}
+
+/// Moves the current sprite with `update`, drawing a line from where it was
+/// if its pen is down, like the pen extension does whenever a sprite moves.
+#[inline]
+fn move_sprite(
+    targets: &mut [Target],
+    current_target: Option<usize>,
+    pen_layer: &mut PenLayer,
+    update: impl FnOnce(&mut Motion),
+) -> Result<(), &'static str> {
+    update_sprite(targets, current_target, |target| {
+        let from = (target.motion.x, target.motion.y);
+        update(&mut target.motion);
+        if target.pen.down {
+            pen_layer.draw_line(&target.pen, from, (target.motion.x, target.motion.y));
+        }
+    })
+}
+
 /// Executes the instruction given by the argument, along with the stack,
 /// constants, etc.
 ///
@@ -40,19 +187,39 @@ fn scratch_find(list: &Vec<ScratchValue>, term: &str) -> usize {
 ///
 /// Panics if the ID for a constant/variable/list is out of bounds. The compiler
 /// should know better than that.
+#[allow(clippy::too_many_arguments)]
 pub fn execute_instruction<F, G>(
     instruction: &Instruction,
     stack: &mut Vec<ScratchValue>,
-    constants: &Vec<ScratchValue>,
-    variables: &mut Vec<ScratchValue>,
-    lists: &mut Vec<Vec<ScratchValue>>,
+    constants: &[ScratchValue],
+    variables: &mut [ScratchValue],
+    lists: &mut [Vec<ScratchValue>],
+    context: &mut Context<'_>,
     jmp_consume_extra_arg: &mut F,
     return_control: &mut G,
+    host: &mut dyn HostCallHandler,
 ) -> Result<(), &'static str>
 where
     F: FnMut(isize) -> Option<u32>,
-    G: FnMut(u32) -> (),
+    G: FnMut(u32),
 {
+    let Context {
+        list_limits,
+        list_size,
+        monitors,
+        targets,
+        current_target,
+        io,
+        collision,
+        pen_layer,
+        clock,
+        random,
+    } = context;
+    let (list_limits, io, collision, clock) = (*list_limits, *io, *collision, *clock);
+    let (targets, current_target, pen_layer): (&mut [Target], _, &mut PenLayer) =
+        (targets, *current_target, pen_layer);
+    let list_size: &mut usize = list_size;
+    let random = &mut || random.random();
     match &instruction.name {
         InstructionType::Noop => Ok(()),
         InstructionType::ExtraArg => Err("Found ExtraArg where none was required"),
@@ -60,23 +227,18 @@ where
             // Load a constant from the constants, falling back on an empty
             // string, and push it to the stack.
             #[cfg(feature = "safety_checks")]
-            bounds_check(&constants, instruction.argument)?;
+            bounds_check(constants, instruction.argument)?;
             stack.push(constants[instruction.argument as usize].clone());
             Ok(())
         }
         InstructionType::LoadConstInt => {
-            stack.push(
-                ScratchValue::Number(
-                    unsafe { std::mem::transmute::<_, i32>(instruction.argument) }.into(),
-                )
-                .into(),
-            );
+            stack.push(ScratchValue::Number((instruction.argument as i32).into()));
             Ok(())
         }
         InstructionType::LoadConstFloat => {
-            stack.push(ScratchValue::Number(unsafe {
-                std::mem::transmute::<_, f32>(instruction.argument)
-            } as f64));
+            stack.push(ScratchValue::Number(
+                f32::from_bits(instruction.argument) as f64
+            ));
             Ok(())
         }
         InstructionType::LoadConstBool => {
@@ -86,30 +248,30 @@ where
         InstructionType::Load => {
             // Load a variable with the same schematics as above.
             #[cfg(feature = "safety_checks")]
-            bounds_check(&variables, instruction.argument)?;
+            bounds_check(variables, instruction.argument)?;
             stack.push(variables[instruction.argument as usize].clone());
             Ok(())
         }
         InstructionType::Store => {
             // Pop the top of the stack and store it
             #[cfg(feature = "safety_checks")]
-            bounds_check(&variables, instruction.argument)?;
+            bounds_check(variables, instruction.argument)?;
             variables[instruction.argument as usize] = pop_stack(stack)?;
             Ok(())
         }
         InstructionType::Jump => {
             // Jump by the argument, which is a i32, so we need to reinterpret
-            // it as so using unsafe Rust.
-            let offset = unsafe { std::mem::transmute::<_, i32>(instruction.argument) };
+            // it as so.
+            let offset = instruction.argument as i32;
             jmp_consume_extra_arg(offset as isize);
             Ok(())
         }
         InstructionType::JumpIf => {
             // Jump by the argument if the top of the stack is truthful
-            // Need same unsafe code as above, since the argument can be
+            // Need same reinterpretation as above, since the argument can be
             // negative
             if pop_stack(stack)?.into() {
-                let offset = unsafe { std::mem::transmute::<_, i32>(instruction.argument) };
+                let offset = instruction.argument as i32;
                 jmp_consume_extra_arg(offset as isize);
             }
             Ok(())
@@ -117,20 +279,18 @@ where
         InstructionType::AllocList => {
             // Get the list from the map
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
+            bounds_check(lists, instruction.argument)?;
             let list = lists.index_mut(instruction.argument as usize);
             // Load the extra argument
             let additional_elements =
                 jmp_consume_extra_arg(1).ok_or("ALLOC_LIST missing extra arg")?;
-            // Check if allocation is too big, but only if safety_checks is
-            // enabled
-            #[cfg(feature = "safety_checks")]
-            if additional_elements > 200_000 {
-                return Err("allocation exceeds list limit");
-            }
+            // Never reserve more than the list could ever hold. This is only
+            // a hint, so it's up to the instructions that add items to fail
+            // when the list is full
+            let room = list_limits.max_items_per_list.saturating_sub(list.len());
             // Attempt to allocate the vector, but if not possible, then
             // ignore the error
-            let _ = list.try_reserve(additional_elements as usize);
+            let _ = list.try_reserve((additional_elements as usize).min(room));
             Ok(())
         }
         InstructionType::OpAdd => {
@@ -196,140 +356,149 @@ where
         }
         InstructionType::UnarySin => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().sin(),
-            ));
+            stack.push(ScratchValue::Number(round_trig(
+                (PI * Into::<f64>::into(op) / 180.0).sin(),
+            )));
             Ok(())
         }
         InstructionType::UnaryCos => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().cos(),
-            ));
+            stack.push(ScratchValue::Number(round_trig(
+                (PI * Into::<f64>::into(op) / 180.0).cos(),
+            )));
             Ok(())
         }
         InstructionType::UnaryTan => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().tan(),
-            ));
+            stack.push(ScratchValue::Number(scratch_tan(op.into())));
             Ok(())
         }
         InstructionType::UnaryAsin => {
             let op = pop_stack(stack)?;
             stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().asin(),
+                Into::<f64>::into(op).asin() * 180.0 / PI,
             ));
             Ok(())
         }
         InstructionType::UnaryAcos => {
             let op = pop_stack(stack)?;
             stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().acos(),
+                Into::<f64>::into(op).acos() * 180.0 / PI,
             ));
             Ok(())
         }
         InstructionType::UnaryAtan => {
             let op = pop_stack(stack)?;
             stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().atan(),
+                Into::<f64>::into(op).atan() * 180.0 / PI,
             ));
             Ok(())
         }
         InstructionType::UnaryLn => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().ln(),
-            ));
+            stack.push(ScratchValue::Number(Into::<f64>::into(op).ln()));
             Ok(())
         }
         InstructionType::UnaryLog => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                Into::<f64>::into(op).to_radians().log10(),
-            ));
+            stack.push(ScratchValue::Number(Into::<f64>::into(op).log10()));
             Ok(())
         }
         InstructionType::UnaryEPow => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                std::f64::consts::E.powf(Into::<f64>::into(op).to_radians()),
-            ));
+            stack.push(ScratchValue::Number(Into::<f64>::into(op).exp()));
             Ok(())
         }
         InstructionType::Unary10Pow => {
             let op = pop_stack(stack)?;
-            stack.push(ScratchValue::Number(
-                10.0_f64.powf(Into::<f64>::into(op).to_radians()),
-            ));
+            stack.push(ScratchValue::Number(10.0_f64.powf(op.into())));
             Ok(())
         }
         InstructionType::OpLt => {
             let lhs = pop_stack(stack)?;
             let rhs = pop_stack(stack)?;
-            stack.push(ScratchValue::Boolean(
-                Into::<f64>::into(lhs) < Into::<f64>::into(rhs),
-            ));
+            stack.push(ScratchValue::Boolean(lhs.compare(&rhs) == Ordering::Less));
             Ok(())
         }
-        InstructionType::Reserved => todo!(),
+        InstructionType::Reserved => Err("found reserved instruction"),
         InstructionType::OpEq => {
             let lhs = pop_stack(stack)?;
             let rhs = pop_stack(stack)?;
-            stack.push(ScratchValue::Boolean(
-                Into::<f64>::into(lhs) == Into::<f64>::into(rhs),
-            ));
+            stack.push(ScratchValue::Boolean(lhs.compare(&rhs) == Ordering::Equal));
             Ok(())
         }
         InstructionType::ListDel => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
+            bounds_check(lists, instruction.argument)?;
             let list = lists.index_mut(instruction.argument as usize);
-            let index = Into::<f64>::into(pop_stack(stack)?) as usize - 1;
-            if index < list.len() {
-                // If the index is out of bounds, no-op just like Scratch does
-                list.remove(index);
+            let index = pop_stack(stack)?;
+            // If the index is out of bounds, no-op just like Scratch does
+            match index.to_list_index(list.len(), true, random) {
+                ListIndex::Index(index) => {
+                    *list_size = list_size.saturating_sub(item_size(&list.remove(index)));
+                }
+                ListIndex::All => {
+                    *list_size = list_size.saturating_sub(list.iter().map(item_size).sum());
+                    list.clear();
+                }
+                ListIndex::Invalid => {}
             }
             Ok(())
         }
         InstructionType::ListIns => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
-            let list = lists.index_mut(instruction.argument as usize);
+            bounds_check(lists, instruction.argument)?;
+            let list_index = instruction.argument as usize;
             let element = pop_stack(stack)?;
-            let index = Into::<f64>::into(pop_stack(stack)?) as usize - 1;
-            if index <= list.len() {
-                // If the index is out of bounds, no-op just like Scratch does
-                list.insert(index, element);
+            let index = pop_stack(stack)?;
+            // One past the end is allowed, so that "last" appends
+            if let ListIndex::Index(index) =
+                index.to_list_index(lists[list_index].len() + 1, false, random)
+            {
+                let size = *list_size + item_size(&element);
+                if list_limits.allow_growth(lists[list_index].len() + 1, size)? {
+                    lists[list_index].insert(index, element);
+                    *list_size = size;
+                }
             }
             Ok(())
         }
         InstructionType::ListDelAll => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
+            bounds_check(lists, instruction.argument)?;
             let list = lists.index_mut(instruction.argument as usize);
+            *list_size = list_size.saturating_sub(list.iter().map(item_size).sum());
             list.clear();
             // TODO: Deallocate vector? How?
             Ok(())
         }
         InstructionType::ListReplace => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
+            bounds_check(lists, instruction.argument)?;
             let list = lists.index_mut(instruction.argument as usize);
             let element = pop_stack(stack)?;
-            let index = Into::<f64>::into(pop_stack(stack)?) as usize - 1;
-            if index < list.len() {
-                // If the index is out of bounds, no-op just like Scratch does
-                list[index] = element;
+            let index = pop_stack(stack)?;
+            if let ListIndex::Index(index) = index.to_list_index(list.len(), false, random) {
+                let (old_size, new_size) = (item_size(&list[index]), item_size(&element));
+                let size = (*list_size + new_size).saturating_sub(old_size);
+                // Only a bigger item needs to fit
+                if new_size <= old_size || list_limits.allow_growth(list.len(), size)? {
+                    list[index] = element;
+                    *list_size = size;
+                }
             }
             Ok(())
         }
         InstructionType::ListPush => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
-            let list = lists.index_mut(instruction.argument as usize);
+            bounds_check(lists, instruction.argument)?;
             let element = pop_stack(stack)?;
-            list.push(element);
+            let list = lists.index_mut(instruction.argument as usize);
+            let size = *list_size + item_size(&element);
+            if list_limits.allow_growth(list.len() + 1, size)? {
+                list.push(element);
+                *list_size = size;
+            }
             Ok(())
         }
         InstructionType::ListLoad => {
@@ -337,17 +506,16 @@ where
             let list = lists
                 .get(instruction.argument as usize)
                 .ok_or("failed to find list")?;
-            let index = Into::<f64>::into(pop_stack(stack)?) as usize - 1;
-            stack.push(if index < list.len() {
-                list[index].clone()
-            } else {
-                ScratchValue::EMPTY
+            let index = pop_stack(stack)?;
+            stack.push(match index.to_list_index(list.len(), false, random) {
+                ListIndex::Index(index) => list[index].clone(),
+                _ => ScratchValue::EMPTY,
             });
             Ok(())
         }
         InstructionType::ListLen => {
             #[cfg(feature = "safety_checks")]
-            bounds_check(&lists, instruction.argument)?;
+            bounds_check(lists, instruction.argument)?;
             stack.push(ScratchValue::Number(
                 lists[instruction.argument as usize].len() as f64,
             ));
@@ -358,8 +526,8 @@ where
             let list = lists
                 .get(instruction.argument as usize)
                 .ok_or("failed to find list")?;
-            let term: String = pop_stack(stack)?.into();
-            stack.push(ScratchValue::Number(scratch_find(&list, &term) as f64));
+            let term = pop_stack(stack)?;
+            stack.push(ScratchValue::Number(scratch_find(list, &term) as f64));
             Ok(())
         }
         InstructionType::ListIIncludes => {
@@ -367,64 +535,82 @@ where
             let list = lists
                 .get(instruction.argument as usize)
                 .ok_or("failed to find list")?;
-            let term: String = pop_stack(stack)?.into();
-            stack.push(ScratchValue::Boolean(scratch_find(&list, &term) > 0));
+            let term = pop_stack(stack)?;
+            stack.push(ScratchValue::Boolean(scratch_find(list, &term) > 0));
+            Ok(())
+        }
+        InstructionType::MonitorShowVar | InstructionType::MonitorHideVar => {
+            #[cfg(feature = "safety_checks")]
+            bounds_check(variables, instruction.argument)?;
+            monitors.set_variable_visible(
+                instruction.argument,
+                instruction.name == InstructionType::MonitorShowVar,
+            );
+            Ok(())
+        }
+        InstructionType::MonitorShowList | InstructionType::MonitorHideList => {
+            #[cfg(feature = "safety_checks")]
+            bounds_check(lists, instruction.argument)?;
+            monitors.set_list_visible(
+                instruction.argument,
+                instruction.name == InstructionType::MonitorShowList,
+            );
             Ok(())
         }
-        InstructionType::MonitorShowVar => todo!(),
-        InstructionType::MonitorHideVar => todo!(),
-        InstructionType::MonitorShowList => todo!(),
-        InstructionType::MonitorHideList => todo!(),
         InstructionType::Return => {
             return_control(instruction.argument);
             Ok(())
         }
         InstructionType::OpMod => {
-            let lhs = pop_stack(stack)?;
             let rhs = pop_stack(stack)?;
+            let lhs = pop_stack(stack)?;
             stack.push(lhs % rhs);
             Ok(())
         }
         InstructionType::StringIndexChar => {
-            let index = Into::<f64>::into(pop_stack(stack)?);
+            // Like JS's `charAt`, fractional indices are truncated and the
+            // string is indexed by UTF-16 code units
+            let index = Into::<f64>::into(pop_stack(stack)?) - 1.0;
             let string = Into::<String>::into(pop_stack(stack)?);
-            stack.push(
-                if index % 1.0 == 0.0 && index > 0.0 && (index as usize) <= string.len() {
-                    ScratchValue::String(string[(index as usize - 1)..(index as usize)].to_string())
-                } else {
-                    ScratchValue::EMPTY
-                },
-            );
+            let units = string.encode_utf16().collect::<Vec<_>>();
+            stack.push(if index >= 0.0 && index < units.len() as f64 {
+                ScratchValue::String(String::from_utf16_lossy(&units[index as usize..][..1]))
+            } else {
+                ScratchValue::EMPTY
+            });
             Ok(())
         }
         InstructionType::StringLen => {
             let string = Into::<String>::into(pop_stack(stack)?);
-            stack.push(ScratchValue::Number(string.len() as f64));
+            stack.push(ScratchValue::Number(string.encode_utf16().count() as f64));
             Ok(())
         }
         InstructionType::StringConcat => {
             let rhs = Into::<String>::into(pop_stack(stack)?);
             let mut lhs = Into::<String>::into(pop_stack(stack)?);
+            // JS throws when a string gets this long, and without a limit a
+            // loop that doubles a string can take all the memory there is
+            #[cfg(feature = "safety_checks")]
+            if lhs.len() + rhs.len() > MAX_STRING_LENGTH {
+                return Err("string too long");
+            }
             lhs.push_str(&rhs);
             stack.push(ScratchValue::String(lhs));
             Ok(())
         }
         InstructionType::UnaryRound => {
             let op = Into::<f64>::into(pop_stack(stack)?);
-            stack.push(ScratchValue::Number(op.round()));
+            // JS's `Math.round` rounds halves up rather than away from zero
+            stack.push(ScratchValue::Number((op + 0.5).floor()));
             Ok(())
         }
         InstructionType::DataRand => {
-            if !cfg!(target_arch = "wasm32") {
-                return Err("cannot generate random numbers if not on WASM");
-            }
-
             let max = pop_stack(stack)?;
             let min = pop_stack(stack)?;
             let fractional_part = instruction.argument > 0;
             let num_min = Into::<f64>::into(min);
             let num_max = Into::<f64>::into(max);
-            let rand = js_sys::Math::random();
+            let rand = random();
             stack.push(ScratchValue::Number(if fractional_part {
                 (rand * (num_max - num_min)) + num_min
             } else {
@@ -433,40 +619,480 @@ where
             Ok(())
         }
         InstructionType::DataDate => {
-            stack.push(ScratchValue::Number(Local::now().day() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.day() as f64));
             Ok(())
         }
         InstructionType::DataWeekday => {
             stack.push(ScratchValue::Number(
-                Local::now().weekday().number_from_monday() as f64,
+                local_time(clock)?.weekday().number_from_monday() as f64,
             ));
             Ok(())
         }
         InstructionType::DataDaysSince2000 => {
             stack.push(ScratchValue::Number(
-                (Local::now().timestamp_millis() as f64 - 946684800000.0)
-                    / (24.0 * 60.0 * 60.0 * 1000.0),
+                (clock.now() - 946684800000.0) / (24.0 * 60.0 * 60.0 * 1000.0),
             ));
             Ok(())
         }
         InstructionType::DataHour => {
-            stack.push(ScratchValue::Number(Local::now().hour() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.hour() as f64));
             Ok(())
         }
         InstructionType::DataMinute => {
-            stack.push(ScratchValue::Number(Local::now().minute() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.minute() as f64));
             Ok(())
         }
         InstructionType::DataMonth => {
-            stack.push(ScratchValue::Number(Local::now().month() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.month() as f64));
             Ok(())
         }
         InstructionType::DataSecond => {
-            stack.push(ScratchValue::Number(Local::now().second() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.second() as f64));
             Ok(())
         }
         InstructionType::DataYear => {
-            stack.push(ScratchValue::Number(Local::now().year() as f64));
+            stack.push(ScratchValue::Number(local_time(clock)?.year() as f64));
+            Ok(())
+        }
+        InstructionType::CallHost => {
+            let extra_arg = jmp_consume_extra_arg(1).ok_or("CALL_HOST missing extra arg")?;
+            let argument_count = (extra_arg & HOST_CALL_ARGUMENT_COUNT) as usize;
+            if argument_count > stack.len() {
+                return Err("not enough arguments on the stack for CALL_HOST");
+            }
+            // Arguments are passed in the order they were pushed
+            let arguments = stack.split_off(stack.len() - argument_count);
+            match host.call_host(instruction.argument, arguments)? {
+                HostCallResult::Value(value) => {
+                    if extra_arg & HOST_CALL_REPORTER > 0 {
+                        stack.push(value);
+                    }
+                }
+                HostCallResult::Pending => {
+                    if extra_arg & HOST_CALL_AWAIT > 0 {
+                        // The resolved value is pushed by the host on resume
+                        return_control(ReturnReason::HostPromise as u32);
+                    } else if extra_arg & HOST_CALL_REPORTER > 0 {
+                        stack.push(ScratchValue::EMPTY);
+                    }
+                }
+            }
+            Ok(())
+        }
+        InstructionType::Extension => {
+            let (arity, block_type) = match host.extension_block(instruction.argument) {
+                Some(signature) => (signature.arity as usize, signature.block_type),
+                None => return Err("no extension block registered with that opcode"),
+            };
+            if arity > stack.len() {
+                return Err("not enough arguments on the stack for the extension block");
+            }
+            let arguments = stack.split_off(stack.len() - arity);
+            match host.call_extension(instruction.argument, arguments)? {
+                HostCallResult::Value(value) => match block_type {
+                    BlockType::Command => {}
+                    BlockType::Reporter => stack.push(value),
+                    BlockType::Boolean => stack.push(ScratchValue::Boolean(value.into())),
+                },
+                // Scratch always waits for the promises extension blocks
+                // hand back. The resolved value is pushed by the host on
+                // resume, like for CALL_HOST
+                HostCallResult::Pending => return_control(ReturnReason::HostPromise as u32),
+            }
+            Ok(())
+        }
+        InstructionType::Wait => {
+            // Leave the duration on the stack for the scheduler to pop, since
+            // it's the one that knows what time it is
+            #[cfg(feature = "safety_checks")]
+            if stack.is_empty() {
+                return Err("WAIT requires a duration on the stack");
+            }
+            return_control(ReturnReason::WaitTimer as u32);
+            Ok(())
+        }
+        InstructionType::WaitUntil => {
+            // If the condition isn't met yet, jump back to the code that
+            // evaluates it and park until the next tick
+            if !Into::<bool>::into(pop_stack(stack)?) {
+                let offset = instruction.argument as i32;
+                jmp_consume_extra_arg(offset as isize);
+                return_control(ReturnReason::WaitCondition as u32);
+            }
+            Ok(())
+        }
+        InstructionType::Yield => {
+            return_control(ReturnReason::LoopYield as u32);
+            Ok(())
+        }
+        InstructionType::StopAll => {
+            return_control(ReturnReason::StopAll as u32);
+            Ok(())
+        }
+        InstructionType::StopThisScript => {
+            return_control(ReturnReason::Finished as u32);
+            Ok(())
+        }
+        InstructionType::StopOtherScriptsInSprite => {
+            // The scheduler resumes this thread right after stopping the
+            // others
+            return_control(ReturnReason::StopOtherScripts as u32);
+            Ok(())
+        }
+        InstructionType::ListContents => {
+            #[cfg(feature = "safety_checks")]
+            bounds_check(lists, instruction.argument)?;
+            stack.push(ScratchValue::String(list_contents(
+                &lists[instruction.argument as usize],
+            )));
+            Ok(())
+        }
+        InstructionType::MotionMove => {
+            let steps: f64 = pop_stack(stack)?.into();
+            move_sprite(targets, current_target, pen_layer, |motion| {
+                motion.move_steps(steps)
+            })
+        }
+        InstructionType::MotionTurnRight | InstructionType::MotionTurnLeft => {
+            let mut degrees: f64 = pop_stack(stack)?.into();
+            if instruction.name == InstructionType::MotionTurnLeft {
+                degrees = -degrees;
+            }
+            update_sprite(targets, current_target, |target| {
+                target
+                    .motion
+                    .set_direction(target.motion.direction + degrees)
+            })
+        }
+        InstructionType::MotionGotoXY => {
+            let y: f64 = pop_stack(stack)?.into();
+            let x: f64 = pop_stack(stack)?.into();
+            move_sprite(targets, current_target, pen_layer, |motion| {
+                motion.set_xy(x, y)
+            })
+        }
+        InstructionType::MotionGlide => {
+            let seconds = match stack.len().checked_sub(3) {
+                Some(index) => f64::from(stack[index].clone()),
+                None => return Err("MOTION_GLIDE requires a duration, x and y on the stack"),
+            };
+            current(targets, current_target)?;
+            if seconds > 0.0 {
+                // Leave the duration and destination on the stack for the
+                // scheduler, which moves the sprite along every tick
+                return_control(ReturnReason::Glide as u32);
+                return Ok(());
+            }
+            // Glides that take no time go there straight away, like any other
+            // move
+            let y: f64 = pop_stack(stack)?.into();
+            let x: f64 = pop_stack(stack)?.into();
+            pop_stack(stack)?;
+            move_sprite(targets, current_target, pen_layer, |motion| {
+                motion.set_xy(x, y)
+            })
+        }
+        InstructionType::MotionChangeX | InstructionType::MotionSetX => {
+            let x: f64 = pop_stack(stack)?.into();
+            let relative = instruction.name == InstructionType::MotionChangeX;
+            move_sprite(targets, current_target, pen_layer, |motion| {
+                let x = if relative { motion.x + x } else { x };
+                motion.set_xy(x, motion.y)
+            })
+        }
+        InstructionType::MotionChangeY | InstructionType::MotionSetY => {
+            let y: f64 = pop_stack(stack)?.into();
+            let relative = instruction.name == InstructionType::MotionChangeY;
+            move_sprite(targets, current_target, pen_layer, |motion| {
+                let y = if relative { motion.y + y } else { y };
+                motion.set_xy(motion.x, y)
+            })
+        }
+        InstructionType::MotionPointInDirection => {
+            let direction: f64 = pop_stack(stack)?.into();
+            update_sprite(targets, current_target, |target| {
+                target.motion.set_direction(direction)
+            })
+        }
+        InstructionType::MotionIfOnEdgeBounce => move_sprite(
+            targets,
+            current_target,
+            pen_layer,
+            Motion::if_on_edge_bounce,
+        ),
+        InstructionType::MotionSetRotationStyle => {
+            let rotation_style = RotationStyle::try_from(instruction.argument)?;
+            update_sprite(targets, current_target, |target| {
+                target.motion.set_rotation_style(rotation_style)
+            })
+        }
+        InstructionType::MotionX => {
+            let x = current(targets, current_target)?.motion.x;
+            stack.push(ScratchValue::Number(limit_precision(x)));
+            Ok(())
+        }
+        InstructionType::MotionY => {
+            let y = current(targets, current_target)?.motion.y;
+            stack.push(ScratchValue::Number(limit_precision(y)));
+            Ok(())
+        }
+        InstructionType::MotionDirection => {
+            let direction = current(targets, current_target)?.motion.direction;
+            stack.push(ScratchValue::Number(direction));
+            Ok(())
+        }
+        InstructionType::LooksSwitchCostume => {
+            let requested = pop_stack(stack)?;
+            current(targets, current_target)?
+                .looks
+                .switch_costume(&requested);
+            Ok(())
+        }
+        InstructionType::LooksNextCostume => {
+            let looks = &mut current(targets, current_target)?.looks;
+            looks.set_costume(looks.costume as f64 + 1.0);
+            Ok(())
+        }
+        InstructionType::LooksChangeSize | InstructionType::LooksSetSize => {
+            let size: f64 = pop_stack(stack)?.into();
+            let relative = instruction.name == InstructionType::LooksChangeSize;
+            update_sprite(targets, current_target, |target| {
+                let looks = &mut target.looks;
+                let size = if relative { looks.size + size } else { size };
+                looks.set_size(size)
+            })
+        }
+        InstructionType::LooksShow | InstructionType::LooksHide => {
+            let visible = instruction.name == InstructionType::LooksShow;
+            update_sprite(targets, current_target, |target| {
+                target.looks.set_visible(visible)
+            })
+        }
+        InstructionType::LooksChangeEffect | InstructionType::LooksSetEffect => {
+            let effect = GraphicEffect::try_from(instruction.argument)?;
+            let mut value: f64 = pop_stack(stack)?.into();
+            let looks = &mut current(targets, current_target)?.looks;
+            if instruction.name == InstructionType::LooksChangeEffect {
+                value += looks.effects[effect as usize];
+            }
+            looks.set_effect(effect, value);
+            Ok(())
+        }
+        InstructionType::LooksClearEffects => {
+            current(targets, current_target)?.looks.clear_effects();
+            Ok(())
+        }
+        InstructionType::LooksGoToFront | InstructionType::LooksGoToBack => {
+            let index = current_index(targets, current_target)?;
+            let position = if instruction.name == InstructionType::LooksGoToFront {
+                LayerPosition::Front
+            } else {
+                LayerPosition::Back
+            };
+            restack(targets, index, position);
+            Ok(())
+        }
+        InstructionType::LooksCostumeNumber => {
+            let costume = current(targets, current_target)?.looks.costume;
+            stack.push(ScratchValue::Number(costume as f64 + 1.0));
+            Ok(())
+        }
+        InstructionType::LooksCostumeName => {
+            let name = current(targets, current_target)?.looks.costume_name();
+            stack.push(ScratchValue::String(name.to_string()));
+            Ok(())
+        }
+        InstructionType::LooksSize => {
+            let size = current(targets, current_target)?.looks.size;
+            // Rounded like `Math.round`
+            stack.push(ScratchValue::Number((size + 0.5).floor()));
+            Ok(())
+        }
+        InstructionType::LooksSay | InstructionType::LooksThink => {
+            let message = pop_stack(stack)?;
+            let kind = if instruction.name == InstructionType::LooksSay {
+                BubbleKind::Say
+            } else {
+                BubbleKind::Think
+            };
+            update_sprite(targets, current_target, |target| {
+                target.bubble.update(kind, &message)
+            })
+        }
+        InstructionType::LooksSayForSecs | InstructionType::LooksThinkForSecs => {
+            // Leave the duration on the stack for the scheduler, which clears
+            // the bubble afterwards
+            let seconds = pop_stack(stack)?;
+            let message = pop_stack(stack)?;
+            stack.push(seconds);
+            let kind = if instruction.name == InstructionType::LooksSayForSecs {
+                BubbleKind::Say
+            } else {
+                BubbleKind::Think
+            };
+            update_sprite(targets, current_target, |target| {
+                target.bubble.update(kind, &message)
+            })?;
+            return_control(ReturnReason::SpeechTimer as u32);
+            Ok(())
+        }
+        InstructionType::SensingKeyPressed => {
+            let key = pop_stack(stack)?;
+            stack.push(ScratchValue::Boolean(io.keyboard.is_down(&key)));
+            Ok(())
+        }
+        InstructionType::SensingMouseDown => {
+            stack.push(ScratchValue::Boolean(io.mouse.down));
+            Ok(())
+        }
+        InstructionType::SensingMouseX => {
+            stack.push(ScratchValue::Number(io.mouse.x));
+            Ok(())
+        }
+        InstructionType::SensingMouseY => {
+            stack.push(ScratchValue::Number(io.mouse.y));
+            Ok(())
+        }
+        InstructionType::SensingAskAndWait => {
+            // Leave the question for the scheduler, which queues it up
+            #[cfg(feature = "safety_checks")]
+            if stack.is_empty() {
+                return Err("SENSING_ASK_AND_WAIT requires a question on the stack");
+            }
+            return_control(ReturnReason::Ask as u32);
+            Ok(())
+        }
+        InstructionType::SensingAnswer => {
+            stack.push(ScratchValue::String(io.answer.clone()));
+            Ok(())
+        }
+        InstructionType::SensingDistanceTo => {
+            let object = pop_stack(stack)?;
+            let index = current_index(targets, current_target)?;
+            let distance = distance_to(targets, index, &io.mouse, &object);
+            stack.push(ScratchValue::Number(distance));
+            Ok(())
+        }
+        InstructionType::SensingTouchingEdge => {
+            let target = current(targets, current_target)?;
+            let touching = !target.is_stage && target.motion.is_touching_edge();
+            stack.push(ScratchValue::Boolean(touching));
+            Ok(())
+        }
+        InstructionType::SensingOf => {
+            let object = pop_stack(stack)?;
+            let property = pop_stack(stack)?;
+            stack.push(attribute_of(
+                targets,
+                variables,
+                &property.as_str(),
+                &object,
+            ));
+            Ok(())
+        }
+        InstructionType::SensingTouchingObject => {
+            let object = pop_stack(stack)?;
+            let index = current_index(targets, current_target)?;
+            let touching = touching_object(targets, index, &io.mouse, collision, &object);
+            stack.push(ScratchValue::Boolean(touching));
+            Ok(())
+        }
+        InstructionType::SensingTouchingColor => {
+            let color = pop_stack(stack)?;
+            let index = current_index(targets, current_target)?;
+            let touching = touching_color(targets, index, collision, pen_layer, &color);
+            stack.push(ScratchValue::Boolean(touching));
+            Ok(())
+        }
+        InstructionType::PenClear => {
+            pen_layer.clear();
+            Ok(())
+        }
+        InstructionType::PenStamp => {
+            let index = current_index(targets, current_target)?;
+            if !targets[index].is_stage {
+                pen_layer.stamp(targets, index, collision);
+            }
+            Ok(())
+        }
+        InstructionType::PenDown => update_sprite(targets, current_target, |target| {
+            // Putting the pen down draws a dot, even if it was already down
+            target.pen.down = true;
+            let position = (target.motion.x, target.motion.y);
+            pen_layer.draw_line(&target.pen, position, position);
+        }),
+        InstructionType::PenUp => update_sprite(targets, current_target, |target| {
+            target.pen.down = false;
+        }),
+        InstructionType::PenSetColor => {
+            let color = pop_stack(stack)?;
+            current(targets, current_target)?.pen.set_color(&color);
+            Ok(())
+        }
+        InstructionType::PenChangeColorParam | InstructionType::PenSetColorParam => {
+            let param = ColorParam::try_from(instruction.argument)?;
+            let mut value: f64 = pop_stack(stack)?.into();
+            let pen = &mut current(targets, current_target)?.pen;
+            if instruction.name == InstructionType::PenChangeColorParam {
+                value += pen.color_param(param);
+            }
+            pen.set_color_param(param, value);
+            Ok(())
+        }
+        InstructionType::PenChangeSize | InstructionType::PenSetSize => {
+            let mut size: f64 = pop_stack(stack)?.into();
+            let pen = &mut current(targets, current_target)?.pen;
+            if instruction.name == InstructionType::PenChangeSize {
+                size += pen.size;
+            }
+            pen.set_size(size);
+            Ok(())
+        }
+        InstructionType::SoundPlay | InstructionType::SoundPlayUntilDone => {
+            // Leave the sound for the scheduler, which plays it and knows how
+            // long to wait for
+            #[cfg(feature = "safety_checks")]
+            if stack.is_empty() {
+                return Err("SOUND_PLAY requires a sound on the stack");
+            }
+            let reason = if instruction.name == InstructionType::SoundPlay {
+                ReturnReason::StartSound
+            } else {
+                ReturnReason::PlaySoundUntilDone
+            };
+            return_control(reason as u32);
+            Ok(())
+        }
+        InstructionType::SoundStopAll => {
+            return_control(ReturnReason::StopAllSounds as u32);
+            Ok(())
+        }
+        InstructionType::SoundChangeEffect | InstructionType::SoundSetEffect => {
+            let effect = SoundEffect::try_from(instruction.argument)?;
+            let mut value: f64 = pop_stack(stack)?.into();
+            let sound = &mut current(targets, current_target)?.sound;
+            if instruction.name == InstructionType::SoundChangeEffect {
+                value += sound.effects[effect as usize];
+            }
+            sound.set_effect(effect, value);
+            Ok(())
+        }
+        InstructionType::SoundClearEffects => {
+            current(targets, current_target)?.sound.clear_effects();
+            Ok(())
+        }
+        InstructionType::SoundChangeVolume | InstructionType::SoundSetVolume => {
+            let mut volume: f64 = pop_stack(stack)?.into();
+            let sound = &mut current(targets, current_target)?.sound;
+            if instruction.name == InstructionType::SoundChangeVolume {
+                volume += sound.volume;
+            }
+            sound.set_volume(volume);
+            Ok(())
+        }
+        InstructionType::SoundVolume => {
+            let volume = current(targets, current_target)?.sound.volume;
+            stack.push(ScratchValue::Number(volume));
             Ok(())
         }
         #[allow(unreachable_patterns)]
@@ -0,0 +1,288 @@
+use std::{cell::RefCell, convert::TryFrom, convert::TryInto, rc::Rc};
+
+use js_sys::{Function, Object, Promise, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::console;
+
+use crate::host::{HostCallHandler, HostCallResult};
+use crate::scratch_value::ScratchValue;
+
+/// The opcodes from here to `0xffff` are reserved for extension blocks: the
+/// block registered `n`th runs with the opcode `EXTENSION_OPCODE_START + n`.
+pub const EXTENSION_OPCODE_START: u16 = 0x8000;
+/// How many extension blocks there's room for in the reserved opcodes.
+pub const MAX_EXTENSION_BLOCKS: usize = 0x8000;
+
+/// What an extension block leaves on the stack, like Scratch's `BlockType`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockType {
+    /// Nothing; whatever the handler gives back is dropped.
+    Command = 0,
+    /// Whatever the handler gives back.
+    Reporter = 1,
+    /// Whatever the handler gives back, cast to a boolean.
+    Boolean = 2,
+}
+
+impl TryFrom<&str> for BlockType {
+    type Error = &'static str;
+    /// Reads the `blockType` of a block in an extension's `getInfo()`. Hats,
+    /// buttons and labels can't be run by the interpreter.
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "command" => Self::Command,
+            "reporter" => Self::Reporter,
+            "Boolean" => Self::Boolean,
+            _ => return Err("extension blocks must be commands, reporters or Booleans"),
+        })
+    }
+}
+
+/// Which block an extension opcode runs, and what it takes and gives back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockSignature {
+    /// The extension's ID, like `music` or `text2speech`.
+    pub extension: String,
+    /// The block's ID within the extension, like `playNoteForBeats`.
+    pub block: String,
+    /// How many values the block pops, which are handed to the handler in the
+    /// order they were pushed.
+    pub arity: u16,
+    pub block_type: BlockType,
+}
+
+impl BlockSignature {
+    /// How many values the block pops and pushes.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        let pushes = match self.block_type {
+            BlockType::Command => 0,
+            BlockType::Reporter | BlockType::Boolean => 1,
+        };
+        (self.arity as usize, pushes)
+    }
+}
+
+/// Runs an extension block, in Rust or by calling into JS.
+pub trait ExtensionHandler {
+    /// Runs the block with `arguments`, which are in the order they were
+    /// pushed. If the handler hands back `Pending`, the thread waits for the
+    /// host to resume it, like an awaited `CALL_HOST`.
+    fn call(&mut self, arguments: Vec<ScratchValue>) -> Result<HostCallResult, &'static str>;
+
+    /// Called by the scheduler when the thread `thread` is parked on the
+    /// result of the last call.
+    fn thread_parked(&mut self, _thread: u32) {}
+}
+
+impl<F> ExtensionHandler for F
+where
+    F: FnMut(Vec<ScratchValue>) -> Result<HostCallResult, &'static str>,
+{
+    fn call(&mut self, arguments: Vec<ScratchValue>) -> Result<HostCallResult, &'static str> {
+        self(arguments)
+    }
+}
+
+/// The extension blocks a runtime knows about, each bound to its handler.
+#[derive(Default)]
+pub struct Extensions {
+    /// Indexed by the opcode minus `EXTENSION_OPCODE_START`, like `handlers`.
+    signatures: Vec<BlockSignature>,
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    /// The block called last, if nothing else has been called since, since a
+    /// thread that's parked is waiting on it.
+    last_called: Option<usize>,
+}
+
+impl Extensions {
+    /// Binds the block in `signature` to `handler`, returning the opcode that
+    /// runs it. Registering a block again replaces it but keeps its opcode,
+    /// so bytecode compiled for it still works.
+    pub fn register(
+        &mut self,
+        signature: BlockSignature,
+        handler: Box<dyn ExtensionHandler>,
+    ) -> Result<u16, &'static str> {
+        let index = match self.index(&signature.extension, &signature.block) {
+            Some(index) => {
+                self.signatures[index] = signature;
+                self.handlers[index] = handler;
+                index
+            }
+            None if self.signatures.len() >= MAX_EXTENSION_BLOCKS => {
+                return Err("no opcodes left for extension blocks")
+            }
+            None => {
+                self.signatures.push(signature);
+                self.handlers.push(handler);
+                self.signatures.len() - 1
+            }
+        };
+        Ok(EXTENSION_OPCODE_START + index as u16)
+    }
+
+    fn index(&self, extension: &str, block: &str) -> Option<usize> {
+        self.signatures
+            .iter()
+            .position(|signature| signature.extension == extension && signature.block == block)
+    }
+
+    /// The opcode of the block `block` of `extension`, if it's registered.
+    pub fn opcode(&self, extension: &str, block: &str) -> Option<u16> {
+        self.index(extension, block)
+            .map(|index| EXTENSION_OPCODE_START + index as u16)
+    }
+
+    /// Every registered block, by opcode (minus `EXTENSION_OPCODE_START`).
+    pub fn signatures(&self) -> &[BlockSignature] {
+        &self.signatures
+    }
+
+    /// Runs the block with the opcode `EXTENSION_OPCODE_START + block`.
+    pub fn call(
+        &mut self,
+        block: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        let handler = self
+            .handlers
+            .get_mut(block as usize)
+            .ok_or("no extension block registered with that opcode")?;
+        self.last_called = Some(block as usize);
+        handler.call(arguments)
+    }
+
+    /// Tells the block called last that `thread` is parked on it, returning
+    /// `false` if something else has been called since.
+    pub fn thread_parked(&mut self, thread: u32) -> bool {
+        match self.last_called.take() {
+            Some(index) => {
+                self.handlers[index].thread_parked(thread);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Puts extension blocks next to a host: `CALL_HOST` goes to `host`, and the
+/// extension opcodes to the handlers in `extensions`.
+pub struct ExtendedHost<'a> {
+    pub host: &'a mut dyn HostCallHandler,
+    pub extensions: &'a mut Extensions,
+}
+
+impl HostCallHandler for ExtendedHost<'_> {
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        self.extensions.last_called = None;
+        self.host.call_host(id, arguments)
+    }
+
+    fn extension_block(&self, block: u32) -> Option<&BlockSignature> {
+        self.extensions.signatures.get(block as usize)
+    }
+
+    fn call_extension(
+        &mut self,
+        block: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        self.extensions.call(block, arguments)
+    }
+
+    fn thread_parked(&mut self, thread: u32) {
+        if !self.extensions.thread_parked(thread) {
+            self.host.thread_parked(thread);
+        }
+    }
+}
+
+/// A block of a TurboWarp-style JS extension object, run by calling the
+/// object's method named after the block with an object of its arguments.
+pub struct JsExtensionBlock {
+    pub extension: JsValue,
+    pub method: String,
+    /// The names of the block's arguments, in the order they're pushed.
+    pub argument_names: Vec<String>,
+    pub pending_promise: Option<Promise>,
+    /// Where the promises threads are parked on go, shared with the runtime
+    /// like `JsHost::parked_threads`.
+    pub parked_threads: Rc<RefCell<Vec<(u32, Promise)>>>,
+}
+
+impl ExtensionHandler for JsExtensionBlock {
+    fn call(&mut self, arguments: Vec<ScratchValue>) -> Result<HostCallResult, &'static str> {
+        let method = Reflect::get(&self.extension, &JsValue::from_str(&self.method))
+            .ok()
+            .and_then(|method| method.dyn_into::<Function>().ok())
+            .ok_or("extension has no method for the block")?;
+        let object = Object::new();
+        for (name, argument) in self.argument_names.iter().zip(arguments) {
+            Reflect::set(&object, &JsValue::from_str(name), &argument.into())
+                .map_err(|_| "couldn't pass the arguments to the extension")?;
+        }
+        let result = method
+            .call1(&self.extension, &object)
+            .map_err(|exception| {
+                console::error_1(&exception);
+                "extension block threw an exception"
+            })?;
+        if result.is_instance_of::<Promise>() {
+            self.pending_promise = Some(result.unchecked_into());
+            Ok(HostCallResult::Pending)
+        } else if result.is_undefined() || result.is_null() {
+            Ok(HostCallResult::Value(ScratchValue::EMPTY))
+        } else {
+            Ok(HostCallResult::Value(result.try_into()?))
+        }
+    }
+
+    fn thread_parked(&mut self, thread: u32) {
+        if let Some(promise) = self.pending_promise.take() {
+            self.parked_threads.borrow_mut().push((thread, promise));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(block: &str, arity: u16, block_type: BlockType) -> BlockSignature {
+        BlockSignature {
+            extension: "test".into(),
+            block: block.into(),
+            arity,
+            block_type,
+        }
+    }
+
+    #[test]
+    fn blocks_keep_their_opcodes() {
+        let mut extensions = Extensions::default();
+        let constant = |value: f64| {
+            Box::new(move |_| Ok(HostCallResult::Value(ScratchValue::Number(value))))
+                as Box<dyn ExtensionHandler>
+        };
+        let first = extensions.register(signature("a", 0, BlockType::Reporter), constant(1.0));
+        let second = extensions.register(signature("b", 2, BlockType::Command), constant(2.0));
+        assert_eq!((first, second), (Ok(0x8000), Ok(0x8001)));
+        assert_eq!(extensions.opcode("test", "b"), Some(0x8001));
+        assert_eq!(extensions.opcode("other", "b"), None);
+
+        // Registering again replaces the block in place
+        let again = extensions.register(signature("a", 1, BlockType::Boolean), constant(3.0));
+        assert_eq!(again, Ok(0x8000));
+        assert_eq!(extensions.signatures()[0].stack_effect(), (1, 1));
+        assert_eq!(
+            extensions.call(0, vec![]),
+            Ok(HostCallResult::Value(ScratchValue::Number(3.0)))
+        );
+        assert!(extensions.call(2, vec![]).is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+/// Something other than the green flag that starts a script.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Hat {
+    /// "when (key) key pressed", with the key's Scratch name (e.g. "a",
+    /// "space" or "any").
+    KeyPressed(String),
+    /// "when this sprite clicked", or "when stage clicked" on the stage.
+    Clicked,
+}
+
+impl Hat {
+    /// Whether firing the hat again restarts a thread that's still running
+    /// its script, rather than leaving it be (scratch-vm's
+    /// `restartExistingThreads`).
+    pub fn restarts_existing_threads(&self) -> bool {
+        matches!(self, Hat::Clicked)
+    }
+}
+
+/// A script that starts at `script` (a program counter) when `hat` fires.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HatScript {
+    pub hat: Hat,
+    pub script: usize,
+}
@@ -0,0 +1,163 @@
+use std::{cell::RefCell, convert::TryInto, rc::Rc};
+
+use js_sys::{Array, Function, Promise};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::console;
+
+use crate::extension::BlockSignature;
+use crate::scratch_value::ScratchValue;
+
+/// Set in the `EXTRA_ARG` of a `CALL_HOST` when the block is a reporter, so
+/// the return value should be pushed to the stack.
+pub const HOST_CALL_REPORTER: u32 = 1 << 16;
+/// Set in the `EXTRA_ARG` of a `CALL_HOST` when the thread should be suspended
+/// if the host returns a promise. Otherwise, the promise is left to run on its
+/// own.
+pub const HOST_CALL_AWAIT: u32 = 1 << 17;
+/// Mask for the number of arguments in the `EXTRA_ARG` of a `CALL_HOST`.
+pub const HOST_CALL_ARGUMENT_COUNT: u32 = 0xffff;
+
+/// What the host handed back from a `CALL_HOST` instruction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HostCallResult {
+    /// The host finished synchronously; the value is pushed to the stack.
+    Value(ScratchValue),
+    /// The host started something asynchronous (e.g. a JS `Promise`). If the
+    /// call was made with `HOST_CALL_AWAIT`, the thread is suspended and, for
+    /// reporters, the resolved value should be pushed onto the stack by
+    /// whoever resumes it.
+    Pending,
+}
+
+/// Handles blocks the instruction set can't express (motion, looks, pen,
+/// extensions...) by delegating them to whoever is embedding the runtime.
+pub trait HostCallHandler {
+    /// Calls the host function identified by `id` with `arguments`, which are
+    /// in the order they were pushed to the stack.
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str>;
+
+    /// The extension block with the opcode `EXTENSION_OPCODE_START + block`,
+    /// if there is one. Hosts without extensions (see `ExtendedHost`) have
+    /// none.
+    fn extension_block(&self, _block: u32) -> Option<&BlockSignature> {
+        None
+    }
+
+    /// Runs the extension block with the opcode
+    /// `EXTENSION_OPCODE_START + block` with `arguments`, which are in the
+    /// order they were pushed.
+    fn call_extension(
+        &mut self,
+        _block: u32,
+        _arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        Err("no extension block registered with that opcode")
+    }
+
+    /// Called by the scheduler when the thread `thread` is parked on the
+    /// result of the last pending call.
+    fn thread_parked(&mut self, _thread: u32) {}
+}
+
+impl<F> HostCallHandler for F
+where
+    F: FnMut(u32, Vec<ScratchValue>) -> Result<HostCallResult, &'static str>,
+{
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        self(id, arguments)
+    }
+}
+
+/// A host that doesn't support any calls. Used when the embedder doesn't
+/// need `CALL_HOST`.
+pub struct NoHost;
+
+impl HostCallHandler for NoHost {
+    fn call_host(&mut self, _: u32, _: Vec<ScratchValue>) -> Result<HostCallResult, &'static str> {
+        Err("no host call handler registered")
+    }
+}
+
+thread_local! {
+    /// The table of JS callbacks registered through `register_host_function`,
+    /// indexed by the argument to `CALL_HOST`.
+    static HOST_FUNCTIONS: RefCell<Vec<Option<Function>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Registers `callback` in the JS function table under `id`, replacing any
+/// function already there.
+pub fn register_js_host_function(id: u32, callback: Function) {
+    HOST_FUNCTIONS.with(|functions| {
+        let mut functions = functions.borrow_mut();
+        let id = id as usize;
+        if functions.len() <= id {
+            functions.resize(id + 1, None);
+        }
+        functions[id] = Some(callback);
+    });
+}
+
+/// Empties the JS function table.
+pub fn clear_js_host_functions() {
+    HOST_FUNCTIONS.with(|functions| functions.borrow_mut().clear());
+}
+
+/// Calls into the JS function table. If a callback returns a `Promise`, the
+/// call is reported as pending and the promise is kept so that it can be
+/// handed back to JS.
+#[derive(Default)]
+pub struct JsHost {
+    pub pending_promise: Option<Promise>,
+    /// The promises that threads were parked on by the scheduler, shared so
+    /// that they can be picked up after a tick.
+    pub parked_threads: Rc<RefCell<Vec<(u32, Promise)>>>,
+}
+
+impl HostCallHandler for JsHost {
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        let callback = HOST_FUNCTIONS
+            .with(|functions| functions.borrow().get(id as usize).cloned().flatten())
+            .ok_or("no host function registered with that id")?;
+        let result = callback
+            .apply(
+                &JsValue::NULL,
+                &arguments
+                    .into_iter()
+                    .map(Into::<JsValue>::into)
+                    .collect::<Array>(),
+            )
+            .map_err(|exception| {
+                console::error_1(&exception);
+                "host function threw an exception"
+            })?;
+        if result.is_instance_of::<Promise>() {
+            self.pending_promise = Some(result.unchecked_into());
+            Ok(HostCallResult::Pending)
+        } else if result.is_undefined() || result.is_null() {
+            // Command blocks don't report anything, so treat them like an
+            // empty reporter rather than failing the conversion.
+            Ok(HostCallResult::Value(ScratchValue::EMPTY))
+        } else {
+            Ok(HostCallResult::Value(result.try_into()?))
+        }
+    }
+
+    fn thread_parked(&mut self, thread: u32) {
+        if let Some(promise) = self.pending_promise.take() {
+            self.parked_threads.borrow_mut().push((thread, promise));
+        }
+    }
+}
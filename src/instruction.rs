@@ -1,8 +1,12 @@
+use std::{convert::TryFrom, fmt};
+
 use wasm_bindgen::prelude::*;
 
+use crate::extension::EXTENSION_OPCODE_START;
+
 #[wasm_bindgen]
 #[repr(u16)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum InstructionType {
     Noop = 0x0000,
     ExtraArg = 0x0001,
@@ -67,6 +71,488 @@ pub enum InstructionType {
     LoadConstInt = 0x003c,
     LoadConstBool = 0x003d,
     LoadConstFloat = 0x003e,
+    CallHost = 0x003f,
+    Wait = 0x0040,
+    WaitUntil = 0x0041,
+    Yield = 0x0042,
+    StopAll = 0x0043,
+    StopThisScript = 0x0044,
+    StopOtherScriptsInSprite = 0x0045,
+    ListContents = 0x0046,
+    MotionMove = 0x0047,
+    MotionTurnRight = 0x0048,
+    MotionTurnLeft = 0x0049,
+    MotionGotoXY = 0x004a,
+    MotionGlide = 0x004b,
+    MotionChangeX = 0x004c,
+    MotionSetX = 0x004d,
+    MotionChangeY = 0x004e,
+    MotionSetY = 0x004f,
+    MotionPointInDirection = 0x0050,
+    MotionIfOnEdgeBounce = 0x0051,
+    MotionSetRotationStyle = 0x0052,
+    MotionX = 0x0053,
+    MotionY = 0x0054,
+    MotionDirection = 0x0055,
+    LooksSwitchCostume = 0x0056,
+    LooksNextCostume = 0x0057,
+    LooksChangeSize = 0x0058,
+    LooksSetSize = 0x0059,
+    LooksShow = 0x005a,
+    LooksHide = 0x005b,
+    LooksChangeEffect = 0x005c,
+    LooksSetEffect = 0x005d,
+    LooksClearEffects = 0x005e,
+    LooksGoToFront = 0x005f,
+    LooksGoToBack = 0x0060,
+    LooksCostumeNumber = 0x0061,
+    LooksCostumeName = 0x0062,
+    LooksSize = 0x0063,
+    LooksSay = 0x0064,
+    LooksThink = 0x0065,
+    LooksSayForSecs = 0x0066,
+    LooksThinkForSecs = 0x0067,
+    SensingKeyPressed = 0x0068,
+    SensingMouseDown = 0x0069,
+    SensingMouseX = 0x006a,
+    SensingMouseY = 0x006b,
+    SensingAskAndWait = 0x006c,
+    SensingAnswer = 0x006d,
+    SensingDistanceTo = 0x006e,
+    SensingTouchingEdge = 0x006f,
+    SensingOf = 0x0070,
+    SensingTouchingObject = 0x0071,
+    SensingTouchingColor = 0x0072,
+    PenClear = 0x0073,
+    PenStamp = 0x0074,
+    PenDown = 0x0075,
+    PenUp = 0x0076,
+    PenSetColor = 0x0077,
+    PenChangeColorParam = 0x0078,
+    PenSetColorParam = 0x0079,
+    PenChangeSize = 0x007a,
+    PenSetSize = 0x007b,
+    SoundPlay = 0x007c,
+    SoundPlayUntilDone = 0x007d,
+    SoundStopAll = 0x007e,
+    SoundChangeEffect = 0x007f,
+    SoundSetEffect = 0x0080,
+    SoundClearEffects = 0x0081,
+    SoundChangeVolume = 0x0082,
+    SoundSetVolume = 0x0083,
+    SoundVolume = 0x0084,
+    /// Stands for every opcode from here up, which are reserved for extension
+    /// blocks (see `extension.rs`). Decoded instructions hold the block's
+    /// index, the opcode minus this one, as their argument.
+    Extension = 0x8000,
+}
+
+impl InstructionType {
+    /// Every built-in instruction, in opcode order. `Extension` isn't one.
+    pub const ALL: [InstructionType; 133] = {
+        use InstructionType::*;
+        [
+            Noop,
+            ExtraArg,
+            LoadConst,
+            Load,
+            Store,
+            Jump,
+            JumpIf,
+            AllocList,
+            OpAdd,
+            OpSubtract,
+            OpMultiply,
+            OpDivide,
+            OpAnd,
+            OpOr,
+            UnaryNot,
+            UnaryAbs,
+            UnaryFloor,
+            UnaryCeil,
+            UnarySqrt,
+            UnarySin,
+            UnaryCos,
+            UnaryTan,
+            UnaryAsin,
+            UnaryAcos,
+            UnaryAtan,
+            UnaryLn,
+            UnaryLog,
+            UnaryEPow,
+            Unary10Pow,
+            OpLt,
+            Reserved,
+            OpEq,
+            ListDel,
+            ListIns,
+            ListDelAll,
+            ListReplace,
+            ListPush,
+            ListLoad,
+            ListLen,
+            ListIFind,
+            ListIIncludes,
+            MonitorShowVar,
+            MonitorHideVar,
+            MonitorShowList,
+            MonitorHideList,
+            Return,
+            OpMod,
+            StringIndexChar,
+            StringLen,
+            StringConcat,
+            UnaryRound,
+            DataRand,
+            DataDate,
+            DataWeekday,
+            DataDaysSince2000,
+            DataHour,
+            DataMinute,
+            DataMonth,
+            DataSecond,
+            DataYear,
+            LoadConstInt,
+            LoadConstBool,
+            LoadConstFloat,
+            CallHost,
+            Wait,
+            WaitUntil,
+            Yield,
+            StopAll,
+            StopThisScript,
+            StopOtherScriptsInSprite,
+            ListContents,
+            MotionMove,
+            MotionTurnRight,
+            MotionTurnLeft,
+            MotionGotoXY,
+            MotionGlide,
+            MotionChangeX,
+            MotionSetX,
+            MotionChangeY,
+            MotionSetY,
+            MotionPointInDirection,
+            MotionIfOnEdgeBounce,
+            MotionSetRotationStyle,
+            MotionX,
+            MotionY,
+            MotionDirection,
+            LooksSwitchCostume,
+            LooksNextCostume,
+            LooksChangeSize,
+            LooksSetSize,
+            LooksShow,
+            LooksHide,
+            LooksChangeEffect,
+            LooksSetEffect,
+            LooksClearEffects,
+            LooksGoToFront,
+            LooksGoToBack,
+            LooksCostumeNumber,
+            LooksCostumeName,
+            LooksSize,
+            LooksSay,
+            LooksThink,
+            LooksSayForSecs,
+            LooksThinkForSecs,
+            SensingKeyPressed,
+            SensingMouseDown,
+            SensingMouseX,
+            SensingMouseY,
+            SensingAskAndWait,
+            SensingAnswer,
+            SensingDistanceTo,
+            SensingTouchingEdge,
+            SensingOf,
+            SensingTouchingObject,
+            SensingTouchingColor,
+            PenClear,
+            PenStamp,
+            PenDown,
+            PenUp,
+            PenSetColor,
+            PenChangeColorParam,
+            PenSetColorParam,
+            PenChangeSize,
+            PenSetSize,
+            SoundPlay,
+            SoundPlayUntilDone,
+            SoundStopAll,
+            SoundChangeEffect,
+            SoundSetEffect,
+            SoundClearEffects,
+            SoundChangeVolume,
+            SoundSetVolume,
+            SoundVolume,
+        ]
+    };
+
+    /// The name the instruction goes by in the docs and the assembler.
+    pub fn mnemonic(self) -> &'static str {
+        use InstructionType::*;
+        match self {
+            Noop => "NOOP",
+            ExtraArg => "EXTRA_ARG",
+            LoadConst => "LOAD_CONST",
+            Load => "LOAD",
+            Store => "STORE",
+            Jump => "JUMP",
+            JumpIf => "JUMP_IF",
+            AllocList => "ALLOC_LIST",
+            OpAdd => "OP_ADD",
+            OpSubtract => "OP_SUBTRACT",
+            OpMultiply => "OP_MULTIPLY",
+            OpDivide => "OP_DIVIDE",
+            OpAnd => "OP_AND",
+            OpOr => "OP_OR",
+            UnaryNot => "UNARY_NOT",
+            UnaryAbs => "UNARY_ABS",
+            UnaryFloor => "UNARY_FLOOR",
+            UnaryCeil => "UNARY_CEIL",
+            UnarySqrt => "UNARY_SQRT",
+            UnarySin => "UNARY_SIN",
+            UnaryCos => "UNARY_COS",
+            UnaryTan => "UNARY_TAN",
+            UnaryAsin => "UNARY_ASIN",
+            UnaryAcos => "UNARY_ACOS",
+            UnaryAtan => "UNARY_ATAN",
+            UnaryLn => "UNARY_LN",
+            UnaryLog => "UNARY_LOG",
+            UnaryEPow => "UNARY_EPOW",
+            Unary10Pow => "UNARY_10POW",
+            OpLt => "OP_LT",
+            Reserved => "RESERVED",
+            OpEq => "OP_EQ",
+            ListDel => "LIST_DEL",
+            ListIns => "LIST_INS",
+            ListDelAll => "LIST_DEL_ALL",
+            ListReplace => "LIST_REPLACE",
+            ListPush => "LIST_PUSH",
+            ListLoad => "LIST_LOAD",
+            ListLen => "LIST_LEN",
+            ListIFind => "LIST_IFIND",
+            ListIIncludes => "LIST_IINCLUDES",
+            MonitorShowVar => "MONITOR_SHOWVAR",
+            MonitorHideVar => "MONITOR_HIDEVAR",
+            MonitorShowList => "MONITOR_SHOWLIST",
+            MonitorHideList => "MONITOR_HIDELIST",
+            Return => "RETURN",
+            OpMod => "OP_MOD",
+            StringIndexChar => "STRING_INDEXCHAR",
+            StringLen => "STRING_LEN",
+            StringConcat => "STRING_CONCAT",
+            UnaryRound => "UNARY_ROUND",
+            DataRand => "DATA_RAND",
+            DataDate => "DATA_DATE",
+            DataWeekday => "DATA_WEEKDAY",
+            DataDaysSince2000 => "DATA_DAYSSINCE2000",
+            DataHour => "DATA_HOUR",
+            DataMinute => "DATA_MINUTE",
+            DataMonth => "DATA_MONTH",
+            DataSecond => "DATA_SECOND",
+            DataYear => "DATA_YEAR",
+            LoadConstInt => "LOAD_CONST_INT",
+            LoadConstBool => "LOAD_CONST_BOOL",
+            LoadConstFloat => "LOAD_CONST_FLOAT",
+            CallHost => "CALL_HOST",
+            Wait => "WAIT",
+            WaitUntil => "WAIT_UNTIL",
+            Yield => "YIELD",
+            StopAll => "STOP_ALL",
+            StopThisScript => "STOP_THIS_SCRIPT",
+            StopOtherScriptsInSprite => "STOP_OTHER_SCRIPTS",
+            ListContents => "LIST_CONTENTS",
+            MotionMove => "MOTION_MOVE",
+            MotionTurnRight => "MOTION_TURN_RIGHT",
+            MotionTurnLeft => "MOTION_TURN_LEFT",
+            MotionGotoXY => "MOTION_GOTO_XY",
+            MotionGlide => "MOTION_GLIDE",
+            MotionChangeX => "MOTION_CHANGE_X",
+            MotionSetX => "MOTION_SET_X",
+            MotionChangeY => "MOTION_CHANGE_Y",
+            MotionSetY => "MOTION_SET_Y",
+            MotionPointInDirection => "MOTION_POINT_IN_DIRECTION",
+            MotionIfOnEdgeBounce => "MOTION_IF_ON_EDGE_BOUNCE",
+            MotionSetRotationStyle => "MOTION_SET_ROTATION_STYLE",
+            MotionX => "MOTION_X",
+            MotionY => "MOTION_Y",
+            MotionDirection => "MOTION_DIRECTION",
+            LooksSwitchCostume => "LOOKS_SWITCH_COSTUME",
+            LooksNextCostume => "LOOKS_NEXT_COSTUME",
+            LooksChangeSize => "LOOKS_CHANGE_SIZE",
+            LooksSetSize => "LOOKS_SET_SIZE",
+            LooksShow => "LOOKS_SHOW",
+            LooksHide => "LOOKS_HIDE",
+            LooksChangeEffect => "LOOKS_CHANGE_EFFECT",
+            LooksSetEffect => "LOOKS_SET_EFFECT",
+            LooksClearEffects => "LOOKS_CLEAR_EFFECTS",
+            LooksGoToFront => "LOOKS_GO_TO_FRONT",
+            LooksGoToBack => "LOOKS_GO_TO_BACK",
+            LooksCostumeNumber => "LOOKS_COSTUME_NUMBER",
+            LooksCostumeName => "LOOKS_COSTUME_NAME",
+            LooksSize => "LOOKS_SIZE",
+            LooksSay => "LOOKS_SAY",
+            LooksThink => "LOOKS_THINK",
+            LooksSayForSecs => "LOOKS_SAY_FOR_SECS",
+            LooksThinkForSecs => "LOOKS_THINK_FOR_SECS",
+            SensingKeyPressed => "SENSING_KEY_PRESSED",
+            SensingMouseDown => "SENSING_MOUSE_DOWN",
+            SensingMouseX => "SENSING_MOUSE_X",
+            SensingMouseY => "SENSING_MOUSE_Y",
+            SensingAskAndWait => "SENSING_ASK_AND_WAIT",
+            SensingAnswer => "SENSING_ANSWER",
+            SensingDistanceTo => "SENSING_DISTANCE_TO",
+            SensingTouchingEdge => "SENSING_TOUCHING_EDGE",
+            SensingOf => "SENSING_OF",
+            SensingTouchingObject => "SENSING_TOUCHING_OBJECT",
+            SensingTouchingColor => "SENSING_TOUCHING_COLOR",
+            PenClear => "PEN_CLEAR",
+            PenStamp => "PEN_STAMP",
+            PenDown => "PEN_DOWN",
+            PenUp => "PEN_UP",
+            PenSetColor => "PEN_SET_COLOR",
+            PenChangeColorParam => "PEN_CHANGE_COLOR_PARAM",
+            PenSetColorParam => "PEN_SET_COLOR_PARAM",
+            PenChangeSize => "PEN_CHANGE_SIZE",
+            PenSetSize => "PEN_SET_SIZE",
+            SoundPlay => "SOUND_PLAY",
+            SoundPlayUntilDone => "SOUND_PLAY_UNTIL_DONE",
+            SoundStopAll => "SOUND_STOP_ALL",
+            SoundChangeEffect => "SOUND_CHANGE_EFFECT",
+            SoundSetEffect => "SOUND_SET_EFFECT",
+            SoundClearEffects => "SOUND_CLEAR_EFFECTS",
+            SoundChangeVolume => "SOUND_CHANGE_VOLUME",
+            SoundSetVolume => "SOUND_SET_VOLUME",
+            SoundVolume => "SOUND_VOLUME",
+            Extension => "EXTENSION",
+        }
+    }
+    /// How many values the instruction pops from the stack and then pushes,
+    /// or `None` if that depends on its `EXTRA_ARG` (`CALL_HOST`) or it can't
+    /// be run at all.
+    pub fn stack_effect(self) -> Option<(usize, usize)> {
+        use InstructionType::*;
+        Some(match self {
+            Noop
+            | AllocList
+            | Jump
+            | ListDelAll
+            | MonitorShowVar
+            | MonitorHideVar
+            | MonitorShowList
+            | MonitorHideList
+            | Return
+            | Yield
+            | StopAll
+            | StopThisScript
+            | StopOtherScriptsInSprite
+            | MotionIfOnEdgeBounce
+            | MotionSetRotationStyle
+            | LooksNextCostume
+            | LooksShow
+            | LooksHide
+            | LooksClearEffects
+            | LooksGoToFront
+            | LooksGoToBack
+            | PenClear
+            | PenStamp
+            | PenDown
+            | PenUp
+            | SoundStopAll
+            | SoundClearEffects => (0, 0),
+            LoadConst | Load | LoadConstInt | LoadConstBool | LoadConstFloat | ListLen
+            | ListContents | DataDate | DataWeekday | DataDaysSince2000 | DataHour | DataMinute
+            | DataMonth | DataSecond | DataYear | MotionX | MotionY | MotionDirection
+            | LooksCostumeNumber | LooksCostumeName | LooksSize | SensingMouseDown
+            | SensingMouseX | SensingMouseY | SensingAnswer | SensingTouchingEdge | SoundVolume => {
+                (0, 1)
+            }
+            Store
+            | JumpIf
+            | ListDel
+            | ListPush
+            | WaitUntil
+            | MotionMove
+            | MotionTurnRight
+            | MotionTurnLeft
+            | MotionChangeX
+            | MotionSetX
+            | MotionChangeY
+            | MotionSetY
+            | MotionPointInDirection
+            | LooksSwitchCostume
+            | LooksChangeSize
+            | LooksSetSize
+            | LooksChangeEffect
+            | LooksSetEffect
+            | LooksSay
+            | LooksThink
+            | PenSetColor
+            | PenChangeColorParam
+            | PenSetColorParam
+            | PenChangeSize
+            | PenSetSize
+            | SoundChangeEffect
+            | SoundSetEffect
+            | SoundChangeVolume
+            | SoundSetVolume => (1, 0),
+            // The duration (and the glide's destination, the question and the
+            // sound) are popped by the scheduler before the thread carries on
+            Wait | SensingAskAndWait | SoundPlay | SoundPlayUntilDone => (1, 0),
+            MotionGlide => (3, 0),
+            LooksSayForSecs | LooksThinkForSecs => (2, 0),
+            UnaryNot
+            | UnaryAbs
+            | UnaryFloor
+            | UnaryCeil
+            | UnarySqrt
+            | UnarySin
+            | UnaryCos
+            | UnaryTan
+            | UnaryAsin
+            | UnaryAcos
+            | UnaryAtan
+            | UnaryLn
+            | UnaryLog
+            | UnaryEPow
+            | Unary10Pow
+            | UnaryRound
+            | StringLen
+            | ListLoad
+            | ListIFind
+            | ListIIncludes
+            | SensingKeyPressed
+            | SensingDistanceTo
+            | SensingTouchingObject
+            | SensingTouchingColor => (1, 1),
+            OpAdd | OpSubtract | OpMultiply | OpDivide | OpAnd | OpOr | OpLt | OpEq | OpMod
+            | StringIndexChar | StringConcat | DataRand | SensingOf => (2, 1),
+            ListIns | ListReplace | MotionGotoXY => (2, 0),
+            // Extension blocks declare theirs when they're registered
+            ExtraArg | Reserved | CallHost | Extension => return None,
+        })
+    }
+
+    /// Whether the instruction must be followed by an `EXTRA_ARG`.
+    pub fn takes_extra_arg(self) -> bool {
+        matches!(self, InstructionType::AllocList | InstructionType::CallHost)
+    }
+}
+
+impl TryFrom<u16> for InstructionType {
+    type Error = &'static str;
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        if value >= EXTENSION_OPCODE_START {
+            return Ok(InstructionType::Extension);
+        }
+        // The built-in opcodes have no gaps, so they line up with their index
+        InstructionType::ALL
+            .get(value as usize)
+            .copied()
+            .filter(|instruction| *instruction as u16 == value)
+            .ok_or("unknown opcode")
+    }
 }
 
 #[wasm_bindgen]
@@ -77,6 +563,45 @@ pub enum ReturnReason {
     LoopYield = 0x00000001,
     Repaint = 0x00000002,
     VisualReport = 0x00000003,
+    HostPromise = 0x00000004,
+    WaitTimer = 0x00000005,
+    WaitCondition = 0x00000006,
+    StopAll = 0x00000007,
+    StopOtherScripts = 0x00000008,
+    BudgetExhausted = 0x00000009,
+    DebugPause = 0x0000000a,
+    Glide = 0x0000000b,
+    SpeechTimer = 0x0000000c,
+    Ask = 0x0000000d,
+    StartSound = 0x0000000e,
+    PlaySoundUntilDone = 0x0000000f,
+    StopAllSounds = 0x00000010,
+}
+
+impl TryFrom<u32> for ReturnReason {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00000000 => Self::Finished,
+            0x00000001 => Self::LoopYield,
+            0x00000002 => Self::Repaint,
+            0x00000003 => Self::VisualReport,
+            0x00000004 => Self::HostPromise,
+            0x00000005 => Self::WaitTimer,
+            0x00000006 => Self::WaitCondition,
+            0x00000007 => Self::StopAll,
+            0x00000008 => Self::StopOtherScripts,
+            0x00000009 => Self::BudgetExhausted,
+            0x0000000a => Self::DebugPause,
+            0x0000000b => Self::Glide,
+            0x0000000c => Self::SpeechTimer,
+            0x0000000d => Self::Ask,
+            0x0000000e => Self::StartSound,
+            0x0000000f => Self::PlaySoundUntilDone,
+            0x00000010 => Self::StopAllSounds,
+            _ => return Err("unknown return reason"),
+        })
+    }
 }
 
 #[wasm_bindgen]
@@ -87,3 +612,116 @@ pub struct Instruction {
     _padding: [u8; 2],
     pub argument: u32,
 }
+
+impl Instruction {
+    pub fn new(name: InstructionType, argument: u32) -> Self {
+        Self {
+            name,
+            _padding: [0; 2],
+            argument,
+        }
+    }
+}
+
+impl TryFrom<u64> for Instruction {
+    type Error = &'static str;
+    /// Decodes an instruction from its little-endian layout (opcode in the
+    /// low 16 bits, argument in the high 32), rejecting unknown opcodes
+    /// rather than transmuting them into an invalid `InstructionType`.
+    /// Extension blocks take everything from the stack, so their argument is
+    /// ignored and replaced with the block's index.
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        let opcode = value as u16;
+        Ok(match InstructionType::try_from(opcode)? {
+            InstructionType::Extension => Self::new(
+                InstructionType::Extension,
+                (opcode - EXTENSION_OPCODE_START) as u32,
+            ),
+            name => Self::new(name, (value >> 32) as u32),
+        })
+    }
+}
+
+/// Where some bytecode couldn't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub program_counter: usize,
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Decoding failed (@{}): unknown opcode {:#06x}",
+            self.program_counter, self.opcode
+        )
+    }
+}
+
+/// Decodes bytecode passed in as 64-bit words, checking every opcode. This is
+/// what anything loading untrusted bytecode should use.
+pub fn decode(bytecode: &[u64]) -> Result<Vec<Instruction>, DecodeError> {
+    bytecode
+        .iter()
+        .enumerate()
+        .map(|(program_counter, word)| {
+            Instruction::try_from(*word).map_err(|_| DecodeError {
+                program_counter,
+                opcode: *word as u16,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_valid() {
+        assert_eq!(
+            decode(&[
+                0x0000000000000000u64, // NOOP
+                0xfffffffe00000005u64, // JUMP -2
+                0x0000000500ff003cu64, // LOAD_CONST_INT 5, with junk padding
+            ]),
+            Ok(vec![
+                Instruction::new(InstructionType::Noop, 0),
+                Instruction::new(InstructionType::Jump, 0xfffffffe),
+                Instruction::new(InstructionType::LoadConstInt, 5),
+            ])
+        );
+    }
+
+    #[test]
+    fn decode_unknown_opcode() {
+        assert_eq!(
+            decode(&[0x0000000000000000u64, 0x0000000000007fffu64]),
+            Err(DecodeError {
+                program_counter: 1,
+                opcode: 0x7fff
+            })
+        );
+        assert!(decode(&[0x0000000000000085u64]).is_err());
+    }
+
+    #[test]
+    fn decode_extension_blocks() {
+        assert_eq!(
+            decode(&[0x0000000000008000u64, 0x000000050000ffffu64]),
+            Ok(vec![
+                Instruction::new(InstructionType::Extension, 0),
+                Instruction::new(InstructionType::Extension, 0x7fff),
+            ])
+        );
+    }
+
+    #[test]
+    fn opcodes_match_all() {
+        for (index, instruction) in InstructionType::ALL.iter().enumerate() {
+            assert_eq!(*instruction as usize, index);
+            assert_eq!(InstructionType::try_from(index as u16), Ok(*instruction));
+        }
+    }
+}
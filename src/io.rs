@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+use crate::motion::{STAGE_HEIGHT, STAGE_WIDTH};
+use crate::scratch_value::ScratchValue;
+
+/// The names Scratch gives the keys that aren't a single character.
+const KEY_NAMES: [&str; 6] = [
+    "space",
+    "left arrow",
+    "up arrow",
+    "right arrow",
+    "down arrow",
+    "enter",
+];
+
+/// Turns a key from the host (a `KeyboardEvent.key`) into the name Scratch
+/// uses for it, like scratch-vm's `_keyStringToScratchKey`. Keys Scratch
+/// doesn't know about (e.g. Shift) give `None`.
+fn scratch_key_from_event(key: &str) -> Option<String> {
+    Some(match key {
+        " " => "space".into(),
+        "ArrowLeft" | "Left" => "left arrow".into(),
+        "ArrowUp" | "Up" => "up arrow".into(),
+        "ArrowRight" | "Right" => "right arrow".into(),
+        "ArrowDown" | "Down" => "down arrow".into(),
+        "Enter" => "enter".into(),
+        // Modifier keys and the like, whose names are longer than a
+        // character
+        _ if key.encode_utf16().count() != 1 => return None,
+        _ => key.to_uppercase(),
+    })
+}
+
+/// Turns the argument of "key (key) pressed?" into a Scratch key name, like
+/// scratch-vm's `_keyArgToScratchKey`: numbers are key codes, and strings
+/// other than the key names only count their first character.
+fn scratch_key_from_argument(key: &ScratchValue) -> String {
+    if let ScratchValue::Number(code) = *key {
+        // Digits, some punctuation and capital letters
+        if (48.0..=90.0).contains(&code) {
+            return char::from(code as u8).to_string();
+        }
+        let names = [
+            (32.0, "space"),
+            (37.0, "left arrow"),
+            (38.0, "up arrow"),
+            (39.0, "right arrow"),
+            (40.0, "down arrow"),
+        ];
+        if let Some((_, name)) = names.iter().find(|(named, _)| *named == code) {
+            return name.to_string();
+        }
+    }
+    let key = key.as_str();
+    if KEY_NAMES.contains(&&*key) {
+        return key.into_owned();
+    }
+    match key.chars().next() {
+        Some(' ') => "space".into(),
+        Some(first) => first.to_uppercase().collect(),
+        None => String::new(),
+    }
+}
+
+/// Whether a "when (key) key pressed" hat's key matches a key pressed with
+/// the Scratch name `key`. Hats for "any" match every key.
+pub fn key_matches(hat_key: &str, key: &str) -> bool {
+    hat_key == "any" || hat_key.to_uppercase() == key.to_uppercase()
+}
+
+/// The keys being held down, by Scratch name, in the order they were
+/// pressed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Keyboard {
+    pub pressed: Vec<String>,
+}
+
+impl Keyboard {
+    /// Presses or releases `key` (a `KeyboardEvent.key`), returning its
+    /// Scratch name if it was pressed and Scratch knows it. Held keys repeat,
+    /// so a key can be pressed again without being released.
+    pub fn post(&mut self, key: &str, down: bool) -> Option<String> {
+        let key = scratch_key_from_event(key)?;
+        let index = self.pressed.iter().position(|pressed| *pressed == key);
+        match (down, index) {
+            (true, None) => self.pressed.push(key.clone()),
+            (false, Some(index)) => {
+                self.pressed.remove(index);
+            }
+            _ => {}
+        }
+        down.then_some(key)
+    }
+
+    /// The "key (key) pressed?" block.
+    pub fn is_down(&self, key: &ScratchValue) -> bool {
+        if matches!(key, ScratchValue::String(key) if key == "any") {
+            return !self.pressed.is_empty();
+        }
+        let key = scratch_key_from_argument(key);
+        self.pressed.contains(&key)
+    }
+}
+
+/// Where the mouse pointer is on the stage and whether its button is down.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mouse {
+    pub x: f64,
+    pub y: f64,
+    pub down: bool,
+}
+
+impl Mouse {
+    /// Moves the pointer to (`x`, `y`) in stage coordinates, which are kept
+    /// on the stage and rounded like scratch-vm does with the pointer's
+    /// position on the canvas.
+    pub fn move_to(&mut self, x: f64, y: f64) {
+        let (right, top) = (STAGE_WIDTH / 2.0, STAGE_HEIGHT / 2.0);
+        // JS's `Math.round` rounds halves up
+        self.x = (x.clamp(-right, right) + 0.5).floor();
+        self.y = (y.clamp(-top, top) + 0.5).floor();
+    }
+}
+
+/// The keyboard and mouse (and the last answer typed in), which the host
+/// tells the runtime about and the sensing instructions read (scratch-vm's
+/// `ioDevices`).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IoDevices {
+    pub keyboard: Keyboard,
+    pub mouse: Mouse,
+    /// The answer to the last question from "ask (question) and wait", for
+    /// the "answer" block.
+    pub answer: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_get_scratch_names() {
+        let mut keyboard = Keyboard::default();
+        assert_eq!(keyboard.post(" ", true).as_deref(), Some("space"));
+        assert_eq!(keyboard.post("a", true).as_deref(), Some("A"));
+        assert_eq!(keyboard.post("ArrowUp", true).as_deref(), Some("up arrow"));
+        assert_eq!(keyboard.post("Shift", true), None);
+        assert_eq!(keyboard.pressed, ["space", "A", "up arrow"]);
+
+        for (key, down) in [
+            (ScratchValue::String("space".into()), true),
+            (ScratchValue::String("a".into()), true),
+            // Only the first character counts
+            (ScratchValue::String("apple".into()), true),
+            (ScratchValue::String("up arrow".into()), true),
+            (ScratchValue::String("b".into()), false),
+            (ScratchValue::Number(65.0), true),
+            (ScratchValue::Number(32.0), true),
+            (ScratchValue::Number(38.0), true),
+            (ScratchValue::Number(1.0), false),
+            (ScratchValue::String("any".into()), true),
+        ] {
+            assert_eq!(keyboard.is_down(&key), down, "{:?}", key);
+        }
+
+        keyboard.post("A", false);
+        keyboard.post(" ", false);
+        keyboard.post("ArrowUp", false);
+        assert!(!keyboard.is_down(&ScratchValue::String("any".into())));
+    }
+
+    #[test]
+    fn mouse_stays_on_stage() {
+        let mut mouse = Mouse::default();
+        mouse.move_to(-300.0, 10.5);
+        assert_eq!((mouse.x, mouse.y), (-240.0, 11.0));
+        mouse.move_to(12.4, -1000.0);
+        assert_eq!((mouse.x, mouse.y), (12.0, -180.0));
+    }
+}
@@ -1,16 +1,60 @@
+pub mod assembler;
+pub mod bubble;
+pub mod clock;
+pub mod collision;
+pub mod debugger;
 mod execute_instruction;
-mod instruction;
-mod runner;
-mod scratch_value;
+pub mod extension;
+pub mod hat;
+pub mod host;
+pub mod instruction;
+pub mod io;
+pub mod limits;
+pub mod looks;
+pub mod monitor;
+pub mod motion;
+pub mod pen;
+#[cfg(feature = "profiling")]
+pub mod profiler;
+pub mod question;
+pub mod random;
+pub mod replay;
+pub mod runner;
+pub mod runtime;
+pub mod scratch_value;
+pub mod sensing;
+pub mod snapshot;
+pub mod sound;
+pub mod target;
+pub mod thread;
 mod utils;
+pub mod verifier;
 
-use std::convert::TryInto;
+use std::{
+    cell::RefCell,
+    convert::{TryFrom, TryInto},
+    rc::Rc,
+};
 
-use instruction::Instruction;
-use js_sys::{Array, Reflect};
-use runner::run_instructions;
+use clock::{Clock, SystemClock};
+use debugger::{Debugger, TraceEvent, TraceHook};
+use extension::{BlockSignature, BlockType, JsExtensionBlock};
+use hat::Hat;
+use host::{clear_js_host_functions, register_js_host_function, JsHost};
+use instruction::{decode, Instruction, ReturnReason};
+use js_sys::{Array, Function, Object, Promise, Reflect};
+use limits::{lists_size, LimitMode, ListLimits};
+use looks::Costume;
+use motion::{Bounds, RotationStyle};
+use random::{MathRandom, SeededRandom};
+use replay::Recording;
+use runner::{run_instructions, Budget, Context, StandaloneContext};
+use runtime::Runtime;
 use scratch_value::ScratchValue;
+use snapshot::Snapshot;
+use sound::{JsSoundPlayer, Sound};
 use utils::set_panic_hook;
+use verifier::{verify, StoreSizes};
 use wasm_bindgen::prelude::*;
 use web_sys::console;
 
@@ -28,43 +72,47 @@ const TS_DECLARATION: &'static str = r#"
 export type VariableStore = Map<number, string | number | boolean>; 
 "#;
 
+/// Registers a JS function that `CALL_HOST` instructions with the argument `id`
+/// will call. The function is called with the popped arguments; if it returns
+/// a `Promise` and the call is awaited, `run_sync` returns with the promise as
+/// `hostPromise`, and the resolved value should be pushed onto the stack when
+/// resuming.
+#[wasm_bindgen]
+pub fn register_host_function(id: u32, callback: Function) {
+    register_js_host_function(id, callback);
+}
+
+/// Unregisters all the functions registered by `register_host_function`.
+#[wasm_bindgen]
+pub fn clear_host_functions() {
+    clear_js_host_functions();
+}
+
+/// Reinterprets bytecode in place without checking the opcodes. Only for
+/// tests, where the bytecode is known to be valid; everything else goes
+/// through `load_instructions`.
+#[cfg(test)]
 unsafe fn transmute_instructions(bytecode: &[u64]) -> &[Instruction] {
     std::mem::transmute::<&[u64], &[Instruction]>(bytecode)
 }
 
-/// For lists, they are passed as strings with the null character as the list
-/// item separator
-/// This function is the "glue" binding the real logic in runner.rs to JS
-#[wasm_bindgen]
-pub fn run_sync(
-    initial_program_counter: usize,
-    initial_stack: Vec<JsValue>,
-    bytecode: &[u64],
-    constants_vec: Vec<JsValue>,
-    variables_vec: Vec<JsValue>,
-    lists_vec: Vec<JsValue>,
-) -> Result<js_sys::Object, JsValue> {
-    // Set the panic hook (remove if too slow? maybe just call init() from js?)
-    // In theory it no-ops if already set
-    set_panic_hook();
-    // Load the instructions unsafely
-    let instructions = unsafe { transmute_instructions(bytecode) };
-    // Set up the stack
-    let mut stack: Vec<ScratchValue> = Vec::with_capacity(initial_stack.len());
-    for stack_item in initial_stack {
-        stack.push(stack_item.try_into()?);
-    }
-    // Load the constants from the map
-    let mut constants: Vec<ScratchValue> = Vec::with_capacity(constants_vec.len());
-    for constant in constants_vec {
-        constants.push(constant.try_into()?);
-    }
-    // Load the variables from the map
-    let mut variables: Vec<ScratchValue> = Vec::with_capacity(variables_vec.len());
-    for variable in variables_vec {
-        variables.push(variable.try_into()?);
-    }
-    // Load the lists from the map
+/// Decodes the bytecode passed in from JS, rejecting unknown opcodes.
+fn load_instructions(bytecode: &[u64]) -> Result<Vec<Instruction>, JsValue> {
+    decode(bytecode).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Converts the JS values passed in for a store into `ScratchValue`s.
+fn load_values(values: Vec<JsValue>) -> Result<Vec<ScratchValue>, JsValue> {
+    let mut loaded: Vec<ScratchValue> = Vec::with_capacity(values.len());
+    for value in values {
+        loaded.push(value.try_into()?);
+    }
+    Ok(loaded)
+}
+
+/// Loads lists, which are passed as strings with the null character as the
+/// list item separator.
+fn load_lists(lists_vec: Vec<JsValue>) -> Result<Vec<Vec<ScratchValue>>, JsValue> {
     let mut lists: Vec<Vec<ScratchValue>> = Vec::with_capacity(lists_vec.len());
     for list in lists_vec {
         let list_contents = list.as_string().ok_or("failed to parse list")?;
@@ -82,49 +130,115 @@ pub fn run_sync(
         }
         lists.push(items);
     }
+    Ok(lists)
+}
 
+fn values_to_js(values: impl IntoIterator<Item = ScratchValue>) -> Array {
+    values.into_iter().map(Into::<JsValue>::into).collect()
+}
+
+fn lists_to_js(lists: impl IntoIterator<Item = Vec<ScratchValue>>) -> Array {
+    lists
+        .into_iter()
+        .map(|v| {
+            Into::<JsValue>::into(
+                v.into_iter()
+                    .map(Into::<String>::into)
+                    .collect::<Vec<_>>()
+                    .join("\0"),
+            )
+        })
+        .collect()
+}
+
+/// Statically checks the bytecode (see verifier.rs) from each of the
+/// `entry_points`, returning the maximum stack height or throwing with the
+/// first problem found
+#[wasm_bindgen]
+pub fn verify_bytecode(
+    bytecode: &[u64],
+    entry_points: Vec<usize>,
+    constant_count: usize,
+    variable_count: usize,
+    list_count: usize,
+) -> Result<usize, JsValue> {
+    let instructions = load_instructions(bytecode)?;
+    verify(
+        &instructions,
+        &entry_points,
+        Some(StoreSizes {
+            constants: constant_count,
+            variables: variable_count,
+            lists: list_count,
+        }),
+        &[],
+    )
+    .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// For lists, they are passed as strings with the null character as the list
+/// item separator
+/// If given, `instruction_budget` limits the number of instructions run and
+/// `time_budget` the number of milliseconds taken before returning with
+/// `BudgetExhausted`, with the program counter where execution should resume
+/// This function is the "glue" binding the real logic in runner.rs to JS
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn run_sync(
+    initial_program_counter: usize,
+    initial_stack: Vec<JsValue>,
+    bytecode: &[u64],
+    constants_vec: Vec<JsValue>,
+    variables_vec: Vec<JsValue>,
+    lists_vec: Vec<JsValue>,
+    instruction_budget: Option<u32>,
+    time_budget: Option<f64>,
+) -> Result<js_sys::Object, JsValue> {
+    // Set the panic hook (remove if too slow? maybe just call init() from js?)
+    // In theory it no-ops if already set
+    set_panic_hook();
+    let instructions = load_instructions(bytecode)?;
+    // Set up the stack and load the stores
+    let mut stack = load_values(initial_stack)?;
+    let constants = load_values(constants_vec)?;
+    let mut variables = load_values(variables_vec)?;
+    let mut lists = load_lists(lists_vec)?;
+
+    let mut list_size = lists_size(&lists);
     let mut program_counter = initial_program_counter;
+    let mut host = JsHost::default();
+    let clock = SystemClock;
+    let mut budget = Budget::new(
+        instruction_budget.map(Into::into),
+        time_budget.map(|time_budget| (clock.now() + time_budget, &clock as &dyn Clock)),
+    );
     let return_reason = run_instructions(
         &mut program_counter,
         &mut stack,
-        instructions,
+        &instructions,
         &constants,
         &mut variables,
         &mut lists,
-    )?;
+        &mut host,
+        &mut budget,
+        &mut Context {
+            list_size: &mut list_size,
+            clock: &clock,
+            random: &mut MathRandom,
+            ..StandaloneContext::default().context()
+        },
+        None,
+    )
+    .map_err(|err| JsValue::from_str(&err))?;
     // Load the variable store into a Map for the response
     let response = js_sys::Object::new();
     Reflect::set(
         &response,
         &JsValue::from_str("variables"),
-        &variables
-            .into_iter()
-            .map(|item| Into::<JsValue>::into(item))
-            .collect::<Array>(),
-    )?;
-    Reflect::set(
-        &response,
-        &JsValue::from_str("lists"),
-        &lists
-            .into_iter()
-            .map(|v| {
-                Into::<JsValue>::into(
-                    v.into_iter()
-                        .map(|item| Into::<String>::into(item))
-                        .collect::<Vec<_>>()
-                        .join("\0"),
-                )
-            })
-            .collect::<Array>(),
-    )?;
-    Reflect::set(
-        &response,
-        &JsValue::from_str("stack"),
-        &stack
-            .into_iter()
-            .map(|item| Into::<JsValue>::into(item))
-            .collect::<Array>(),
+        &values_to_js(variables),
     )?;
+    Reflect::set(&response, &JsValue::from_str("lists"), &lists_to_js(lists))?;
+    Reflect::set(&response, &JsValue::from_str("stack"), &values_to_js(stack))?;
     Reflect::set(
         &response,
         &JsValue::from_str("programCounter"),
@@ -136,6 +250,718 @@ pub fn run_sync(
             &JsValue::from_str("returnReason"),
             &JsValue::from_f64(return_argument as f64),
         )?;
+        if return_argument == ReturnReason::HostPromise as u32 {
+            if let Some(promise) = host.pending_promise {
+                Reflect::set(&response, &JsValue::from_str("hostPromise"), &promise)?;
+            }
+        }
     }
     Ok(response)
 }
+
+/// The scheduler from runtime.rs for JS, which keeps the program, stores and
+/// threads around between ticks
+#[wasm_bindgen(js_name = Runtime)]
+pub struct JsRuntime {
+    runtime: Runtime,
+    parked_threads: Rc<RefCell<Vec<(u32, Promise)>>>,
+}
+
+#[wasm_bindgen(js_class = Runtime)]
+impl JsRuntime {
+    /// Takes the same bytecode and stores as `run_sync`
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        bytecode: &[u64],
+        constants_vec: Vec<JsValue>,
+        variables_vec: Vec<JsValue>,
+        lists_vec: Vec<JsValue>,
+    ) -> Result<JsRuntime, JsValue> {
+        set_panic_hook();
+        let instructions = load_instructions(bytecode)?;
+        let mut runtime = Runtime::new(
+            instructions,
+            load_values(constants_vec)?,
+            load_values(variables_vec)?,
+            load_lists(lists_vec)?,
+        );
+        let host = JsHost::default();
+        let parked_threads = host.parked_threads.clone();
+        runtime.set_host(Box::new(host));
+        Ok(JsRuntime {
+            runtime,
+            parked_threads,
+        })
+    }
+
+    pub fn add_target(&mut self, name: String, is_stage: bool) -> u32 {
+        self.runtime.add_target(name, is_stage)
+    }
+
+    pub fn start_thread(&mut self, target: u32, program_counter: usize) -> u32 {
+        self.runtime.start_thread(target, program_counter)
+    }
+
+    /// Makes the script at `program_counter` start whenever `key` is pressed.
+    /// `key` is the name from the block's menu: a letter or digit, "space",
+    /// "left arrow" etc., or "any".
+    pub fn add_key_pressed_hat(
+        &mut self,
+        target: u32,
+        key: String,
+        program_counter: usize,
+    ) -> Result<(), JsValue> {
+        Ok(self
+            .runtime
+            .add_hat(target, Hat::KeyPressed(key), program_counter)?)
+    }
+
+    /// Makes the script at `program_counter` start whenever the target is
+    /// clicked.
+    pub fn add_clicked_hat(&mut self, target: u32, program_counter: usize) -> Result<(), JsValue> {
+        Ok(self
+            .runtime
+            .add_hat(target, Hat::Clicked, program_counter)?)
+    }
+
+    /// Puts a sprite where the project says it starts, without keeping it on
+    /// the stage. `rotation_style` is 0 for all around, 1 for left-right and
+    /// 2 for don't rotate.
+    pub fn set_motion(
+        &mut self,
+        target: u32,
+        x: f64,
+        y: f64,
+        direction: f64,
+        rotation_style: u32,
+    ) -> Result<(), JsValue> {
+        let rotation_style = RotationStyle::try_from(rotation_style)?;
+        let motion = &mut self
+            .runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .motion;
+        motion.x = x;
+        motion.y = y;
+        motion.set_direction(direction);
+        motion.rotation_style = rotation_style;
+        motion.transform_changed = true;
+        Ok(())
+    }
+
+    /// Updates a sprite's bounding box (relative to its position) after its
+    /// costume or size changed, which keeps it on the stage and makes it
+    /// bounce off the edges.
+    pub fn set_bounds(
+        &mut self,
+        target: u32,
+        left: f64,
+        right: f64,
+        bottom: f64,
+        top: f64,
+    ) -> Result<(), JsValue> {
+        self.runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .motion
+            .bounds = Bounds {
+            left,
+            right,
+            bottom,
+            top,
+        };
+        Ok(())
+    }
+
+    /// The speech and thought bubbles that changed since this was last
+    /// called, as `[targetId, "say" | "think", text]` arrays. An empty text
+    /// means the bubble should be hidden.
+    pub fn take_bubble_changes(&mut self) -> Array {
+        self.runtime
+            .take_bubble_changes()
+            .into_iter()
+            .map(|event| {
+                Into::<JsValue>::into(Array::of3(
+                    &JsValue::from_f64(event.target as f64),
+                    &JsValue::from_str(event.kind.name()),
+                    &JsValue::from_str(&event.text),
+                ))
+            })
+            .collect()
+    }
+
+    /// Gives a target its costumes (or backdrops), in order. `sizes` holds
+    /// each costume's width and height one after the other; they limit how
+    /// big the sprite can get, and can be 0 if unknown.
+    pub fn set_costumes(
+        &mut self,
+        target: u32,
+        names: Vec<String>,
+        sizes: Vec<f64>,
+    ) -> Result<(), JsValue> {
+        if sizes.len() != names.len() * 2 {
+            return Err("there should be a width and height for every costume".into());
+        }
+        let looks = &mut self
+            .runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .looks;
+        looks.costumes = names
+            .into_iter()
+            .zip(sizes.chunks(2))
+            .map(|(name, size)| Costume {
+                name,
+                width: size[0],
+                height: size[1],
+            })
+            .collect();
+        looks.costume = looks.costume.min(looks.costumes.len().saturating_sub(1));
+        looks.render_changed = true;
+        Ok(())
+    }
+
+    /// Tells the runtime the names of a target's own variables (the global
+    /// ones, for the stage), with `variables` holding each one's index, so the
+    /// "of" block can find them.
+    pub fn set_variable_names(
+        &mut self,
+        target: u32,
+        names: Vec<String>,
+        variables: Vec<u32>,
+    ) -> Result<(), JsValue> {
+        if variables.len() != names.len() {
+            return Err("there should be an index for every variable name".into());
+        }
+        if variables
+            .iter()
+            .any(|index| *index as usize >= self.runtime.variables.len())
+        {
+            return Err("no variable with that index".into());
+        }
+        self.runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .variables = names.into_iter().zip(variables).collect();
+        Ok(())
+    }
+
+    /// Sets a target's costume (zero-based), size and visibility to what the
+    /// project says they start as.
+    pub fn set_looks(
+        &mut self,
+        target: u32,
+        costume: usize,
+        size: f64,
+        visible: bool,
+    ) -> Result<(), JsValue> {
+        let looks = &mut self
+            .runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .looks;
+        looks.set_costume(costume as f64);
+        looks.size = size;
+        looks.visible = visible;
+        looks.render_changed = true;
+        Ok(())
+    }
+
+    /// Gives a target its sounds, in order, and the volume it starts at.
+    /// `sample_counts` and `rates` hold each sound's length in samples and
+    /// samples per second, which are how long "play sound until done" waits.
+    pub fn set_sounds(
+        &mut self,
+        target: u32,
+        names: Vec<String>,
+        sample_counts: Vec<u32>,
+        rates: Vec<u32>,
+        volume: f64,
+    ) -> Result<(), JsValue> {
+        if sample_counts.len() != names.len() || rates.len() != names.len() {
+            return Err("there should be a sample count and rate for every sound".into());
+        }
+        let sound = &mut self
+            .runtime
+            .target_mut(target)
+            .ok_or("no target with that id")?
+            .sound;
+        sound.sounds = names
+            .into_iter()
+            .zip(sample_counts.into_iter().zip(rates))
+            .map(|(name, (sample_count, rate))| Sound {
+                name,
+                sample_count,
+                rate,
+            })
+            .collect();
+        sound.set_volume(volume);
+        Ok(())
+    }
+
+    /// Plays sounds with `player`, an object with `play(target, sound,
+    /// volume, pitch, pan)`, `stop(target)` and `update(target, volume, pitch,
+    /// pan)` methods (see `JsSoundPlayer`). Without one, sounds only take
+    /// time.
+    pub fn set_sound_player(&mut self, player: JsValue) {
+        self.runtime
+            .set_sound_player(Box::new(JsSoundPlayer { player }));
+    }
+
+    /// The targets whose costume, size, visibility, layer or effects changed
+    /// since this was last called, as `[id, costume, size, visible, layer,
+    /// ...effects]` runs of 12 numbers in one flat array. The effects are in
+    /// the order color, fisheye, whirl, pixelate, mosaic, brightness, ghost.
+    pub fn take_render_changes(&mut self) -> Vec<f64> {
+        let changed = self.runtime.take_render_changes();
+        let mut changes = Vec::with_capacity(changed.len() * 12);
+        for id in changed {
+            if let Some(target) = self.runtime.target(id) {
+                let looks = &target.looks;
+                changes.extend([
+                    id as f64,
+                    looks.costume as f64,
+                    looks.size,
+                    looks.visible as u8 as f64,
+                    looks.layer as f64,
+                ]);
+                changes.extend(looks.effects);
+            }
+        }
+        changes
+    }
+
+    /// The sprites that moved, turned or changed rotation style since this
+    /// was last called, as `[id, x, y, direction, rotationStyle]` runs in one
+    /// flat array. Call it after `step` and update the renderer with them.
+    pub fn take_transform_changes(&mut self) -> Vec<f64> {
+        let changed = self.runtime.take_transform_changes();
+        let mut transforms = Vec::with_capacity(changed.len() * 5);
+        for id in changed {
+            if let Some(target) = self.runtime.target(id) {
+                let motion = &target.motion;
+                transforms.extend([
+                    id as f64,
+                    motion.x,
+                    motion.y,
+                    motion.direction,
+                    motion.rotation_style as u32 as f64,
+                ]);
+            }
+        }
+        transforms
+    }
+
+    /// Runs one tick, returning `[threadId, promise]` pairs for the threads
+    /// that were parked on a host promise during it. Once a promise resolves,
+    /// pass the value to `resolve_host_promise`.
+    pub fn step(&mut self) -> Result<Array, JsValue> {
+        self.runtime.step().map_err(|err| JsValue::from_str(&err))?;
+        Ok(self
+            .parked_threads
+            .borrow_mut()
+            .drain(..)
+            .map(|(thread, promise)| {
+                Into::<JsValue>::into(Array::of2(&JsValue::from_f64(thread as f64), &promise))
+            })
+            .collect())
+    }
+
+    /// Resumes a thread parked on a host promise. `value` is dropped if the
+    /// awaited block was a command and cast if it was a Boolean, like when
+    /// there's no promise.
+    pub fn resolve_host_promise(&mut self, thread: u32, value: JsValue) -> Result<(), JsValue> {
+        let value = if value.is_undefined() {
+            None
+        } else {
+            Some(value.try_into()?)
+        };
+        Ok(self.runtime.resolve_host_promise(thread, value)?)
+    }
+
+    /// Registers a TurboWarp-style extension object. `getInfo()` gives its
+    /// `id` and `blocks`, and each command, reporter or Boolean block is run
+    /// by calling the method named after its `opcode` with an object of its
+    /// `arguments`, which are popped in the order they're listed. Methods can
+    /// return a `Promise`, which the thread waits for like an awaited host
+    /// call. Returns an object mapping each block's `opcode` to the bytecode
+    /// opcode that runs it.
+    pub fn register_extension(&mut self, extension: JsValue) -> Result<Object, JsValue> {
+        let get = |object: &JsValue, key: &str| Reflect::get(object, &JsValue::from_str(key));
+        let info = get(&extension, "getInfo")?
+            .dyn_into::<Function>()
+            .map_err(|_| "extension has no getInfo method")?
+            .call0(&extension)?;
+        let id = get(&info, "id")?
+            .as_string()
+            .ok_or("extension info has no id")?;
+        let opcodes = Object::new();
+        for block in Array::from(&get(&info, "blocks")?).iter() {
+            // Separators are strings, and hats, buttons and labels can't be
+            // run
+            let (opcode, block_type) = match (
+                get(&block, "opcode")?.as_string(),
+                get(&block, "blockType")?.as_string(),
+            ) {
+                (Some(opcode), Some(block_type)) => {
+                    match BlockType::try_from(block_type.as_str()) {
+                        Ok(block_type) => (opcode, block_type),
+                        Err(_) => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let arguments = get(&block, "arguments")?;
+            let argument_names = if arguments.is_object() {
+                Object::keys(arguments.unchecked_ref::<Object>())
+                    .iter()
+                    .filter_map(|name| name.as_string())
+                    .collect::<Vec<_>>()
+            } else {
+                Vec::new()
+            };
+            let signature = BlockSignature {
+                extension: id.clone(),
+                block: opcode.clone(),
+                arity: argument_names.len() as u16,
+                block_type,
+            };
+            let handler = JsExtensionBlock {
+                extension: extension.clone(),
+                method: opcode.clone(),
+                argument_names,
+                pending_promise: None,
+                parked_threads: self.parked_threads.clone(),
+            };
+            let bytecode_opcode = self
+                .runtime
+                .register_extension_block(signature, Box::new(handler))?;
+            Reflect::set(
+                &opcodes,
+                &JsValue::from_str(&opcode),
+                &JsValue::from_f64(bytecode_opcode as f64),
+            )?;
+        }
+        Ok(opcodes)
+    }
+
+    /// Like `verify_bytecode`, for the loaded program and the extension
+    /// blocks registered so far.
+    pub fn verify(&self, entry_points: Vec<usize>) -> Result<usize, JsValue> {
+        let runtime = &self.runtime;
+        verify(
+            &runtime.instructions,
+            &entry_points,
+            Some(StoreSizes {
+                constants: runtime.constants.len(),
+                variables: runtime.variables.len(),
+                lists: runtime.lists.len(),
+            }),
+            runtime.extensions().signatures(),
+        )
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// Presses a key, given as a `KeyboardEvent.key`.
+    pub fn key_down(&mut self, key: &str) -> Result<(), JsValue> {
+        Ok(self.runtime.key_down(key)?)
+    }
+
+    pub fn key_up(&mut self, key: &str) -> Result<(), JsValue> {
+        Ok(self.runtime.key_up(key)?)
+    }
+
+    /// Moves the mouse pointer, in stage coordinates (so (0, 0) is the
+    /// middle of the stage and y goes up).
+    pub fn mouse_move(&mut self, x: f64, y: f64) -> Result<(), JsValue> {
+        Ok(self.runtime.mouse_move(x, y)?)
+    }
+
+    pub fn mouse_down(&mut self) -> Result<(), JsValue> {
+        Ok(self.runtime.mouse_down()?)
+    }
+
+    pub fn mouse_up(&mut self) -> Result<(), JsValue> {
+        Ok(self.runtime.mouse_up()?)
+    }
+
+    /// What the question prompt should say, or `undefined` if it should be
+    /// hidden. It's empty when the question is in a sprite's speech bubble
+    /// instead, so only the text box is needed.
+    pub fn question(&self) -> Option<String> {
+        self.runtime.questions.prompt().map(String::from)
+    }
+
+    /// Whether the question being asked changed since this was last called.
+    pub fn question_changed(&mut self) -> bool {
+        std::mem::take(&mut self.runtime.questions.changed)
+    }
+
+    /// Answers the question being asked, resuming the script that asked it.
+    pub fn answer(&mut self, answer: &str) -> Result<(), JsValue> {
+        Ok(self.runtime.answer(answer)?)
+    }
+
+    /// What the pens have drawn, as 480x360 RGBA pixels (not premultiplied)
+    /// row by row from the top left, ready to upload as a texture.
+    pub fn pen_layer(&self) -> Vec<u8> {
+        self.runtime.pen_layer.to_rgba()
+    }
+
+    /// Whether anything was drawn or erased since this was last called.
+    pub fn pen_layer_changed(&mut self) -> bool {
+        std::mem::take(&mut self.runtime.pen_layer.changed)
+    }
+
+    pub fn set_budget(&mut self, instruction_budget: Option<u32>, time_budget: Option<f64>) {
+        self.runtime.instruction_budget = instruction_budget.map(Into::into);
+        self.runtime.time_budget = time_budget;
+    }
+
+    pub fn set_turbo_mode(&mut self, turbo_mode: bool) {
+        self.runtime.turbo_mode = turbo_mode;
+    }
+
+    /// Caps how many items each list may hold and, if given, roughly how many
+    /// bytes all the lists together may take up. Adding past a cap is ignored
+    /// like in Scratch, unless `report_errors` is set, in which case the
+    /// thread fails.
+    pub fn set_list_limits(
+        &mut self,
+        max_items_per_list: usize,
+        max_total_size: Option<usize>,
+        report_errors: bool,
+    ) {
+        self.runtime.list_limits = ListLimits {
+            max_items_per_list,
+            max_total_size,
+            mode: if report_errors {
+                LimitMode::Error
+            } else {
+                LimitMode::Ignore
+            },
+        };
+    }
+
+    /// Makes "pick random" and random list indices repeatable. Otherwise the
+    /// generator is seeded from the time.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.runtime.set_random(Box::new(SeededRandom::new(seed)));
+    }
+
+    pub fn redraw_requested(&self) -> bool {
+        self.runtime.redraw_requested
+    }
+
+    pub fn budget_exhausted(&self) -> bool {
+        self.runtime.budget_exhausted
+    }
+
+    /// Turns the debugger on or off. The other debugger methods turn it on if
+    /// needed.
+    pub fn set_debugger_enabled(&mut self, enabled: bool) {
+        self.runtime.debugger = if enabled { Some(Debugger::new()) } else { None };
+    }
+
+    pub fn add_breakpoint(&mut self, program_counter: usize) {
+        self.debugger().breakpoints.insert(program_counter);
+    }
+
+    pub fn remove_breakpoint(&mut self, program_counter: usize) {
+        self.debugger().breakpoints.remove(&program_counter);
+    }
+
+    pub fn set_single_step(&mut self, single_step: bool) {
+        self.debugger().single_step = single_step;
+    }
+
+    /// Makes single-stepping apply to the thread `thread`, or to whichever
+    /// runs next if it's `undefined`.
+    pub fn set_stepping_thread(&mut self, thread: Option<u32>) {
+        self.runtime.stepping_thread = thread;
+    }
+
+    /// Calls `hook` after every instruction with an object containing the
+    /// `programCounter`, `opcode`, `argument`, `stackBefore` and `stackAfter`.
+    pub fn set_trace_hook(&mut self, hook: Option<Function>) {
+        self.debugger().trace_hook = hook.map(|hook| -> TraceHook {
+            Box::new(move |event: &TraceEvent| {
+                let object = js_sys::Object::new();
+                // Setting properties on a fresh object can't fail
+                let _ = Reflect::set(
+                    &object,
+                    &JsValue::from_str("programCounter"),
+                    &JsValue::from_f64(event.program_counter as f64),
+                );
+                let _ = Reflect::set(
+                    &object,
+                    &JsValue::from_str("opcode"),
+                    &JsValue::from_f64(event.instruction as u16 as f64),
+                );
+                let _ = Reflect::set(
+                    &object,
+                    &JsValue::from_str("argument"),
+                    &JsValue::from_f64(event.argument as f64),
+                );
+                let _ = Reflect::set(
+                    &object,
+                    &JsValue::from_str("stackBefore"),
+                    &values_to_js(event.stack_before.iter().cloned()),
+                );
+                let _ = Reflect::set(
+                    &object,
+                    &JsValue::from_str("stackAfter"),
+                    &values_to_js(event.stack_after.iter().cloned()),
+                );
+                if let Err(exception) = hook.call1(&JsValue::NULL, &object) {
+                    console::error_1(&exception);
+                }
+            })
+        });
+    }
+
+    pub fn resume(&mut self) {
+        self.runtime.resume();
+    }
+
+    /// The ID of the thread the debugger paused, if any.
+    pub fn paused_thread(&self) -> Option<u32> {
+        self.runtime.paused_thread
+    }
+
+    pub fn variables(&self) -> Array {
+        values_to_js(self.runtime.variables.iter().cloned())
+    }
+
+    /// The lists, in the same null-separated format as they're passed in.
+    pub fn lists(&self) -> Array {
+        lists_to_js(self.runtime.lists.iter().cloned())
+    }
+
+    pub fn thread_stack(&self, thread: u32) -> Option<Array> {
+        self.runtime
+            .thread(thread)
+            .map(|thread| values_to_js(thread.stack.iter().cloned()))
+    }
+
+    pub fn thread_program_counter(&self, thread: u32) -> Option<usize> {
+        self.runtime
+            .thread(thread)
+            .map(|thread| thread.program_counter)
+    }
+
+    /// The indices of the variables with visible monitors.
+    pub fn visible_variable_monitors(&self) -> Vec<u32> {
+        self.runtime.monitors.variables.iter().copied().collect()
+    }
+
+    /// The indices of the lists with visible monitors.
+    pub fn visible_list_monitors(&self) -> Vec<u32> {
+        self.runtime.monitors.lists.iter().copied().collect()
+    }
+
+    /// Whether a monitor was shown or hidden since this was last called.
+    pub fn monitors_changed(&mut self) -> bool {
+        std::mem::take(&mut self.runtime.monitors.changed)
+    }
+
+    /// Saves everything the runtime is doing in the binary format described
+    /// in snapshot.rs, e.g. for save states or crash reports.
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.runtime.snapshot().to_bytes()
+    }
+
+    /// Puts back a snapshot taken by `snapshot` of a runtime with the same
+    /// program.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), JsValue> {
+        Ok(self.runtime.restore(Snapshot::from_bytes(snapshot)?)?)
+    }
+
+    /// Starts logging every random number, clock reading and host call
+    /// result, so the session can be replayed exactly with `start_replay`.
+    pub fn start_recording(&mut self) {
+        self.runtime.start_recording();
+    }
+
+    /// Stops recording and returns the recording, in the same binary format
+    /// as snapshots.
+    pub fn stop_recording(&mut self) -> Option<Vec<u8>> {
+        self.runtime
+            .stop_recording()
+            .map(|recording| recording.to_bytes())
+    }
+
+    /// Goes back to where the recording started and replays it over the
+    /// following ticks, without calling the host functions. A tick throws if
+    /// the replay diverges from the recording.
+    pub fn start_replay(&mut self, recording: &[u8]) -> Result<(), JsValue> {
+        Ok(self
+            .runtime
+            .start_replay(Recording::from_bytes(recording)?)?)
+    }
+
+    pub fn stop_replay(&mut self) {
+        self.runtime.stop_replay();
+    }
+
+    /// The number of inputs left to replay, or `undefined` if not replaying.
+    pub fn replay_remaining(&self) -> Option<usize> {
+        self.runtime.replay_remaining()
+    }
+}
+
+#[cfg(feature = "snapshot_json")]
+#[wasm_bindgen(js_class = Runtime)]
+impl JsRuntime {
+    /// Like `snapshot`, but as JSON that's easier to read. The JSON should be
+    /// passed back to `restore_json` as it is, since `JSON.parse` loses
+    /// precision in the 64-bit numbers.
+    pub fn snapshot_json(&self) -> Result<String, JsValue> {
+        serde_json::to_string(&self.runtime.snapshot())
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    pub fn restore_json(&mut self, snapshot: &str) -> Result<(), JsValue> {
+        let snapshot =
+            serde_json::from_str(snapshot).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(self.runtime.restore(snapshot)?)
+    }
+}
+
+#[cfg(feature = "profiling")]
+#[wasm_bindgen(js_class = Runtime)]
+impl JsRuntime {
+    /// Starts profiling every instruction, throwing away anything recorded
+    /// so far.
+    pub fn start_profiling(&mut self) {
+        self.debugger().profiler = Some(profiler::Profiler::new());
+    }
+
+    /// Names a range of program counters (e.g. a script) for the profile.
+    pub fn add_profile_range(&mut self, name: String, start: usize, end: usize) {
+        if let Some(profiler) = self.debugger().profiler.as_mut() {
+            profiler.add_range(name, start, end);
+        }
+    }
+
+    pub fn profile_json(&mut self) -> Option<String> {
+        self.debugger()
+            .profiler
+            .as_ref()
+            .map(|profiler| profiler.to_json())
+    }
+
+    /// The profile as folded stacks, for flame graph tools.
+    pub fn profile_folded(&mut self) -> Option<String> {
+        self.debugger()
+            .profiler
+            .as_ref()
+            .map(|profiler| profiler.to_folded())
+    }
+}
+
+impl JsRuntime {
+    fn debugger(&mut self) -> &mut Debugger {
+        self.runtime.debugger.get_or_insert_with(Debugger::new)
+    }
+}
@@ -0,0 +1,210 @@
+use std::mem::size_of;
+
+use crate::scratch_value::ScratchValue;
+
+/// The most items Scratch lets a list hold.
+pub const SCRATCH_LIST_LIMIT: usize = 200_000;
+
+/// What to do when an instruction would grow a list past a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Leave the list alone and carry on, like Scratch does.
+    Ignore,
+    /// Fail the instruction, which is more useful when debugging.
+    Error,
+}
+
+/// Caps on how big lists can get, so that a runaway script can't use up all
+/// the memory. Every instruction that adds items checks them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListLimits {
+    /// The most items any one list may hold.
+    pub max_items_per_list: usize,
+    /// Roughly how many bytes the items in all the lists together may take
+    /// up (see `item_size`), if limited.
+    pub max_total_size: Option<usize>,
+    pub mode: LimitMode,
+}
+
+impl Default for ListLimits {
+    /// Scratch's limits and behavior.
+    fn default() -> Self {
+        Self {
+            max_items_per_list: SCRATCH_LIST_LIMIT,
+            max_total_size: None,
+            mode: LimitMode::Ignore,
+        }
+    }
+}
+
+impl ListLimits {
+    /// Checks whether a list may grow to `length` items and all the lists
+    /// together to `size` bytes. Returns `Ok(false)` if the change should be
+    /// silently skipped.
+    pub(crate) fn allow_growth(&self, length: usize, size: usize) -> Result<bool, &'static str> {
+        let per_list_ok = length <= self.max_items_per_list;
+        let total_ok = self
+            .max_total_size
+            .is_none_or(|max_total_size| size <= max_total_size);
+        match (per_list_ok && total_ok, self.mode) {
+            (true, _) => Ok(true),
+            (false, LimitMode::Ignore) => Ok(false),
+            (false, LimitMode::Error) if !per_list_ok => Err("list is full"),
+            (false, LimitMode::Error) => Err("lists are using too much memory"),
+        }
+    }
+}
+
+/// Roughly how much memory `item` takes up in a list, in bytes: the value
+/// itself and any text it owns.
+pub fn item_size(item: &ScratchValue) -> usize {
+    size_of::<ScratchValue>()
+        + match item {
+            ScratchValue::String(string) => string.len(),
+            _ => 0,
+        }
+}
+
+/// Roughly how much memory the items in `lists` take up, in bytes.
+pub fn lists_size(lists: &[Vec<ScratchValue>]) -> usize {
+    lists.iter().flatten().map(item_size).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::host::NoHost;
+    use crate::runner::{run_instructions, Budget, Context, StandaloneContext};
+    use crate::transmute_instructions;
+
+    use super::*;
+
+    /// Pushes an item onto list 0 and inserts another.
+    const PUSH_AND_INSERT: &[u64] = &[
+        0x000000010000003cu64, // LOAD_CONST_INT 1
+        0x0000000000000024u64, // LIST_PUSH 0
+        0x000000010000003cu64, // LOAD_CONST_INT 1
+        0x000000020000003cu64, // LOAD_CONST_INT 2
+        0x0000000000000021u64, // LIST_INS 0
+    ];
+
+    fn run(lists: &mut [Vec<ScratchValue>], limits: &ListLimits) -> Result<Option<u32>, String> {
+        run_bytecode(PUSH_AND_INSERT, lists, limits)
+    }
+
+    fn run_bytecode(
+        bytecode: &[u64],
+        lists: &mut [Vec<ScratchValue>],
+        limits: &ListLimits,
+    ) -> Result<Option<u32>, String> {
+        let instructions = unsafe { transmute_instructions(bytecode) };
+        let mut list_size = lists_size(lists);
+        run_instructions(
+            &mut 0,
+            &mut vec![],
+            instructions,
+            &[],
+            &mut [],
+            lists,
+            &mut NoHost,
+            &mut Budget::default(),
+            &mut Context {
+                list_limits: limits,
+                list_size: &mut list_size,
+                ..StandaloneContext::default().context()
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn per_list_limit() {
+        let limits = ListLimits {
+            max_items_per_list: 2,
+            ..ListLimits::default()
+        };
+        let mut lists = vec![vec![ScratchValue::EMPTY]];
+        // The push fits, but the insert is ignored
+        assert_eq!(run(&mut lists, &limits), Ok(None));
+        assert_eq!(lists[0].len(), 2);
+
+        let limits = ListLimits {
+            mode: LimitMode::Error,
+            ..limits
+        };
+        let mut lists = vec![vec![ScratchValue::EMPTY]];
+        assert!(run(&mut lists, &limits)
+            .unwrap_err()
+            .contains("list is full"));
+        assert_eq!(lists[0].len(), 2);
+    }
+
+    #[test]
+    fn total_limit() {
+        let item = item_size(&ScratchValue::Number(1.0));
+        let limits = ListLimits {
+            max_total_size: Some(3 * item),
+            ..ListLimits::default()
+        };
+        let mut lists = vec![vec![], vec![ScratchValue::EMPTY; 2]];
+        assert_eq!(run(&mut lists, &limits), Ok(None));
+        assert_eq!(lists[0].len(), 1);
+
+        // Text takes up room too
+        let mut lists = vec![vec![], vec![ScratchValue::String("a".repeat(2 * item))]];
+        assert_eq!(run(&mut lists, &limits), Ok(None));
+        assert!(lists[0].is_empty());
+
+        let limits = ListLimits {
+            mode: LimitMode::Error,
+            ..limits
+        };
+        let mut lists = vec![vec![], vec![ScratchValue::EMPTY; 3]];
+        assert!(run(&mut lists, &limits)
+            .unwrap_err()
+            .contains("too much memory"));
+        assert!(lists[0].is_empty());
+    }
+
+    #[test]
+    fn shrinking_lists_makes_room() {
+        let item = item_size(&ScratchValue::Number(1.0));
+        let limits = ListLimits {
+            max_total_size: Some(3 * item),
+            mode: LimitMode::Error,
+            ..ListLimits::default()
+        };
+        let replace = [
+            0x000000010000003cu64, // LOAD_CONST_INT 1
+            0x000000070000003cu64, // LOAD_CONST_INT 7
+            0x0000000100000023u64, // LIST_REPLACE 1
+        ];
+        let delete = [
+            0x000000010000003cu64, // LOAD_CONST_INT 1
+            0x0000000100000020u64, // LIST_DEL 1
+        ];
+        for shrink in [&replace[..], &delete[..]] {
+            let bytecode = [shrink, PUSH_AND_INSERT].concat();
+            let mut lists = vec![vec![], vec![ScratchValue::String("a".repeat(2 * item))]];
+            assert_eq!(run_bytecode(&bytecode, &mut lists, &limits), Ok(None));
+            assert_eq!(lists[0].len(), 2);
+        }
+    }
+
+    #[test]
+    fn allocating_is_only_a_hint() {
+        let limits = ListLimits {
+            max_items_per_list: 2,
+            mode: LimitMode::Error,
+            ..ListLimits::default()
+        };
+        let mut lists = vec![vec![ScratchValue::EMPTY]];
+        let bytecode = [
+            0x0000000000000007u64, // ALLOC_LIST 0
+            0x0000006400000001u64, // EXTRA_ARG 100
+        ];
+        // Asking for more room than there is doesn't fail until items are
+        // actually added
+        assert_eq!(run_bytecode(&bytecode, &mut lists, &limits), Ok(None));
+        assert_eq!(lists[0].len(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::motion::{STAGE_HEIGHT, STAGE_WIDTH};
+use crate::scratch_value::{js_string_to_number, ScratchValue};
+use crate::target::Target;
+
+/// The graphic effects, in the order they're stored in `Looks::effects`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GraphicEffect {
+    Color = 0,
+    Fisheye = 1,
+    Whirl = 2,
+    Pixelate = 3,
+    Mosaic = 4,
+    Brightness = 5,
+    Ghost = 6,
+}
+
+impl TryFrom<u32> for GraphicEffect {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Color,
+            1 => Self::Fisheye,
+            2 => Self::Whirl,
+            3 => Self::Pixelate,
+            4 => Self::Mosaic,
+            5 => Self::Brightness,
+            6 => Self::Ghost,
+            _ => return Err("unknown graphic effect"),
+        })
+    }
+}
+
+impl GraphicEffect {
+    pub const COUNT: usize = 7;
+
+    /// Keeps the effect's value in range like scratch-vm's `clampEffect`.
+    fn clamp(self, value: f64) -> f64 {
+        match self {
+            GraphicEffect::Ghost => value.clamp(0.0, 100.0),
+            GraphicEffect::Brightness => value.clamp(-100.0, 100.0),
+            _ => value,
+        }
+    }
+}
+
+/// A costume (or backdrop). Its size limits how big or small the sprite can
+/// be made; a size of zero means it isn't known, and then the size isn't
+/// limited.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Costume {
+    pub name: String,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// What a target looks like, as far as the looks blocks are concerned.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Looks {
+    pub costumes: Vec<Costume>,
+    /// The current costume, zero-based.
+    pub costume: usize,
+    /// In percent of the costume's size.
+    pub size: f64,
+    pub visible: bool,
+    /// Indexed by `GraphicEffect`.
+    pub effects: [f64; GraphicEffect::COUNT],
+    /// Where the target is drawn: higher layers are in front. The stage is
+    /// always layer 0, behind all the sprites.
+    pub layer: u32,
+    /// Set whenever any of the above changes, so the renderer can be sent all
+    /// the changes at once at the end of a tick.
+    #[serde(skip)]
+    pub render_changed: bool,
+}
+
+impl Default for Looks {
+    fn default() -> Self {
+        Self {
+            costumes: Vec::new(),
+            costume: 0,
+            size: 100.0,
+            visible: true,
+            effects: [0.0; GraphicEffect::COUNT],
+            layer: 0,
+            // New targets need drawing
+            render_changed: true,
+        }
+    }
+}
+
+impl Looks {
+    /// Implements `RenderedTarget.setCostume`: the index is rounded and
+    /// wrapped around the costumes.
+    pub fn set_costume(&mut self, index: f64) {
+        if self.costumes.is_empty() {
+            return;
+        }
+        // JS's `Math.round` rounds halves up
+        let index = (index + 0.5).floor();
+        let index = if index.is_finite() { index } else { 0.0 };
+        let count = self.costumes.len() as f64;
+        let costume = (index - (index / count).floor() * count) as usize;
+        if costume != self.costume {
+            self.costume = costume;
+            self.render_changed = true;
+        }
+    }
+
+    /// Implements scratch-vm's `_setCostume` for the "switch costume to" block:
+    /// numbers are one-based indices, strings are names first, then "next
+    /// costume" and "previous costume", then numbers if they look like one.
+    pub fn switch_costume(&mut self, requested: &ScratchValue) {
+        if let ScratchValue::Number(index) = requested {
+            self.set_costume(index - 1.0);
+            return;
+        }
+        let name = requested.as_str();
+        if let Some(index) = self
+            .costumes
+            .iter()
+            .position(|costume| costume.name == name)
+        {
+            self.set_costume(index as f64);
+        } else if name == "next costume" {
+            self.set_costume(self.costume as f64 + 1.0);
+        } else if name == "previous costume" {
+            self.set_costume(self.costume as f64 - 1.0);
+        } else {
+            // Pure whitespace isn't a number here, unlike in JS
+            let index = match requested {
+                ScratchValue::Boolean(value) => *value as u8 as f64,
+                _ if name.trim().is_empty() => return,
+                _ => js_string_to_number(&name),
+            };
+            if !index.is_nan() {
+                self.set_costume(index - 1.0);
+            }
+        }
+    }
+
+    pub fn costume_name(&self) -> &str {
+        self.costumes
+            .get(self.costume)
+            .map_or("", |costume| &costume.name)
+    }
+
+    /// Implements `RenderedTarget.setSize`, which keeps the sprite between 5
+    /// pixels and one and a half times the stage.
+    pub fn set_size(&mut self, size: f64) {
+        let mut scale = size / 100.0;
+        if let Some(costume) = self.costumes.get(self.costume) {
+            let (width, height) = (costume.width, costume.height);
+            if width > 0.0 && height > 0.0 {
+                let min_scale = (5.0 / width).max(5.0 / height).min(1.0);
+                let max_scale = (1.5 * STAGE_WIDTH / width).min(1.5 * STAGE_HEIGHT / height);
+                // Not `clamp`, since a long thin costume can have a minimum
+                // above its maximum, and then the maximum wins like in JS
+                scale = scale.max(min_scale).min(max_scale);
+            }
+        }
+        let size = scale * 100.0;
+        if size != self.size {
+            self.size = size;
+            self.render_changed = true;
+        }
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        if visible != self.visible {
+            self.visible = visible;
+            self.render_changed = true;
+        }
+    }
+
+    pub fn set_effect(&mut self, effect: GraphicEffect, value: f64) {
+        let value = effect.clamp(value);
+        let current = &mut self.effects[effect as usize];
+        if value != *current {
+            *current = value;
+            self.render_changed = true;
+        }
+    }
+
+    pub fn clear_effects(&mut self) {
+        if self.effects.iter().any(|value| *value != 0.0) {
+            self.effects = [0.0; GraphicEffect::COUNT];
+            self.render_changed = true;
+        }
+    }
+}
+
+/// Where to move a sprite to with `restack`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerPosition {
+    Front,
+    Back,
+    /// Right behind the sprite with this ID, like new clones go.
+    Behind(u32),
+}
+
+/// Moves the sprite at `index` in front of or behind the others, and
+/// renumbers all the sprites' layers from 1. The stage stays at the back.
+pub fn restack(targets: &mut [Target], index: usize, position: LayerPosition) {
+    if targets[index].is_stage {
+        return;
+    }
+    let mut order = (0..targets.len())
+        .filter(|other| *other != index && !targets[*other].is_stage)
+        .collect::<Vec<_>>();
+    order.sort_by_key(|other| targets[*other].looks.layer);
+    let at = match position {
+        LayerPosition::Front => order.len(),
+        LayerPosition::Back => 0,
+        LayerPosition::Behind(id) => order
+            .iter()
+            .position(|other| targets[*other].id == id)
+            .unwrap_or(order.len()),
+    };
+    order.insert(at, index);
+    for (layer, target) in order.into_iter().enumerate() {
+        let looks = &mut targets[target].looks;
+        let layer = layer as u32 + 1;
+        if looks.layer != layer {
+            looks.layer = layer;
+            looks.render_changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Runtime;
+
+    use super::*;
+
+    fn looks() -> Looks {
+        Looks {
+            costumes: ["a", "b", "2"]
+                .iter()
+                .map(|name| Costume {
+                    name: name.to_string(),
+                    width: 100.0,
+                    height: 50.0,
+                })
+                .collect(),
+            ..Looks::default()
+        }
+    }
+
+    #[test]
+    fn switch_costume_fallbacks() {
+        let mut looks = looks();
+        for (requested, costume) in [
+            (ScratchValue::String("b".into()), 1),
+            // Names win over numbers
+            (ScratchValue::String("2".into()), 2),
+            (ScratchValue::Number(2.0), 1),
+            (ScratchValue::String("next costume".into()), 2),
+            (ScratchValue::String("next costume".into()), 0),
+            (ScratchValue::String("previous costume".into()), 2),
+            (ScratchValue::String(" 1 ".into()), 0),
+            (ScratchValue::Number(4.5), 1),
+            (ScratchValue::Number(-0.5), 2),
+            (ScratchValue::Boolean(true), 0),
+            (ScratchValue::Number(2.0), 1),
+            (ScratchValue::Number(f64::INFINITY), 0),
+            (ScratchValue::String("missing".into()), 0),
+        ] {
+            looks.switch_costume(&requested);
+            assert_eq!(looks.costume, costume, "{:?}", requested);
+        }
+        // Whitespace isn't costume 0
+        looks.set_costume(1.0);
+        looks.switch_costume(&ScratchValue::String("  ".into()));
+        assert_eq!(looks.costume, 1);
+    }
+
+    #[test]
+    fn size_and_effects_are_clamped() {
+        let mut looks = looks();
+        looks.set_size(1.0);
+        assert_eq!(looks.size, 10.0);
+        looks.set_size(10000.0);
+        assert_eq!(looks.size, 720.0);
+        looks.set_effect(GraphicEffect::Ghost, 150.0);
+        looks.set_effect(GraphicEffect::Brightness, -150.0);
+        looks.set_effect(GraphicEffect::Color, 250.0);
+        assert_eq!(looks.effects, [250.0, 0.0, 0.0, 0.0, 0.0, -100.0, 100.0]);
+    }
+
+    #[test]
+    fn layers() {
+        let mut runtime = Runtime::new(vec![], vec![], vec![], vec![]);
+        let stage = runtime.add_target("Stage".into(), true);
+        let a = runtime.add_target("a".into(), false);
+        let b = runtime.add_target("b".into(), false);
+        let clone = runtime.clone_target(b).unwrap();
+        let layers = |runtime: &Runtime| {
+            let mut ids = runtime
+                .targets
+                .iter()
+                .map(|target| (target.looks.layer, target.id))
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids.into_iter().map(|(_, id)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(layers(&runtime), [stage, a, clone, b]);
+
+        restack(&mut runtime.targets, 1, LayerPosition::Front);
+        assert_eq!(layers(&runtime), [stage, clone, b, a]);
+        restack(&mut runtime.targets, 2, LayerPosition::Back);
+        assert_eq!(layers(&runtime), [stage, b, clone, a]);
+        restack(&mut runtime.targets, 0, LayerPosition::Front);
+        assert_eq!(layers(&runtime), [stage, b, clone, a]);
+    }
+}
@@ -0,0 +1,37 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+/// Which variables and lists are shown on the stage, as set by the
+/// `MONITOR_*` instructions. The host draws the monitors; this only keeps
+/// track of which ones should be visible.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Monitors {
+    /// The indices of the variables being shown.
+    pub variables: BTreeSet<u32>,
+    /// The indices of the lists being shown.
+    pub lists: BTreeSet<u32>,
+    /// Set whenever a monitor is shown or hidden, so the host knows to
+    /// update. The host clears it.
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+impl Monitors {
+    pub fn set_variable_visible(&mut self, index: u32, visible: bool) {
+        self.changed |= Self::set_visible(&mut self.variables, index, visible);
+    }
+
+    pub fn set_list_visible(&mut self, index: u32, visible: bool) {
+        self.changed |= Self::set_visible(&mut self.lists, index, visible);
+    }
+
+    /// Returns whether the visibility changed.
+    fn set_visible(shown: &mut BTreeSet<u32>, index: u32, visible: bool) -> bool {
+        if visible {
+            shown.insert(index)
+        } else {
+            shown.remove(&index)
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::{convert::TryFrom, f64::consts::PI};
+
+use serde::{Deserialize, Serialize};
+
+pub const STAGE_WIDTH: f64 = 480.0;
+pub const STAGE_HEIGHT: f64 = 360.0;
+/// How many pixels of a sprite (at most) are kept on the stage when it's
+/// moved off the edge.
+const FENCE_WIDTH: f64 = 15.0;
+
+/// How the renderer should turn the sprite to face its direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RotationStyle {
+    AllAround = 0,
+    LeftRight = 1,
+    DontRotate = 2,
+}
+
+impl TryFrom<u32> for RotationStyle {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::AllAround,
+            1 => Self::LeftRight,
+            2 => Self::DontRotate,
+            _ => return Err("unknown rotation style"),
+        })
+    }
+}
+
+/// A box around a sprite, relative to its position, in stage units (y up).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    pub left: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub top: f64,
+}
+
+/// Where a sprite is and which way it's facing. The maths is the same as
+/// scratch-vm's `RenderedTarget` and motion blocks, so positions match to the
+/// last bit.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Motion {
+    pub x: f64,
+    pub y: f64,
+    /// In degrees clockwise from up, wrapped to -179..180 (strictly, up to
+    /// but not including 181).
+    pub direction: f64,
+    pub rotation_style: RotationStyle,
+    /// The sprite's bounding box as drawn, which keeps it on the stage and
+    /// decides when it bounces off the edge. The renderer should keep this up
+    /// to date; until it does, the sprite is treated as a point.
+    pub bounds: Bounds,
+    /// Set whenever the position, direction or rotation style changes, so the
+    /// renderer can be told about all the changes at once at the end of a
+    /// tick.
+    #[serde(skip)]
+    pub transform_changed: bool,
+}
+
+impl Default for Motion {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            direction: 90.0,
+            rotation_style: RotationStyle::AllAround,
+            bounds: Bounds::default(),
+            // New sprites need drawing
+            transform_changed: true,
+        }
+    }
+}
+
+/// Scratch's `MathUtil.wrapClamp`.
+pub fn wrap_clamp(value: f64, min: f64, max: f64) -> f64 {
+    let range = max - min + 1.0;
+    value - ((value - min) / range).floor() * range
+}
+
+fn degrees_to_radians(degrees: f64) -> f64 {
+    degrees * PI / 180.0
+}
+
+/// Rounds coordinates that are within a billionth of a whole number, like the
+/// x and y position reporters do, so that e.g. moving 10 steps at 30 degrees
+/// and back reports exactly 0.
+pub fn limit_precision(coordinate: f64) -> f64 {
+    let rounded = coordinate.round();
+    if (coordinate - rounded).abs() < 1e-9 {
+        rounded
+    } else {
+        coordinate
+    }
+}
+
+impl Motion {
+    /// Moves the sprite, keeping at least a little of it on the stage.
+    pub fn set_xy(&mut self, x: f64, y: f64) {
+        let (x, y) = self.fenced_position(x, y);
+        if x != self.x || y != self.y {
+            self.x = x;
+            self.y = y;
+            self.transform_changed = true;
+        }
+    }
+
+    /// Implements the renderer's `getFencedPositionOfDrawable`.
+    fn fenced_position(&self, mut x: f64, mut y: f64) -> (f64, f64) {
+        let bounds = self.bounds;
+        let dx = x - self.x;
+        let dy = y - self.y;
+        let inset = ((bounds.right - bounds.left).min(bounds.top - bounds.bottom) / 2.0).floor();
+        let (left, right) = (self.x + bounds.left, self.x + bounds.right);
+        let (bottom, top) = (self.y + bounds.bottom, self.y + bounds.top);
+        let sx = STAGE_WIDTH / 2.0 - FENCE_WIDTH.min(inset);
+        if right + dx < -sx {
+            x = (self.x - (sx + right)).ceil();
+        } else if left + dx > sx {
+            x = (self.x + (sx - left)).floor();
+        }
+        let sy = STAGE_HEIGHT / 2.0 - FENCE_WIDTH.min(inset);
+        if top + dy < -sy {
+            y = (self.y - (sy + top)).ceil();
+        } else if bottom + dy > sy {
+            y = (self.y + (sy - bottom)).floor();
+        }
+        (x, y)
+    }
+
+    /// Whether the point (`x`, `y`) is inside the sprite's bounds.
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let bounds = self.bounds;
+        x >= self.x + bounds.left
+            && x <= self.x + bounds.right
+            && y >= self.y + bounds.bottom
+            && y <= self.y + bounds.top
+    }
+
+    /// The "touching edge?" block: whether the sprite's bounds reach past
+    /// the edge of the stage, like `RenderedTarget.isTouchingEdge`.
+    pub fn is_touching_edge(&self) -> bool {
+        let bounds = self.bounds;
+        let (right, top) = (STAGE_WIDTH / 2.0, STAGE_HEIGHT / 2.0);
+        self.x + bounds.left < -right
+            || self.x + bounds.right > right
+            || self.y + bounds.top > top
+            || self.y + bounds.bottom < -top
+    }
+
+    /// Points the sprite in `direction`, wrapped like Scratch's `wrapClamp`.
+    /// Infinities and NaN are ignored.
+    pub fn set_direction(&mut self, direction: f64) {
+        if !direction.is_finite() {
+            return;
+        }
+        let direction = wrap_clamp(direction, -179.0, 180.0);
+        if direction != self.direction {
+            self.direction = direction;
+            self.transform_changed = true;
+        }
+    }
+
+    pub fn set_rotation_style(&mut self, rotation_style: RotationStyle) {
+        if rotation_style != self.rotation_style {
+            self.rotation_style = rotation_style;
+            self.transform_changed = true;
+        }
+    }
+
+    /// The "move (steps)" block.
+    pub fn move_steps(&mut self, steps: f64) {
+        let radians = degrees_to_radians(90.0 - self.direction);
+        let dx = steps * radians.cos();
+        let dy = steps * radians.sin();
+        self.set_xy(self.x + dx, self.y + dy);
+    }
+
+    /// Implements `RenderedTarget.keepInFence` against the stage: the
+    /// position `(x, y)` nudged so the sprite's bounds are inside it.
+    fn keep_in_fence(&self, x: f64, y: f64) -> (f64, f64) {
+        let bounds = self.bounds;
+        let (left, right) = (x + bounds.left, x + bounds.right);
+        let (bottom, top) = (y + bounds.bottom, y + bounds.top);
+        let (stage_right, stage_top) = (STAGE_WIDTH / 2.0, STAGE_HEIGHT / 2.0);
+        let mut dx = 0.0;
+        let mut dy = 0.0;
+        if left < -stage_right {
+            dx += -stage_right - left;
+        }
+        if right > stage_right {
+            dx += stage_right - right;
+        }
+        if top > stage_top {
+            dy += stage_top - top;
+        }
+        if bottom < -stage_top {
+            dy += -stage_top - bottom;
+        }
+        (x + dx, y + dy)
+    }
+
+    /// The "if on edge, bounce" block: if the sprite touches an edge, it
+    /// turns away from the nearest one and is moved back onto the stage.
+    pub fn if_on_edge_bounce(&mut self) {
+        let bounds = self.bounds;
+        let distances = [
+            (STAGE_WIDTH / 2.0 + self.x + bounds.left).max(0.0),
+            (STAGE_HEIGHT / 2.0 - (self.y + bounds.top)).max(0.0),
+            (STAGE_WIDTH / 2.0 - (self.x + bounds.right)).max(0.0),
+            (STAGE_HEIGHT / 2.0 + self.y + bounds.bottom).max(0.0),
+        ];
+        // The first edge wins ties, in the order left, top, right, bottom
+        let (nearest_edge, min_distance) = distances.iter().enumerate().fold(
+            (0, f64::INFINITY),
+            |(nearest_edge, min_distance), (edge, distance)| {
+                if *distance < min_distance {
+                    (edge, *distance)
+                } else {
+                    (nearest_edge, min_distance)
+                }
+            },
+        );
+        if min_distance > 0.0 {
+            return;
+        }
+        let radians = degrees_to_radians(90.0 - self.direction);
+        let mut dx = radians.cos();
+        let mut dy = -radians.sin();
+        match nearest_edge {
+            0 => dx = dx.abs().max(0.2),
+            1 => dy = dy.abs().max(0.2),
+            2 => dx = -dx.abs().max(0.2),
+            _ => dy = -dy.abs().max(0.2),
+        }
+        // Not `to_degrees`, which multiplies by `180 / PI` up front
+        self.set_direction(dy.atan2(dx) * 180.0 / PI + 90.0);
+        let (x, y) = self.keep_in_fence(self.x, self.y);
+        self.set_xy(x, y);
+    }
+}
+
+/// A "glide (secs) to x: () y: ()" in progress. The scheduler moves the
+/// sprite along every tick until it gets there.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Glide {
+    /// When the glide started, by the clock (in milliseconds).
+    pub start: f64,
+    /// How long the glide takes, in milliseconds.
+    pub duration: f64,
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+}
+
+impl Glide {
+    /// Moves the sprite to where it should be at `now`, returning whether the
+    /// glide is over.
+    pub fn update(&self, motion: &mut Motion, now: f64) -> bool {
+        let elapsed = now - self.start;
+        if elapsed < self.duration {
+            let fraction = elapsed / self.duration;
+            let dx = fraction * (self.to.0 - self.from.0);
+            let dy = fraction * (self.to.1 - self.from.1);
+            motion.set_xy(self.from.0 + dx, self.from.1 + dy);
+            false
+        } else {
+            motion.set_xy(self.to.0, self.to.1);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direction_wraps() {
+        let mut motion = Motion::default();
+        for (direction, wrapped) in [
+            (180.0, 180.0),
+            (181.0, -179.0),
+            (-180.0, 180.0),
+            (540.0, 180.0),
+            (-90.5, -90.5),
+            (179.5, 179.5),
+            // Scratch wraps whole numbers, so fractions above 180 are fine
+            (-179.5, 180.5),
+        ] {
+            motion.set_direction(direction);
+            assert_eq!(motion.direction, wrapped, "{}", direction);
+        }
+        motion.set_direction(f64::INFINITY);
+        assert_eq!(motion.direction, 180.5);
+    }
+
+    #[test]
+    fn fencing_keeps_part_of_the_sprite_on_stage() {
+        let mut motion = Motion {
+            bounds: Bounds {
+                left: -48.0,
+                right: 48.0,
+                bottom: -50.0,
+                top: 50.0,
+            },
+            ..Motion::default()
+        };
+        motion.set_xy(1000.0, -1000.0);
+        // 15 pixels of the sprite are left showing
+        assert_eq!((motion.x, motion.y), (273.0, -215.0));
+        motion.set_xy(-1000.0, 1000.0);
+        assert_eq!((motion.x, motion.y), (-273.0, 215.0));
+
+        // Points are kept on the stage
+        let mut motion = Motion::default();
+        motion.set_xy(-300.0, 200.0);
+        assert_eq!((motion.x, motion.y), (-240.0, 180.0));
+    }
+
+    #[test]
+    fn bounce_off_the_nearest_edge() {
+        let mut motion = Motion {
+            bounds: Bounds {
+                left: -10.0,
+                right: 10.0,
+                bottom: -10.0,
+                top: 10.0,
+            },
+            ..Motion::default()
+        };
+        motion.set_xy(235.0, 0.0);
+        motion.set_direction(45.0);
+        motion.if_on_edge_bounce();
+        assert_eq!(motion.direction, -45.0);
+        assert_eq!((motion.x, motion.y), (230.0, 0.0));
+
+        // Not touching anything
+        motion.set_xy(0.0, 0.0);
+        motion.if_on_edge_bounce();
+        assert_eq!(motion.direction, -45.0);
+    }
+}
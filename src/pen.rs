@@ -0,0 +1,366 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::collision::CollisionProvider;
+use crate::motion::{wrap_clamp, STAGE_HEIGHT, STAGE_WIDTH};
+use crate::scratch_value::ScratchValue;
+use crate::target::Target;
+
+/// The pen layer's size in pixels, which is the stage's.
+pub const PEN_LAYER_WIDTH: usize = STAGE_WIDTH as usize;
+pub const PEN_LAYER_HEIGHT: usize = STAGE_HEIGHT as usize;
+
+/// The color parameters of the pen blocks, in the order the instructions
+/// number them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorParam {
+    Color = 0,
+    Saturation = 1,
+    Brightness = 2,
+    Transparency = 3,
+}
+
+impl TryFrom<u32> for ColorParam {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Color,
+            1 => Self::Saturation,
+            2 => Self::Brightness,
+            3 => Self::Transparency,
+            _ => return Err("unknown pen color parameter"),
+        })
+    }
+}
+
+/// Scratch's `Color.hsvToRgb`, with the hue in degrees and the saturation
+/// and value from 0 to 1.
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> [u8; 3] {
+    let hue = hue.rem_euclid(360.0);
+    let saturation = saturation.clamp(0.0, 1.0);
+    let value = value.clamp(0.0, 1.0);
+    let sector = (hue / 60.0).floor();
+    let fraction = hue / 60.0 - sector;
+    let p = value * (1.0 - saturation);
+    let q = value * (1.0 - saturation * fraction);
+    let t = value * (1.0 - saturation * (1.0 - fraction));
+    let (red, green, blue) = match sector as u8 {
+        1 => (q, value, p),
+        2 => (p, value, t),
+        3 => (p, q, value),
+        4 => (t, p, value),
+        5 => (value, p, q),
+        _ => (value, t, p),
+    };
+    [red, green, blue].map(|channel| (channel * 255.0).floor() as u8)
+}
+
+/// Scratch's `Color.rgbToHsv`, giving the hue in degrees and the saturation
+/// and value from 0 to 1. Grays have a hue of 0.
+fn rgb_to_hsv([red, green, blue]: [u8; 3]) -> (f64, f64, f64) {
+    let (red, green, blue) = (
+        red as f64 / 255.0,
+        green as f64 / 255.0,
+        blue as f64 / 255.0,
+    );
+    let min = red.min(green).min(blue);
+    let value = red.max(green).max(blue);
+    if min == value {
+        return (0.0, 0.0, value);
+    }
+    let (difference, sector) = if red == min {
+        (green - blue, 3.0)
+    } else if green == min {
+        (blue - red, 5.0)
+    } else {
+        (red - green, 1.0)
+    };
+    let hue = ((sector - difference / (value - min)) * 60.0) % 360.0;
+    (hue, (value - min) / value, value)
+}
+
+/// A target's pen, modelled like the pen extension does: the color is a hue,
+/// saturation, brightness and transparency, each from 0 to 100.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PenState {
+    pub down: bool,
+    /// The hue (Scratch calls it the color).
+    pub color: f64,
+    pub saturation: f64,
+    pub brightness: f64,
+    pub transparency: f64,
+    /// The diameter of the lines, in stage pixels.
+    pub size: f64,
+}
+
+impl Default for PenState {
+    fn default() -> Self {
+        // Blue, like scratch-vm's `DEFAULT_PEN_STATE`
+        Self {
+            down: false,
+            color: 66.66,
+            saturation: 100.0,
+            brightness: 100.0,
+            transparency: 0.0,
+            size: 1.0,
+        }
+    }
+}
+
+impl PenState {
+    /// The color lines are drawn in, as `[r, g, b, a]`.
+    pub fn rgba(&self) -> [u8; 4] {
+        let [red, green, blue] = hsv_to_rgb(
+            self.color * 360.0 / 100.0,
+            self.saturation / 100.0,
+            self.brightness / 100.0,
+        );
+        let alpha = (1.0 - self.transparency / 100.0) * 255.0;
+        [red, green, blue, alpha.round() as u8]
+    }
+
+    /// The "set pen color to (color)" block.
+    pub fn set_color(&mut self, color: &ScratchValue) {
+        let [red, green, blue, alpha] = color.to_rgba_color();
+        let (hue, saturation, value) = rgb_to_hsv([red, green, blue]);
+        self.color = hue / 360.0 * 100.0;
+        self.saturation = saturation * 100.0;
+        self.brightness = value * 100.0;
+        self.transparency = 100.0 * (1.0 - alpha as f64 / 255.0);
+    }
+
+    /// Sets one of the color parameters, like scratch-vm's
+    /// `_setOrChangeColorParam`: the hue wraps around and the others are kept
+    /// from 0 to 100.
+    pub fn set_color_param(&mut self, param: ColorParam, value: f64) {
+        match param {
+            ColorParam::Color => self.color = wrap_clamp(value, 0.0, 100.0),
+            ColorParam::Saturation => self.saturation = value.clamp(0.0, 100.0),
+            ColorParam::Brightness => self.brightness = value.clamp(0.0, 100.0),
+            ColorParam::Transparency => self.transparency = value.clamp(0.0, 100.0),
+        }
+    }
+
+    pub fn color_param(&self, param: ColorParam) -> f64 {
+        match param {
+            ColorParam::Color => self.color,
+            ColorParam::Saturation => self.saturation,
+            ColorParam::Brightness => self.brightness,
+            ColorParam::Transparency => self.transparency,
+        }
+    }
+
+    /// Sets the pen's size, which is kept from 1 to 1200 like Scratch.
+    pub fn set_size(&mut self, size: f64) {
+        self.size = size.clamp(1.0, 1200.0);
+    }
+}
+
+/// Blends `color` over `pixel` with `coverage` (from 0 to 1) of it showing.
+/// Neither is premultiplied.
+fn blend(pixel: &mut [u8], color: [u8; 4], coverage: f64) {
+    let alpha = color[3] as f64 / 255.0 * coverage;
+    if alpha <= 0.0 {
+        return;
+    }
+    let below = pixel[3] as f64 / 255.0 * (1.0 - alpha);
+    let out = alpha + below;
+    for channel in 0..3 {
+        let value = (color[channel] as f64 * alpha + pixel[channel] as f64 * below) / out;
+        pixel[channel] = value.round() as u8;
+    }
+    pixel[3] = (out * 255.0).round() as u8;
+}
+
+/// The distance from (`x`, `y`) to the line segment between `from` and `to`.
+fn distance_to_segment(x: f64, y: f64, from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length_squared = dx * dx + dy * dy;
+    let along = if length_squared > 0.0 {
+        (((x - from.0) * dx + (y - from.1) * dy) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (nearest_x, nearest_y) = (from.0 + along * dx, from.1 + along * dy);
+    ((x - nearest_x).powi(2) + (y - nearest_y).powi(2)).sqrt()
+}
+
+/// What the pen has drawn: a stage-sized RGBA image (not premultiplied),
+/// drawn on the CPU so that it looks the same headless as in the browser,
+/// which can upload it as a texture.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PenLayer {
+    /// Row by row from the top left, or empty if nothing has been drawn
+    /// since the layer was last cleared.
+    pixels: Vec<u8>,
+    /// Set whenever the layer changes, so the host knows to upload it again.
+    /// The host clears it.
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+impl PenLayer {
+    /// The "erase all" block.
+    pub fn clear(&mut self) {
+        self.pixels = Vec::new();
+        self.changed = true;
+    }
+
+    /// The whole layer, row by row from the top left.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        if self.pixels.is_empty() {
+            vec![0; PEN_LAYER_WIDTH * PEN_LAYER_HEIGHT * 4]
+        } else {
+            self.pixels.clone()
+        }
+    }
+
+    /// The pixel under the point (`x`, `y`) on the stage, if it's on the
+    /// stage.
+    pub fn pixel_at(&self, x: f64, y: f64) -> Option<[u8; 4]> {
+        let column = (x + STAGE_WIDTH / 2.0).floor();
+        let row = (STAGE_HEIGHT / 2.0 - y).floor();
+        if !(0.0..PEN_LAYER_WIDTH as f64).contains(&column)
+            || !(0.0..PEN_LAYER_HEIGHT as f64).contains(&row)
+        {
+            return None;
+        }
+        if self.pixels.is_empty() {
+            return Some([0; 4]);
+        }
+        let index = (row as usize * PEN_LAYER_WIDTH + column as usize) * 4;
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(&self.pixels[index..index + 4]);
+        Some(pixel)
+    }
+
+    fn pixels_mut(&mut self) -> &mut [u8] {
+        if self.pixels.is_empty() {
+            self.pixels = vec![0; PEN_LAYER_WIDTH * PEN_LAYER_HEIGHT * 4];
+        }
+        self.changed = true;
+        &mut self.pixels
+    }
+
+    /// Draws an anti-aliased line with round ends from `from` to `to` (in
+    /// stage coordinates) with `pen`, like scratch-render's `PenSkin`. A line
+    /// from a point to itself is a dot.
+    pub fn draw_line(&mut self, pen: &PenState, from: (f64, f64), to: (f64, f64)) {
+        if ![from.0, from.1, to.0, to.1]
+            .iter()
+            .all(|value| value.is_finite())
+        {
+            return;
+        }
+        // Lines 1 and 3 pixels wide are nudged onto the pixel grid, like
+        // Scratch 2 did
+        let offset = if pen.size == 1.0 || pen.size == 3.0 {
+            0.5
+        } else {
+            0.0
+        };
+        let to_layer = |(x, y): (f64, f64)| {
+            (
+                x + offset + STAGE_WIDTH / 2.0,
+                STAGE_HEIGHT / 2.0 - (y + offset),
+            )
+        };
+        let (from, to) = (to_layer(from), to_layer(to));
+        let radius = pen.size / 2.0;
+        let clamp_column = |value: f64| value.clamp(0.0, PEN_LAYER_WIDTH as f64 - 1.0) as usize;
+        let clamp_row = |value: f64| value.clamp(0.0, PEN_LAYER_HEIGHT as f64 - 1.0) as usize;
+        let left = clamp_column((from.0.min(to.0) - radius - 1.0).floor());
+        let right = clamp_column((from.0.max(to.0) + radius + 1.0).ceil());
+        let top = clamp_row((from.1.min(to.1) - radius - 1.0).floor());
+        let bottom = clamp_row((from.1.max(to.1) + radius + 1.0).ceil());
+        let color = pen.rgba();
+        let pixels = self.pixels_mut();
+        for row in top..=bottom {
+            for column in left..=right {
+                let distance = distance_to_segment(column as f64 + 0.5, row as f64 + 0.5, from, to);
+                let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    let index = (row * PEN_LAYER_WIDTH + column) * 4;
+                    blend(&mut pixels[index..index + 4], color, coverage);
+                }
+            }
+        }
+    }
+
+    /// The "stamp" block: draws the target at `index` onto the layer, using
+    /// the pixels `collision` knows about. Hidden sprites can stamp too.
+    pub fn stamp(&mut self, targets: &[Target], index: usize, collision: &dyn CollisionProvider) {
+        for row in 0..PEN_LAYER_HEIGHT {
+            for column in 0..PEN_LAYER_WIDTH {
+                let x = column as f64 + 0.5 - STAGE_WIDTH / 2.0;
+                let y = STAGE_HEIGHT / 2.0 - row as f64 - 0.5;
+                if let Some(color) = collision.pixel(targets, index, x, y) {
+                    let index = (row * PEN_LAYER_WIDTH + column) * 4;
+                    blend(&mut self.pixels_mut()[index..index + 4], color, 1.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_convert_like_scratch() {
+        let mut pen = PenState::default();
+        assert_eq!(pen.rgba(), [0, 0, 255, 255]);
+        pen.set_color(&ScratchValue::String("#ff8000".into()));
+        // Going through HSV loses a little, like it does in Scratch
+        assert_eq!(pen.rgba(), [255, 127, 0, 255]);
+        assert_eq!(pen.brightness, 100.0);
+        pen.set_color_param(ColorParam::Color, 150.0);
+        assert_eq!(pen.color, 49.0);
+        pen.set_color_param(ColorParam::Transparency, 150.0);
+        assert_eq!(pen.rgba()[3], 0);
+        // Numbers can have an alpha
+        pen.set_color(&ScratchValue::Number(0x80ff0000u32 as f64));
+        assert_eq!(pen.rgba(), [255, 0, 0, 128]);
+        pen.set_size(0.0);
+        assert_eq!(pen.size, 1.0);
+    }
+
+    #[test]
+    fn lines_are_anti_aliased() {
+        let mut layer = PenLayer::default();
+        assert_eq!(layer.pixel_at(0.0, 0.0), Some([0; 4]));
+        let mut pen = PenState::default();
+        // One pixel wide lines cover whole pixels, just above and to the
+        // right of the points they're drawn between
+        layer.draw_line(&pen, (-10.0, 0.0), (10.0, 0.0));
+        assert_eq!(layer.pixel_at(0.5, 0.5), Some([0, 0, 255, 255]));
+        assert_eq!(layer.pixel_at(0.5, 1.5), Some([0; 4]));
+        assert_eq!(layer.pixel_at(0.5, -0.5), Some([0; 4]));
+        assert!(layer.changed);
+
+        // Thicker lines fade out at the edges
+        pen.set_size(5.0);
+        pen.set_color(&ScratchValue::String("#ff0000".into()));
+        layer.draw_line(&pen, (0.0, 50.0), (0.0, 100.0));
+        assert_eq!(layer.pixel_at(0.5, 75.0), Some([255, 0, 0, 255]));
+        assert_eq!(layer.pixel_at(-2.5, 75.0), Some([255, 0, 0, 128]));
+        assert_eq!(layer.pixel_at(2.5, 75.0), Some([255, 0, 0, 128]));
+        assert_eq!(layer.pixel_at(3.5, 75.0), Some([0; 4]));
+        // With round ends
+        assert_eq!(layer.pixel_at(0.5, 101.5), Some([255, 0, 0, 255]));
+
+        // Transparent lines blend with what's under them
+        pen.transparency = 50.0;
+        pen.set_color_param(ColorParam::Color, 66.66);
+        layer.draw_line(&pen, (0.0, 60.0), (0.0, 60.0));
+        assert_eq!(layer.pixel_at(0.5, 60.5), Some([127, 0, 128, 255]));
+
+        layer.changed = false;
+        layer.clear();
+        assert!(layer.changed);
+        assert_eq!(layer.pixel_at(0.5, 60.5), Some([0; 4]));
+        assert_eq!(layer.pixel_at(240.0, 0.0), None);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::instruction::InstructionType;
+
+/// How many times something ran and for how long in total.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ProfileStats {
+    pub count: u64,
+    /// In milliseconds.
+    pub time: f64,
+}
+
+impl ProfileStats {
+    fn add(&mut self, time: f64) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+/// A named range of program counters, like a script or a procedure.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProfileRange {
+    pub name: String,
+    pub start: usize,
+    /// Exclusive.
+    pub end: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpcodeProfile {
+    pub opcode: String,
+    #[serde(flatten)]
+    pub stats: ProfileStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeProfile {
+    #[serde(flatten)]
+    pub range: ProfileRange,
+    #[serde(flatten)]
+    pub stats: ProfileStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StackDepthProfile {
+    pub depth: usize,
+    #[serde(flatten)]
+    pub stats: ProfileStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProfileReport {
+    pub opcodes: Vec<OpcodeProfile>,
+    pub ranges: Vec<RangeProfile>,
+    pub stack_depths: Vec<StackDepthProfile>,
+}
+
+/// The name used in reports for instructions outside every range.
+const UNKNOWN_RANGE: &str = "(unknown)";
+
+/// Counts and times every instruction the runner runs, by opcode, by range of
+/// program counters and by the depth of the stack before the instruction.
+/// Attach it to a `Debugger` to use it.
+pub struct Profiler {
+    ranges: Vec<ProfileRange>,
+    by_opcode: HashMap<InstructionType, ProfileStats>,
+    /// Indexed like `ranges`.
+    by_range: Vec<ProfileStats>,
+    outside_ranges: ProfileStats,
+    by_stack_depth: Vec<ProfileStats>,
+    /// By range (`None` outside every range) and opcode, for the folded
+    /// stacks.
+    by_range_and_opcode: HashMap<(Option<usize>, InstructionType), ProfileStats>,
+    #[cfg(not(target_arch = "wasm32"))]
+    epoch: std::time::Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            ranges: Vec::new(),
+            by_opcode: HashMap::new(),
+            by_range: Vec::new(),
+            outside_ranges: ProfileStats::default(),
+            by_stack_depth: Vec::new(),
+            by_range_and_opcode: HashMap::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            epoch: std::time::Instant::now(),
+        }
+    }
+
+    /// Names the instructions from `start` up to (but not including) `end`,
+    /// so time spent in them is reported together. If ranges overlap, the one
+    /// added first wins.
+    pub fn add_range(&mut self, name: String, start: usize, end: usize) {
+        self.ranges.push(ProfileRange { name, start, end });
+        self.by_range.push(ProfileStats::default());
+    }
+
+    /// A high-resolution timestamp in milliseconds, only meaningful relative
+    /// to other calls.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[inline]
+    pub(crate) fn now(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64() * 1000.0
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub(crate) fn now(&self) -> f64 {
+        web_sys::window()
+            .and_then(|window| window.performance())
+            .map_or(0.0, |performance| performance.now())
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        program_counter: usize,
+        instruction: InstructionType,
+        stack_depth: usize,
+        time: f64,
+    ) {
+        let range = self
+            .ranges
+            .iter()
+            .position(|range| range.start <= program_counter && program_counter < range.end);
+        self.by_opcode.entry(instruction).or_default().add(time);
+        match range {
+            Some(range) => self.by_range[range].add(time),
+            None => self.outside_ranges.add(time),
+        }
+        if self.by_stack_depth.len() <= stack_depth {
+            self.by_stack_depth
+                .resize(stack_depth + 1, ProfileStats::default());
+        }
+        self.by_stack_depth[stack_depth].add(time);
+        self.by_range_and_opcode
+            .entry((range, instruction))
+            .or_default()
+            .add(time);
+    }
+
+    fn range_name(&self, range: Option<usize>) -> &str {
+        range.map_or(UNKNOWN_RANGE, |range| &self.ranges[range].name)
+    }
+
+    /// Collects everything recorded so far, slowest first.
+    pub fn report(&self) -> ProfileReport {
+        let mut opcodes = self
+            .by_opcode
+            .iter()
+            .map(|(opcode, stats)| OpcodeProfile {
+                opcode: opcode.mnemonic().to_string(),
+                stats: *stats,
+            })
+            .collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.stats.time.total_cmp(&a.stats.time));
+        let mut ranges = self
+            .ranges
+            .iter()
+            .cloned()
+            .zip(self.by_range.iter().copied())
+            .chain(std::iter::once((
+                ProfileRange {
+                    name: UNKNOWN_RANGE.into(),
+                    start: 0,
+                    end: 0,
+                },
+                self.outside_ranges,
+            )))
+            .filter(|(_, stats)| stats.count > 0)
+            .map(|(range, stats)| RangeProfile { range, stats })
+            .collect::<Vec<_>>();
+        ranges.sort_by(|a, b| b.stats.time.total_cmp(&a.stats.time));
+        let stack_depths = self
+            .by_stack_depth
+            .iter()
+            .enumerate()
+            .filter(|(_, stats)| stats.count > 0)
+            .map(|(depth, stats)| StackDepthProfile {
+                depth,
+                stats: *stats,
+            })
+            .collect();
+        ProfileReport {
+            opcodes,
+            ranges,
+            stack_depths,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        // Nothing in the report can fail to serialize
+        serde_json::to_string(&self.report()).unwrap_or_default()
+    }
+
+    /// Folded stacks (`range;opcode microseconds` per line), which
+    /// `flamegraph.pl` and inferno can turn into a flame graph.
+    pub fn to_folded(&self) -> String {
+        let mut lines = self
+            .by_range_and_opcode
+            .iter()
+            .map(|((range, opcode), stats)| {
+                format!(
+                    "{};{} {}",
+                    self.range_name(*range).replace(';', ":"),
+                    opcode.mnemonic(),
+                    (stats.time * 1000.0).round() as u64
+                )
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler_report() {
+        let mut profiler = Profiler::new();
+        profiler.add_range("when flag clicked".into(), 0, 2);
+        profiler.record(0, InstructionType::LoadConstInt, 0, 1.0);
+        profiler.record(1, InstructionType::LoadConstInt, 1, 2.0);
+        profiler.record(2, InstructionType::OpAdd, 2, 0.5);
+        let report = profiler.report();
+        assert_eq!(report.opcodes[0].opcode, "LOAD_CONST_INT");
+        assert_eq!(
+            report.opcodes[0].stats,
+            ProfileStats {
+                count: 2,
+                time: 3.0
+            }
+        );
+        assert_eq!(report.ranges[0].range.name, "when flag clicked");
+        assert_eq!(report.ranges[1].range.name, UNKNOWN_RANGE);
+        assert_eq!(report.stack_depths.len(), 3);
+        assert_eq!(
+            profiler.to_folded(),
+            "(unknown);OP_ADD 500\nwhen flag clicked;LOAD_CONST_INT 3000"
+        );
+        assert!(profiler
+            .to_json()
+            .starts_with("{\"opcodes\":[{\"opcode\":\"LOAD_CONST_INT\""));
+    }
+}
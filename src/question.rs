@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+/// A question from "ask (question) and wait", waiting to be answered.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Question {
+    /// The thread waiting for the answer.
+    pub thread: u32,
+    /// The target that asked.
+    pub target: u32,
+    pub text: String,
+    /// Whether the question goes in the target's speech bubble rather than
+    /// the prompt, which is the case for sprites that were showing when they
+    /// asked.
+    pub in_bubble: bool,
+}
+
+/// The questions waiting to be answered, in the order they were asked. Only
+/// the first one is shown, like in scratch-vm.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Questions {
+    pub queue: VecDeque<Question>,
+    /// Set whenever the question being shown changes, so the host knows to
+    /// update its prompt. The host clears it.
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+impl Questions {
+    /// What the host's prompt should say, or `None` if there shouldn't be
+    /// one. It's empty when the question is in the asking sprite's bubble.
+    pub fn prompt(&self) -> Option<&str> {
+        self.queue.front().map(|question| {
+            if question.in_bubble {
+                ""
+            } else {
+                question.text.as_str()
+            }
+        })
+    }
+}
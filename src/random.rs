@@ -0,0 +1,82 @@
+use chrono::Utc;
+
+/// A source of random numbers in `[0, 1)`, like JS's `Math.random`. Injected
+/// so that runs can be made (and replayed) deterministically.
+pub trait Random {
+    fn random(&mut self) -> f64;
+
+    /// The generator's whole state, if it can be saved in a snapshot.
+    fn state(&self) -> Option<u64> {
+        None
+    }
+
+    /// Puts back a state returned by `state`.
+    fn set_state(&mut self, _state: u64) {}
+}
+
+/// A small seedable generator (SplitMix64). Its whole state is the `u64` it
+/// was seeded with and advanced from, so it can be saved and restored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeededRandom {
+    pub state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeds from the current time, for when nothing needs to be repeatable.
+    pub fn from_time() -> Self {
+        Self::new(Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Random for SeededRandom {
+    fn random(&mut self) -> f64 {
+        // The top 53 bits fill the mantissa exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn state(&self) -> Option<u64> {
+        Some(self.state)
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+/// JS's `Math.random`. Only works on WASM.
+pub struct MathRandom;
+
+impl Random for MathRandom {
+    fn random(&mut self) -> f64 {
+        js_sys::Math::random()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_random_is_repeatable_and_in_range() {
+        let mut first = SeededRandom::new(42);
+        let mut second = SeededRandom::new(42);
+        for _ in 0..1000 {
+            let value = first.random();
+            assert!((0.0..1.0).contains(&value));
+            assert_eq!(value, second.random());
+        }
+        assert_ne!(SeededRandom::new(1).random(), SeededRandom::new(2).random());
+    }
+}
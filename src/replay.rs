@@ -0,0 +1,609 @@
+//! Recording everything nondeterministic a `Runtime` takes in, so that a
+//! session can be replayed exactly, e.g. to reproduce a bug report.
+//!
+//! While recording, every clock reading, random number, host call result,
+//! host promise resolution, key and mouse event and answer is logged in the
+//! order the runtime takes them in, along with a hash of the runtime's state
+//! whenever a thread yields and where the time budget ran out. When replaying,
+//! the runtime starts from the snapshot taken when the recording started and
+//! takes its inputs from the log instead, without calling the host. If it asks
+//! for a different kind of input than was logged or its state hash differs at
+//! a yield, the replay has diverged, and `step` fails saying where.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::extension::BlockSignature;
+use crate::host::{HostCallHandler, HostCallResult};
+use crate::random::Random;
+use crate::scratch_value::ScratchValue;
+use crate::snapshot::{decode, encode, Snapshot};
+
+const MAGIC: &[u8; 4] = b"SVMR";
+
+/// Something nondeterministic the runtime took in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Input {
+    Clock(f64),
+    Random(f64),
+    /// What a `CALL_HOST` or extension block returned, or `None` if it
+    /// failed.
+    HostCall(Option<HostCallResult>),
+    /// A call to `Runtime::resolve_host_promise` between ticks.
+    HostPromise {
+        thread: u32,
+        value: Option<ScratchValue>,
+    },
+    /// A call to `Runtime::key_down` (or `key_up`) between ticks.
+    Key {
+        key: String,
+        down: bool,
+    },
+    /// A call to `Runtime::mouse_move` between ticks.
+    MouseMove {
+        x: f64,
+        y: f64,
+    },
+    /// A call to `Runtime::mouse_down` (or `mouse_up`) between ticks.
+    MouseButton {
+        down: bool,
+    },
+    /// A call to `Runtime::answer` between ticks.
+    Answer(String),
+    /// The hash of the runtime's state when a thread yielded. Not really an
+    /// input, but checked when replaying.
+    Checkpoint(u64),
+    /// The time budget ran out when the clock was looked at for the
+    /// `check`th time in a tick.
+    OutOfTime {
+        check: u32,
+    },
+}
+
+/// A recorded session: where it started and everything it took in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub start: Snapshot,
+    /// The time on the clock when the recording started, which the timers in
+    /// `start` carry on from.
+    pub start_time: f64,
+    pub inputs: Vec<Input>,
+}
+
+impl Recording {
+    /// Encodes the recording in the same binary format as snapshots.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        decode(
+            MAGIC,
+            bytes,
+            [
+                "not a recording",
+                "recording is corrupt",
+                "recording is from a different version",
+            ],
+        )
+    }
+}
+
+/// Where the inputs go to or come from.
+pub(crate) enum Tape {
+    // Boxed, since the snapshot in it is much bigger than the other variant
+    Recording(Box<Recording>),
+    Replaying {
+        inputs: VecDeque<Input>,
+        /// How many inputs have been taken, for reporting divergences.
+        position: usize,
+        divergence: Option<String>,
+    },
+}
+
+impl Tape {
+    pub fn is_replaying(&self) -> bool {
+        matches!(self, Tape::Replaying { .. })
+    }
+
+    pub fn record(&mut self, input: Input) {
+        if let Tape::Recording(recording) = self {
+            recording.inputs.push(input);
+        }
+    }
+
+    /// Takes the next input when replaying, if `accept` accepts it. Anything
+    /// else means the replay has diverged.
+    pub fn replay<T>(
+        &mut self,
+        expected: &str,
+        accept: impl FnOnce(&Input) -> Option<T>,
+    ) -> Option<T> {
+        let (inputs, position, divergence) = match self {
+            Tape::Replaying {
+                inputs,
+                position,
+                divergence,
+            } => (inputs, position, divergence),
+            Tape::Recording(_) => return None,
+        };
+        if divergence.is_some() {
+            return None;
+        }
+        let accepted = inputs.front().and_then(accept);
+        if accepted.is_some() {
+            inputs.pop_front();
+            *position += 1;
+        } else {
+            *divergence = Some(match inputs.front() {
+                Some(input) => format!(
+                    "Replay diverged (@input {}): expected {}, but the recording has {:?}",
+                    position, expected, input
+                ),
+                None => format!(
+                    "Replay diverged (@input {}): expected {}, but the recording ended",
+                    position, expected
+                ),
+            });
+        }
+        accepted
+    }
+
+    /// Takes the next input if it's one that happens between ticks, like a
+    /// host promise resolution or a key press.
+    pub fn replay_between_ticks(&mut self) -> Option<Input> {
+        match self {
+            Tape::Replaying {
+                inputs, position, ..
+            } => match inputs.front() {
+                Some(
+                    Input::HostPromise { .. }
+                    | Input::Key { .. }
+                    | Input::MouseMove { .. }
+                    | Input::MouseButton { .. }
+                    | Input::Answer(_),
+                ) => {
+                    *position += 1;
+                    inputs.pop_front()
+                }
+                _ => None,
+            },
+            Tape::Recording(_) => None,
+        }
+    }
+
+    /// Takes the next input if it's the time budget running out at the
+    /// `check`th look at the clock. Anything else just means it didn't run
+    /// out there.
+    pub fn replay_out_of_time(&mut self, check: u32) -> bool {
+        match self {
+            Tape::Replaying {
+                inputs,
+                position,
+                divergence: None,
+            } if inputs.front() == Some(&Input::OutOfTime { check }) => {
+                *position += 1;
+                inputs.pop_front();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the state hash at a yield, or checks it against the recording.
+    pub fn checkpoint(&mut self, hash: u64) {
+        if self.is_replaying() {
+            self.replay("the same state at a yield", |input| match input {
+                Input::Checkpoint(recorded) if *recorded == hash => Some(()),
+                _ => None,
+            });
+        } else {
+            self.record(Input::Checkpoint(hash));
+        }
+    }
+
+    pub fn divergence(&self) -> Option<&str> {
+        match self {
+            Tape::Replaying { divergence, .. } => divergence.as_deref(),
+            Tape::Recording(_) => None,
+        }
+    }
+
+    /// The number of inputs left to replay.
+    pub fn remaining(&self) -> Option<usize> {
+        match self {
+            Tape::Replaying { inputs, .. } => Some(inputs.len()),
+            Tape::Recording(_) => None,
+        }
+    }
+}
+
+/// Puts the runtime's clock behind the tape, if there is one.
+pub(crate) struct TapedClock<'a> {
+    pub inner: &'a dyn Clock,
+    pub tape: Option<&'a RefCell<Tape>>,
+}
+
+impl Clock for TapedClock<'_> {
+    fn now(&self) -> f64 {
+        let tape = match self.tape {
+            Some(tape) => tape,
+            None => return self.inner.now(),
+        };
+        let mut tape = tape.borrow_mut();
+        if tape.is_replaying() {
+            let replayed = tape.replay("a clock reading", |input| match input {
+                Input::Clock(time) => Some(*time),
+                _ => None,
+            });
+            // Carry on with the real time once diverged
+            return replayed.unwrap_or_else(|| self.inner.now());
+        }
+        let time = self.inner.now();
+        tape.record(Input::Clock(time));
+        time
+    }
+}
+
+/// The clock the time budget reads, behind the tape if there is one. Only the
+/// look at the clock that finds the time is up is recorded, and the replay
+/// runs out of time at that same look instead of reading the clock.
+pub(crate) struct TapedDeadline<'a> {
+    pub inner: &'a dyn Clock,
+    /// The time the budget runs out at.
+    pub deadline: f64,
+    pub tape: Option<&'a RefCell<Tape>>,
+    checks: Cell<u32>,
+}
+
+impl<'a> TapedDeadline<'a> {
+    pub fn new(inner: &'a dyn Clock, deadline: f64, tape: Option<&'a RefCell<Tape>>) -> Self {
+        Self {
+            inner,
+            deadline,
+            tape,
+            checks: Cell::new(0),
+        }
+    }
+}
+
+impl Clock for TapedDeadline<'_> {
+    fn now(&self) -> f64 {
+        let check = self.checks.get();
+        self.checks.set(check + 1);
+        let tape = match self.tape {
+            Some(tape) => tape,
+            None => return self.inner.now(),
+        };
+        let mut tape = tape.borrow_mut();
+        if tape.is_replaying() && tape.divergence().is_none() {
+            return if tape.replay_out_of_time(check) {
+                self.deadline
+            } else {
+                f64::NEG_INFINITY
+            };
+        }
+        let time = self.inner.now();
+        if time >= self.deadline {
+            tape.record(Input::OutOfTime { check });
+        }
+        time
+    }
+}
+
+/// Puts the runtime's random number generator behind the tape, if there is
+/// one.
+pub(crate) struct TapedRandom<'a> {
+    pub inner: &'a mut dyn Random,
+    pub tape: Option<&'a RefCell<Tape>>,
+}
+
+impl Random for TapedRandom<'_> {
+    fn random(&mut self) -> f64 {
+        let tape = match self.tape {
+            Some(tape) => tape,
+            None => return self.inner.random(),
+        };
+        let mut tape = tape.borrow_mut();
+        if tape.is_replaying() {
+            let replayed = tape.replay("a random number", |input| match input {
+                Input::Random(value) => Some(*value),
+                _ => None,
+            });
+            return replayed.unwrap_or_else(|| self.inner.random());
+        }
+        let value = self.inner.random();
+        tape.record(Input::Random(value));
+        value
+    }
+}
+
+/// Puts the runtime's host behind the tape, if there is one. The host isn't
+/// called at all when replaying.
+pub(crate) struct TapedHost<'a> {
+    pub inner: &'a mut dyn HostCallHandler,
+    pub tape: Option<&'a RefCell<Tape>>,
+}
+
+impl TapedHost<'_> {
+    /// Makes a call to the host, or reads what it returned from the tape.
+    fn call(
+        &mut self,
+        call: impl FnOnce(&mut dyn HostCallHandler) -> Result<HostCallResult, &'static str>,
+    ) -> Result<HostCallResult, &'static str> {
+        let tape = match self.tape {
+            Some(tape) => tape,
+            None => return call(self.inner),
+        };
+        if tape.borrow().is_replaying() {
+            let replayed = tape
+                .borrow_mut()
+                .replay("a host call", |input| match input {
+                    Input::HostCall(result) => Some(result.clone()),
+                    _ => None,
+                })
+                .ok_or("replay diverged")?;
+            return replayed.ok_or("host call failed when recorded");
+        }
+        // The host might take a while, so don't hold on to the tape
+        let result = call(self.inner);
+        tape.borrow_mut()
+            .record(Input::HostCall(result.as_ref().ok().cloned()));
+        result
+    }
+}
+
+impl HostCallHandler for TapedHost<'_> {
+    fn call_host(
+        &mut self,
+        id: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        self.call(|host| host.call_host(id, arguments))
+    }
+
+    fn extension_block(&self, block: u32) -> Option<&BlockSignature> {
+        self.inner.extension_block(block)
+    }
+
+    fn call_extension(
+        &mut self,
+        block: u32,
+        arguments: Vec<ScratchValue>,
+    ) -> Result<HostCallResult, &'static str> {
+        self.call(|host| host.call_extension(block, arguments))
+    }
+
+    fn thread_parked(&mut self, thread: u32) {
+        if !self.tape.is_some_and(|tape| tape.borrow().is_replaying()) {
+            self.inner.thread_parked(thread);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::extension::BlockType;
+    use crate::hat::Hat;
+    use crate::random::SeededRandom;
+    use crate::runtime::Runtime;
+    use crate::thread::ThreadState;
+    use crate::transmute_instructions;
+
+    use super::*;
+
+    fn runtime() -> Runtime {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000640000003cu64, // LOAD_CONST_INT 100
+                0x0000000000000033u64, // DATA_RAND 0
+                0x000000000000003fu64, // CALL_HOST 0
+                0x0001000100000001u64, // EXTRA_ARG 1 | REPORTER
+                0x000000010000003fu64, // CALL_HOST 1
+                0x0003000000000001u64, // EXTRA_ARG REPORTER | AWAIT
+                0x0000000000000008u64, // OP_ADD
+                0x0000000000000024u64, // LIST_PUSH 0
+                0x0000000000000042u64, // YIELD
+                0xfffffff500000005u64, // JUMP -11
+            ])
+        };
+        Runtime::new(instructions.to_vec(), vec![], vec![], vec![vec![]])
+    }
+
+    /// Runs a few ticks, resolving the host promise with a different value
+    /// each time.
+    fn record() -> (Recording, Vec<ScratchValue>) {
+        let clock = ManualClock::new(0.0);
+        let mut runtime = runtime();
+        runtime.set_clock(Box::new(clock.clone()));
+        runtime.set_random(Box::new(SeededRandom::new(1)));
+        runtime.set_host(Box::new(|id, arguments: Vec<ScratchValue>| {
+            Ok(match id {
+                0 => HostCallResult::Value(arguments[0].clone() * ScratchValue::Number(2.0)),
+                _ => HostCallResult::Pending,
+            })
+        }));
+        let thread = runtime.start_thread(0, 1);
+        runtime.start_recording();
+        for tick in 0..6 {
+            runtime.step().unwrap();
+            if runtime.thread(thread).unwrap().state
+                == (ThreadState::WaitingOnHostPromise {
+                    block_type: BlockType::Reporter,
+                })
+            {
+                runtime
+                    .resolve_host_promise(thread, Some(ScratchValue::Number(tick as f64)))
+                    .unwrap();
+            }
+            clock.advance(16.0);
+        }
+        let recording = runtime.stop_recording().unwrap();
+        (recording, runtime.lists[0].clone())
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let (recording, list) = record();
+        assert_eq!(list.len(), 3);
+        let recording = Recording::from_bytes(&recording.to_bytes()).unwrap();
+
+        // Nothing here should be used while replaying
+        let mut runtime = runtime();
+        runtime.set_clock(Box::new(ManualClock::new(1e6)));
+        runtime.set_random(Box::new(SeededRandom::new(99)));
+        runtime.start_replay(recording).unwrap();
+        assert!(runtime.resolve_host_promise(0, None).is_err());
+        for _ in 0..6 {
+            runtime.step().unwrap();
+        }
+        assert_eq!(runtime.replay_remaining(), Some(0));
+        assert_eq!(runtime.lists[0], list);
+        // Past the end of the recording
+        assert!(runtime.step().unwrap_err().contains("the recording ended"));
+        assert!(!runtime.replaying());
+    }
+
+    #[test]
+    fn replay_detects_divergence() {
+        let (mut recording, _) = record();
+        let host_call = recording
+            .inputs
+            .iter_mut()
+            .find(|input| matches!(input, Input::HostCall(Some(_))))
+            .unwrap();
+        *host_call = Input::HostCall(Some(HostCallResult::Value(ScratchValue::EMPTY)));
+        let mut runtime = runtime();
+        runtime.start_replay(recording).unwrap();
+        let err = (0..6)
+            .find_map(|_| runtime.step().err())
+            .expect("replay should diverge");
+        assert!(err.contains("the same state at a yield"), "{}", err);
+        assert!(!runtime.replaying());
+    }
+
+    #[test]
+    fn replay_key_presses() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000024u64, // LIST_PUSH 0
+            ])
+        };
+        let runtime = || {
+            let mut runtime = Runtime::new(instructions.to_vec(), vec![], vec![], vec![vec![]]);
+            let sprite = runtime.add_target("Sprite1".into(), false);
+            runtime
+                .add_hat(sprite, Hat::KeyPressed("any".into()), 0)
+                .unwrap();
+            runtime
+        };
+        let mut original = runtime();
+        original.start_recording();
+        for key in ["a", "Shift", "b"] {
+            original.key_down(key).unwrap();
+            original.step().unwrap();
+        }
+        let recording = original.stop_recording().unwrap();
+        assert_eq!(original.lists[0].len(), 2);
+
+        let mut replayed = runtime();
+        replayed.start_replay(recording).unwrap();
+        assert!(replayed.key_down("c").is_err());
+        for _ in 0..3 {
+            replayed.step().unwrap();
+        }
+        assert_eq!(replayed.lists, original.lists);
+        assert_eq!(replayed.io, original.io);
+    }
+
+    #[test]
+    fn replay_dates_but_not_budget_checks() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000036u64, // DATA_DAYSSINCE2000
+                0x0000000000000024u64, // LIST_PUSH 0
+            ])
+        };
+        let runtime = || {
+            let mut runtime = Runtime::new(instructions.to_vec(), vec![], vec![], vec![vec![]]);
+            runtime.time_budget = Some(1000.0);
+            runtime
+        };
+        let mut original = runtime();
+        // A day after the start of 2000
+        original.set_clock(Box::new(ManualClock::new(946771200000.0)));
+        original.start_thread(0, 0);
+        original.start_recording();
+        original.step().unwrap();
+        let recording = original.stop_recording().unwrap();
+        assert_eq!(original.lists[0], [ScratchValue::Number(1.0)]);
+        // The tick's and the block's, but none from the budget
+        let clock_readings = recording
+            .inputs
+            .iter()
+            .filter(|input| matches!(input, Input::Clock(_)))
+            .count();
+        assert_eq!(clock_readings, 2);
+
+        let mut replayed = runtime();
+        replayed.start_replay(recording).unwrap();
+        replayed.step().unwrap();
+        assert_eq!(replayed.lists, original.lists);
+    }
+    /// Moves on a millisecond every time it's read.
+    struct TickingClock(Cell<f64>);
+
+    impl Clock for TickingClock {
+        fn now(&self) -> f64 {
+            self.0.set(self.0.get() + 1.0);
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn replay_where_the_time_budget_ran_out() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000024u64, // LIST_PUSH 0
+                0xfffffffd00000005u64, // JUMP -3
+            ])
+        };
+        let runtime = || {
+            let mut runtime = Runtime::new(instructions.to_vec(), vec![], vec![], vec![vec![]]);
+            // The instruction budget is only there in case the time budget
+            // never runs out
+            runtime.instruction_budget = Some(100_000);
+            runtime.time_budget = Some(5.0);
+            runtime
+        };
+        let mut original = runtime();
+        original.set_clock(Box::new(TickingClock(Cell::new(0.0))));
+        original.start_thread(0, 0);
+        original.start_recording();
+        original.step().unwrap();
+        original.step().unwrap();
+        let recording = original.stop_recording().unwrap();
+        assert!(original.budget_exhausted);
+        assert!(original.lists[0].len() < 50_000);
+
+        // A clock that doesn't move would never run out of time by itself
+        let mut replayed = runtime();
+        replayed.set_clock(Box::new(ManualClock::new(0.0)));
+        replayed.start_replay(recording).unwrap();
+        replayed.step().unwrap();
+        replayed.step().unwrap();
+        assert_eq!(replayed.replay_remaining(), Some(0));
+        assert_eq!(replayed.lists, original.lists);
+    }
+}
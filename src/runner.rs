@@ -1,20 +1,161 @@
-use wasm_bindgen::JsValue;
-use web_sys::console;
-
+use crate::clock::{Clock, ManualClock};
+use crate::collision::{BoundingBoxCollision, CollisionProvider};
+use crate::debugger::{Debugger, TraceEvent};
 use crate::execute_instruction::execute_instruction;
-use crate::instruction::{Instruction, InstructionType};
+use crate::host::HostCallHandler;
+use crate::instruction::{Instruction, InstructionType, ReturnReason};
+use crate::io::IoDevices;
+use crate::limits::ListLimits;
+use crate::monitor::Monitors;
+use crate::pen::PenLayer;
+use crate::random::{Random, SeededRandom};
 use crate::scratch_value::ScratchValue;
+use crate::target::Target;
+
+/// How many instructions to run between looking at the clock for a wall-clock
+/// budget, since reading it is much slower than running an instruction.
+const CLOCK_CHECK_INTERVAL: u32 = 256;
+
+/// Limits how much `run_instructions` may run before handing control back with
+/// `BudgetExhausted`. The same budget can be shared between several calls, e.g.
+/// all the threads in a frame. The default is unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct Budget<'a> {
+    /// The number of instructions that may still be run, if limited.
+    pub instructions: Option<u64>,
+    /// The clock time (in milliseconds) to stop at, if limited, and the clock
+    /// to read. It's kept apart from the clock the scripts read, so that
+    /// checking it isn't recorded.
+    pub deadline: Option<(f64, &'a dyn Clock)>,
+    since_clock_check: u32,
+}
+
+impl<'a> Budget<'a> {
+    pub fn new(instructions: Option<u64>, deadline: Option<(f64, &'a dyn Clock)>) -> Self {
+        Self {
+            instructions,
+            deadline,
+            since_clock_check: 0,
+        }
+    }
+
+    /// Takes one instruction out of the budget, returning `false` (without
+    /// taking anything) if there's nothing left.
+    #[inline]
+    fn consume(&mut self) -> bool {
+        if let Some(instructions) = self.instructions {
+            if instructions == 0 {
+                return false;
+            }
+            self.instructions = Some(instructions - 1);
+        }
+        if let Some((deadline, clock)) = self.deadline {
+            if self.since_clock_check == 0 && clock.now() >= deadline {
+                return false;
+            }
+            self.since_clock_check = (self.since_clock_check + 1) % CLOCK_CHECK_INTERVAL;
+        }
+        true
+    }
+}
+
+/// Everything the instructions can reach besides the program and its stores:
+/// the stage and sprites, the input devices, the pen and the sources of time
+/// and randomness.
+pub struct Context<'a> {
+    pub list_limits: &'a ListLimits,
+    /// Roughly how many bytes the items in the lists take up (see
+    /// `lists_size`), which the instructions keep up to date.
+    pub list_size: &'a mut usize,
+    pub monitors: &'a mut Monitors,
+    pub targets: &'a mut [Target],
+    /// The index in `targets` of the sprite (or stage) running the script.
+    pub current_target: Option<usize>,
+    pub io: &'a IoDevices,
+    pub collision: &'a dyn CollisionProvider,
+    pub pen_layer: &'a mut PenLayer,
+    pub clock: &'a dyn Clock,
+    pub random: &'a mut dyn Random,
+}
+
+/// Owns what a `Context` borrows, for running instructions without a runtime:
+/// no targets, no input, a clock stopped at 0 and a generator seeded with 0.
+#[derive(Default)]
+pub struct StandaloneContext {
+    pub list_limits: ListLimits,
+    /// Needs setting if the lists start out with items in them and there's a
+    /// limit on their total size.
+    pub list_size: usize,
+    pub monitors: Monitors,
+    pub targets: Vec<Target>,
+    pub current_target: Option<usize>,
+    pub io: IoDevices,
+    pub pen_layer: PenLayer,
+    pub clock: ManualClock,
+    pub random: SeededRandom,
+}
 
+impl StandaloneContext {
+    pub fn context(&mut self) -> Context<'_> {
+        Context {
+            list_limits: &self.list_limits,
+            list_size: &mut self.list_size,
+            monitors: &mut self.monitors,
+            targets: &mut self.targets,
+            current_target: self.current_target,
+            io: &self.io,
+            collision: &BoundingBoxCollision,
+            pen_layer: &mut self.pen_layer,
+            clock: &self.clock,
+            random: &mut self.random,
+        }
+    }
+}
+
+/// Runs the instructions starting at `program_counter` until one of them
+/// hands control back, the end of the program is reached, `budget` runs out or
+/// `debugger` pauses. In the last two cases, `program_counter` is left at the
+/// next instruction to run so that execution can be resumed from there.
+#[allow(clippy::too_many_arguments)]
 pub fn run_instructions(
     program_counter: &mut usize,
     stack: &mut Vec<ScratchValue>,
     instructions: &[Instruction],
-    constants: &Vec<ScratchValue>,
-    variables: &mut Vec<ScratchValue>,
-    lists: &mut Vec<Vec<ScratchValue>>,
-) -> Result<Option<u32>, JsValue> {
+    constants: &[ScratchValue],
+    variables: &mut [ScratchValue],
+    lists: &mut [Vec<ScratchValue>],
+    host: &mut dyn HostCallHandler,
+    budget: &mut Budget<'_>,
+    context: &mut Context<'_>,
+    mut debugger: Option<&mut Debugger>,
+) -> Result<Option<u32>, String> {
     let mut early_return = None;
     while early_return.is_none() && *program_counter < instructions.len() {
+        let mut stack_before = None;
+        #[cfg(feature = "profiling")]
+        let mut profile_start = None;
+        if debugger
+            .as_deref()
+            .is_some_and(|debugger| debugger.should_pause(*program_counter))
+        {
+            return Ok(Some(ReturnReason::DebugPause as u32));
+        }
+        if !budget.consume() {
+            return Ok(Some(ReturnReason::BudgetExhausted as u32));
+        }
+        if let Some(debugger) = debugger.as_deref_mut() {
+            debugger.running();
+            if debugger.is_tracing() {
+                stack_before = Some(stack.clone());
+            }
+            #[cfg(feature = "profiling")]
+            {
+                profile_start = debugger
+                    .profile_start()
+                    .map(|started| (started, stack.len()));
+            }
+        }
+        let instruction_program_counter = *program_counter;
         let instruction = &instructions[*program_counter];
         let result = execute_instruction(
             instruction,
@@ -22,32 +163,50 @@ pub fn run_instructions(
             constants,
             variables,
             lists,
+            context,
             &mut |offset| {
                 *program_counter = program_counter.saturating_add_signed(offset);
-                #[cfg(feature = "safety_checks")]
-                if *program_counter >= instructions.len() {
-                    console::error_1(&JsValue::from_str("new counter too large"));
-                    return None;
-                }
-                let instruction = &instructions[*program_counter];
-                match instruction {
-                    Instruction {
+                // Jumping past the end just ends the program, so there's no
+                // instruction there to read
+                match instructions.get(*program_counter) {
+                    Some(Instruction {
                         name: InstructionType::ExtraArg,
                         argument,
                         ..
-                    } => Some(*argument),
+                    }) => Some(*argument),
                     _ => None,
                 }
             },
             &mut |argument| {
                 early_return = Some(argument);
             },
+            host,
         );
         if let Err(res_err) = result {
-            return Err(JsValue::from_str(&format!(
+            return Err(format!(
                 "Instruction failed to execute (@{}): {} (stack = {:?})",
                 *program_counter, res_err, stack
-            )));
+            ));
+        }
+        #[cfg(feature = "profiling")]
+        if let (Some(debugger), Some((started, stack_depth))) =
+            (debugger.as_deref_mut(), profile_start)
+        {
+            debugger.profile_end(
+                started,
+                instruction_program_counter,
+                instruction.name,
+                stack_depth,
+            );
+        }
+        if let (Some(debugger), Some(stack_before)) = (debugger.as_deref_mut(), stack_before) {
+            debugger.trace(&TraceEvent {
+                program_counter: instruction_program_counter,
+                instruction: instruction.name,
+                argument: instruction.argument,
+                stack_before: &stack_before,
+                stack_after: stack,
+            });
         }
         *program_counter += 1;
     }
@@ -56,8 +215,10 @@ pub fn run_instructions(
 
 #[cfg(test)]
 mod tests {
-    use std::vec;
+    use std::{cell::RefCell, rc::Rc, vec};
 
+    use crate::clock::ManualClock;
+    use crate::host::{HostCallResult, NoHost};
     use crate::transmute_instructions;
 
     use super::*;
@@ -71,9 +232,13 @@ mod tests {
             &mut program_counter,
             &mut stack,
             instructions,
-            &vec![],
-            &mut vec![],
-            &mut vec![],
+            &[],
+            &mut [],
+            &mut [],
+            &mut NoHost,
+            &mut Budget::default(),
+            &mut StandaloneContext::default().context(),
+            None,
         )
         .unwrap();
     }
@@ -95,8 +260,12 @@ mod tests {
             &mut stack,
             instructions,
             &constants,
-            &mut vec![],
-            &mut vec![],
+            &mut [],
+            &mut [],
+            &mut NoHost,
+            &mut Budget::default(),
+            &mut StandaloneContext::default().context(),
+            None,
         )
         .unwrap();
         assert_eq!(
@@ -106,4 +275,259 @@ mod tests {
             stack
         );
     }
+
+    #[test]
+    fn test_runtime_call_host() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000a0000003cu64, // LOAD_CONST_INT 10
+                0x000000030000003cu64, // LOAD_CONST_INT 3
+                0x000000070000003fu64, // CALL_HOST 7
+                0x0001000200000001u64, // EXTRA_ARG 2 | REPORTER
+            ])
+        };
+        let mut program_counter = 0;
+        let mut stack = vec![];
+        let mut calls = vec![];
+        run_instructions(
+            &mut program_counter,
+            &mut stack,
+            instructions,
+            &[],
+            &mut [],
+            &mut [],
+            &mut |id, arguments: Vec<ScratchValue>| {
+                calls.push((id, arguments.clone()));
+                Ok(HostCallResult::Value(
+                    arguments[0].clone() - arguments[1].clone(),
+                ))
+            },
+            &mut Budget::default(),
+            &mut StandaloneContext::default().context(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            calls,
+            [(
+                7,
+                vec![ScratchValue::Number(10.0), ScratchValue::Number(3.0)]
+            )]
+        );
+        assert_eq!(stack, [ScratchValue::Number(7.0)]);
+        assert_eq!(program_counter, 4);
+    }
+
+    #[test]
+    fn test_runtime_call_host_await() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000000000003fu64, // CALL_HOST 0
+                0x0003000000000001u64, // EXTRA_ARG REPORTER | AWAIT
+                0x0000000000000002u64, // LOAD_CONST 0
+            ])
+        };
+        let mut program_counter = 0;
+        let mut stack = vec![];
+        let return_reason = run_instructions(
+            &mut program_counter,
+            &mut stack,
+            instructions,
+            &[ScratchValue::Number(1.0)],
+            &mut [],
+            &mut [],
+            &mut |_, _| Ok(HostCallResult::Pending),
+            &mut Budget::default(),
+            &mut StandaloneContext::default().context(),
+            None,
+        )
+        .unwrap();
+        // The thread should stop right after the call without pushing anything
+        assert_eq!(return_reason, Some(ReturnReason::HostPromise as u32));
+        assert_eq!(program_counter, 2);
+        assert_eq!(stack, []);
+    }
+
+    #[test]
+    fn test_runtime_instruction_budget() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0xfffffffe00000005u64, // JUMP -2
+            ])
+        };
+        let mut program_counter = 0;
+        let mut budget = Budget::new(Some(10), None);
+        let return_reason = run_instructions(
+            &mut program_counter,
+            &mut vec![],
+            instructions,
+            &[],
+            &mut [],
+            &mut [],
+            &mut NoHost,
+            &mut budget,
+            &mut StandaloneContext::default().context(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(return_reason, Some(ReturnReason::BudgetExhausted as u32));
+        assert_eq!(budget.instructions, Some(0));
+        assert_eq!(program_counter, 1);
+    }
+
+    #[test]
+    fn test_runtime_time_budget() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0xfffffffe00000005u64, // JUMP -2
+            ])
+        };
+        let clock = ManualClock::new(100.0);
+        let mut program_counter = 0;
+        let return_reason = run_instructions(
+            &mut program_counter,
+            &mut vec![],
+            instructions,
+            &[],
+            &mut [],
+            &mut [],
+            &mut NoHost,
+            &mut Budget::new(None, Some((100.0, &clock))),
+            &mut StandaloneContext::default().context(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(return_reason, Some(ReturnReason::BudgetExhausted as u32));
+        assert_eq!(program_counter, 0);
+    }
+
+    #[test]
+    fn test_runtime_breakpoint() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000020000003cu64, // LOAD_CONST_INT 2
+                0x0000000000000008u64, // OP_ADD
+            ])
+        };
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.insert(2);
+        let mut program_counter = 0;
+        let mut stack = vec![];
+        let mut run = |program_counter: &mut usize, debugger: &mut Debugger| {
+            run_instructions(
+                program_counter,
+                &mut stack,
+                instructions,
+                &[],
+                &mut [],
+                &mut [],
+                &mut NoHost,
+                &mut Budget::default(),
+                &mut StandaloneContext::default().context(),
+                Some(debugger),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            run(&mut program_counter, &mut debugger),
+            Some(ReturnReason::DebugPause as u32)
+        );
+        assert_eq!(program_counter, 2);
+        // Resuming should get past the breakpoint
+        debugger.resume();
+        assert_eq!(run(&mut program_counter, &mut debugger), None);
+        assert_eq!(program_counter, 3);
+        assert_eq!(stack, [ScratchValue::Number(3.0)]);
+    }
+
+    #[test]
+    fn test_runtime_resume_outlasts_budget() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+            ])
+        };
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.insert(0);
+        let mut program_counter = 0;
+        let mut stack = vec![];
+        let mut run = |debugger: &mut Debugger, budget: Option<u64>| {
+            run_instructions(
+                &mut program_counter,
+                &mut stack,
+                instructions,
+                &[],
+                &mut [],
+                &mut [],
+                &mut NoHost,
+                &mut Budget::new(budget, None),
+                &mut StandaloneContext::default().context(),
+                Some(debugger),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            run(&mut debugger, None),
+            Some(ReturnReason::DebugPause as u32)
+        );
+        debugger.resume();
+        assert_eq!(
+            run(&mut debugger, Some(0)),
+            Some(ReturnReason::BudgetExhausted as u32)
+        );
+        // The resume shouldn't have been used up by the budget running out
+        assert_eq!(run(&mut debugger, None), None);
+        assert_eq!(stack, [ScratchValue::Number(1.0)]);
+    }
+
+    #[test]
+    fn test_runtime_trace_hook() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000020000003cu64, // LOAD_CONST_INT 2
+                0x0000000000000008u64, // OP_ADD
+            ])
+        };
+        let trace = Rc::new(RefCell::new(vec![]));
+        let mut debugger = Debugger::new();
+        debugger.trace_hook = Some(Box::new({
+            let trace = trace.clone();
+            move |event| {
+                trace.borrow_mut().push((
+                    event.program_counter,
+                    event.instruction,
+                    event.stack_before.to_vec(),
+                    event.stack_after.to_vec(),
+                ))
+            }
+        }));
+        run_instructions(
+            &mut 0,
+            &mut vec![],
+            instructions,
+            &[],
+            &mut [],
+            &mut [],
+            &mut NoHost,
+            &mut Budget::default(),
+            &mut StandaloneContext::default().context(),
+            Some(&mut debugger),
+        )
+        .unwrap();
+        let trace = trace.borrow();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(
+            trace[2],
+            (
+                2,
+                InstructionType::OpAdd,
+                vec![ScratchValue::Number(1.0), ScratchValue::Number(2.0)],
+                vec![ScratchValue::Number(3.0)]
+            )
+        );
+    }
 }
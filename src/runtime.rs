@@ -0,0 +1,1911 @@
+use std::{cell::RefCell, convert::TryFrom, rc::Rc};
+
+use crate::bubble::{BubbleEvent, BubbleKind};
+use crate::clock::{Clock, SystemClock};
+use crate::collision::{BoundingBoxCollision, CollisionProvider};
+use crate::debugger::Debugger;
+use crate::extension::{BlockSignature, BlockType, ExtendedHost, ExtensionHandler, Extensions};
+use crate::hat::{Hat, HatScript};
+use crate::host::{HostCallHandler, NoHost, HOST_CALL_REPORTER};
+use crate::instruction::{Instruction, InstructionType, ReturnReason};
+use crate::io::{key_matches, IoDevices};
+use crate::limits::{lists_size, ListLimits};
+use crate::looks::{restack, LayerPosition};
+use crate::monitor::Monitors;
+use crate::motion::Glide;
+use crate::pen::PenLayer;
+use crate::question::{Question, Questions};
+use crate::random::{Random, SeededRandom};
+use crate::replay::{Input, Recording, Tape, TapedClock, TapedDeadline, TapedHost, TapedRandom};
+use crate::runner::{run_instructions, Budget, Context};
+use crate::scratch_value::ScratchValue;
+use crate::snapshot::{program_hash, Fnv1a, Snapshot};
+use crate::sound::{HeadlessSoundPlayer, SoundPlayer};
+use crate::target::Target;
+use crate::thread::{Thread, ThreadState};
+
+/// Owns a loaded program, its stores and its threads, and schedules the
+/// threads cooperatively like scratch-vm's sequencer does.
+pub struct Runtime {
+    pub instructions: Vec<Instruction>,
+    pub constants: Vec<ScratchValue>,
+    pub variables: Vec<ScratchValue>,
+    pub lists: Vec<Vec<ScratchValue>>,
+    pub threads: Vec<Thread>,
+    pub targets: Vec<Target>,
+    pub monitors: Monitors,
+    /// The keyboard and mouse, kept up to date by the host through `key_down`
+    /// and the like.
+    pub io: IoDevices,
+    /// The questions from "ask and wait" that haven't been answered yet.
+    pub questions: Questions,
+    /// What the sprites' pens have drawn.
+    pub pen_layer: PenLayer,
+    /// Set when a thread returned with `Repaint` during the last tick.
+    pub redraw_requested: bool,
+    /// The number of instructions all the threads may run in one tick, if
+    /// limited.
+    pub instruction_budget: Option<u64>,
+    /// How long (in milliseconds, by the clock) a tick may take, if limited.
+    pub time_budget: Option<f64>,
+    /// Set when the last tick ended because it ran out of budget.
+    pub budget_exhausted: bool,
+    /// In turbo mode, a repaint doesn't end the tick: the threads keep taking
+    /// turns until the budget runs out, like in scratch-vm's sequencer. With
+    /// no budget, they each get one turn.
+    pub turbo_mode: bool,
+    /// How big lists may get, and what happens when they would get bigger.
+    pub list_limits: ListLimits,
+    /// Roughly how many bytes the items in `lists` take up.
+    list_size: usize,
+    pub debugger: Option<Debugger>,
+    /// The thread the debugger stopped, if any. Nothing runs until `resume`
+    /// is called.
+    pub paused_thread: Option<u32>,
+    /// The thread the debugger's `single_step` applies to, so that the others
+    /// carry on as normal. If it's `None`, the first thread to run is picked.
+    pub stepping_thread: Option<u32>,
+    resuming_thread: Option<u32>,
+    /// The thread the budget cut off in the last tick, which the next tick
+    /// starts from so that the threads after it get a turn too.
+    cut_off_thread: Option<u32>,
+    next_thread_id: u32,
+    next_target_id: u32,
+    clock: Rc<dyn Clock>,
+    random: Box<dyn Random>,
+    host: Box<dyn HostCallHandler>,
+    collision: Box<dyn CollisionProvider>,
+    sound_player: Box<dyn SoundPlayer>,
+    extensions: Extensions,
+    /// Where inputs are recorded to or replayed from, if anywhere. Shared
+    /// with the time budget during a tick.
+    tape: Option<Rc<RefCell<Tape>>>,
+}
+
+impl Runtime {
+    pub fn new(
+        instructions: Vec<Instruction>,
+        constants: Vec<ScratchValue>,
+        variables: Vec<ScratchValue>,
+        lists: Vec<Vec<ScratchValue>>,
+    ) -> Self {
+        Self {
+            instructions,
+            constants,
+            variables,
+            list_size: lists_size(&lists),
+            lists,
+            threads: Vec::new(),
+            targets: Vec::new(),
+            monitors: Monitors::default(),
+            io: IoDevices::default(),
+            questions: Questions::default(),
+            pen_layer: PenLayer::default(),
+            redraw_requested: false,
+            instruction_budget: None,
+            time_budget: None,
+            budget_exhausted: false,
+            turbo_mode: false,
+            list_limits: ListLimits::default(),
+            debugger: None,
+            paused_thread: None,
+            stepping_thread: None,
+            resuming_thread: None,
+            cut_off_thread: None,
+            next_thread_id: 0,
+            next_target_id: 0,
+            clock: Rc::new(SystemClock),
+            random: Box::new(SeededRandom::from_time()),
+            host: Box::new(NoHost),
+            collision: Box::new(BoundingBoxCollision),
+            sound_player: Box::new(HeadlessSoundPlayer::default()),
+            extensions: Extensions::default(),
+            tape: None,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock.into();
+    }
+
+    pub fn set_random(&mut self, random: Box<dyn Random>) {
+        self.random = random;
+    }
+
+    pub fn set_host(&mut self, host: Box<dyn HostCallHandler>) {
+        self.host = host;
+    }
+
+    /// Changes how the "touching" blocks are answered, which is with
+    /// `BoundingBoxCollision` to begin with.
+    pub fn set_collision_provider(&mut self, collision: Box<dyn CollisionProvider>) {
+        self.collision = collision;
+    }
+
+    /// Changes what plays the sounds, which is a `HeadlessSoundPlayer` to
+    /// begin with.
+    pub fn set_sound_player(&mut self, sound_player: Box<dyn SoundPlayer>) {
+        self.sound_player = sound_player;
+    }
+
+    /// Binds an extension block to `handler` (see `Extensions::register`),
+    /// returning the opcode that runs it.
+    pub fn register_extension_block(
+        &mut self,
+        signature: BlockSignature,
+        handler: Box<dyn ExtensionHandler>,
+    ) -> Result<u16, &'static str> {
+        self.extensions.register(signature, handler)
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Adds a sprite (or the stage) and returns its ID.
+    pub fn add_target(&mut self, name: String, is_stage: bool) -> u32 {
+        let id = self.next_target_id;
+        self.next_target_id += 1;
+        self.targets.push(Target::new(id, name, is_stage));
+        // New sprites go in front of the others
+        let index = self.targets.len() - 1;
+        restack(&mut self.targets, index, LayerPosition::Front);
+        id
+    }
+
+    /// Makes a clone of the target `id` and returns the clone's ID.
+    pub fn clone_target(&mut self, id: u32) -> Result<u32, &'static str> {
+        let original = self.target(id).ok_or("no target with that id")?;
+        let original_id = original.id;
+        if original.is_stage {
+            return Err("the stage can't be cloned");
+        }
+        let mut clone = original.clone();
+        // Clones of clones are clones of the original sprite
+        clone.clone_of = Some(original.clone_of.unwrap_or(original.id));
+        let id = self.next_target_id;
+        self.next_target_id += 1;
+        clone.id = id;
+        // The renderer needs to draw the clone
+        clone.motion.transform_changed = true;
+        clone.looks.render_changed = true;
+        self.targets.push(clone);
+        // Clones start right behind the sprite they were cloned from
+        let index = self.targets.len() - 1;
+        restack(&mut self.targets, index, LayerPosition::Behind(original_id));
+        Ok(id)
+    }
+
+    pub fn target(&self, id: u32) -> Option<&Target> {
+        self.targets.iter().find(|target| target.id == id)
+    }
+
+    pub fn target_mut(&mut self, id: u32) -> Option<&mut Target> {
+        self.targets.iter_mut().find(|target| target.id == id)
+    }
+
+    /// Returns the IDs of the targets that moved, turned or changed rotation
+    /// style since the last call, so the renderer can update them all at
+    /// once after a tick.
+    pub fn take_transform_changes(&mut self) -> Vec<u32> {
+        self.targets
+            .iter_mut()
+            .filter_map(|target| {
+                std::mem::take(&mut target.motion.transform_changed).then_some(target.id)
+            })
+            .collect()
+    }
+
+    /// Returns the IDs of the targets whose costume, size, visibility,
+    /// effects or layer changed since the last call, for the renderer.
+    pub fn take_render_changes(&mut self) -> Vec<u32> {
+        self.targets
+            .iter_mut()
+            .filter_map(|target| {
+                std::mem::take(&mut target.looks.render_changed).then_some(target.id)
+            })
+            .collect()
+    }
+
+    /// Returns the speech and thought bubbles that changed since the last
+    /// call, for the host to draw.
+    pub fn take_bubble_changes(&mut self) -> Vec<BubbleEvent> {
+        self.targets
+            .iter_mut()
+            .filter(|target| target.bubble.changed)
+            .map(|target| {
+                target.bubble.changed = false;
+                BubbleEvent {
+                    target: target.id,
+                    kind: target.bubble.kind,
+                    text: target.bubble.text.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Starts a new thread for `target` at `program_counter` and returns its
+    /// ID.
+    pub fn start_thread(&mut self, target: u32, program_counter: usize) -> u32 {
+        let id = self.next_thread_id;
+        self.next_thread_id += 1;
+        self.threads.push(Thread::new(id, target, program_counter));
+        id
+    }
+
+    /// Adds a script to `target` that starts at `script` whenever `hat`
+    /// fires.
+    pub fn add_hat(&mut self, target: u32, hat: Hat, script: usize) -> Result<(), &'static str> {
+        let target = self.target_mut(target).ok_or("no target with that id")?;
+        target.hats.push(HatScript { hat, script });
+        Ok(())
+    }
+
+    /// Starts every script whose hat `fires` accepts, on the target `only` or
+    /// on all of them, and returns the IDs of the threads started. Scripts
+    /// that are still running are left alone or restarted, depending on the
+    /// hat.
+    pub fn start_hats(&mut self, fires: impl Fn(&Hat) -> bool, only: Option<u32>) -> Vec<u32> {
+        let scripts = self
+            .targets
+            .iter()
+            .filter(|target| only.is_none_or(|id| target.id == id))
+            .flat_map(|target| {
+                target
+                    .hats
+                    .iter()
+                    .filter(|hat| fires(&hat.hat))
+                    .map(move |hat| (target.id, hat.script, hat.hat.restarts_existing_threads()))
+            })
+            .collect::<Vec<_>>();
+        let mut started = Vec::new();
+        for (target, script, restart) in scripts {
+            let running = self.threads.iter_mut().find(|thread| {
+                thread.target == target
+                    && thread.script == script
+                    && thread.state != ThreadState::Finished
+            });
+            match running {
+                Some(thread) if restart => {
+                    thread.restart();
+                    started.push(thread.id);
+                }
+                Some(_) => {}
+                None => started.push(self.start_thread(target, script)),
+            }
+        }
+        started
+    }
+
+    /// Tells the runtime a key (a `KeyboardEvent.key`) was pressed, which
+    /// starts the "when key pressed" scripts for it.
+    pub fn key_down(&mut self, key: &str) -> Result<(), &'static str> {
+        self.post_input(Input::Key {
+            key: key.to_string(),
+            down: true,
+        })
+    }
+
+    pub fn key_up(&mut self, key: &str) -> Result<(), &'static str> {
+        self.post_input(Input::Key {
+            key: key.to_string(),
+            down: false,
+        })
+    }
+
+    /// Moves the mouse pointer to (`x`, `y`) on the stage.
+    pub fn mouse_move(&mut self, x: f64, y: f64) -> Result<(), &'static str> {
+        self.post_input(Input::MouseMove { x, y })
+    }
+
+    /// Presses the mouse button where the pointer is, which starts the "when
+    /// this sprite clicked" scripts of the sprite under it (or the "when stage
+    /// clicked" ones if there isn't one).
+    pub fn mouse_down(&mut self) -> Result<(), &'static str> {
+        self.post_input(Input::MouseButton { down: true })
+    }
+
+    pub fn mouse_up(&mut self) -> Result<(), &'static str> {
+        self.post_input(Input::MouseButton { down: false })
+    }
+
+    /// Answers the question being shown, which resumes the thread that
+    /// asked it and shows the next one.
+    pub fn answer(&mut self, answer: &str) -> Result<(), &'static str> {
+        self.post_input(Input::Answer(answer.to_string()))
+    }
+
+    /// Queues up a question from "ask and wait" on `thread`, showing it if
+    /// nothing else is being asked.
+    fn ask(&mut self, thread: u32, target: u32, question: &ScratchValue) {
+        // Sprites that are showing ask in their speech bubble
+        let in_bubble = self
+            .target(target)
+            .is_some_and(|target| !target.is_stage && target.looks.visible);
+        self.questions.queue.push_back(Question {
+            thread,
+            target,
+            text: question.as_str().into_owned(),
+            in_bubble,
+        });
+        if self.questions.queue.len() == 1 {
+            self.show_question();
+        }
+    }
+
+    /// Shows the question at the front of the queue, if there is one.
+    fn show_question(&mut self) {
+        self.questions.changed = true;
+        let question = match self.questions.queue.front() {
+            Some(question) if question.in_bubble => question.clone(),
+            _ => return,
+        };
+        if let Some(target) = self.target_mut(question.target) {
+            let text = ScratchValue::String(question.text);
+            target.bubble.update(BubbleKind::Say, &text);
+        }
+    }
+
+    /// Takes the question being shown off the queue, hiding the bubble it was
+    /// in, and shows the next one.
+    fn finish_question(&mut self) -> Option<Question> {
+        let question = self.questions.queue.pop_front()?;
+        if question.in_bubble {
+            if let Some(target) = self.target_mut(question.target) {
+                target.bubble.update(BubbleKind::Say, &ScratchValue::EMPTY);
+            }
+        }
+        self.show_question();
+        Some(question)
+    }
+
+    /// Throws away the questions of threads that were stopped.
+    fn forget_stopped_questions(&mut self) {
+        fn asked(threads: &[Thread], question: &Question) -> bool {
+            threads
+                .iter()
+                .any(|thread| thread.id == question.thread && thread.state != ThreadState::Finished)
+        }
+        // The one being shown has to be taken down properly
+        while self
+            .questions
+            .queue
+            .front()
+            .is_some_and(|question| !asked(&self.threads, question))
+        {
+            self.finish_question();
+        }
+        let threads = &self.threads;
+        self.questions
+            .queue
+            .retain(|question| asked(threads, question));
+    }
+
+    /// The ID of the sprite drawn at (`x`, `y`), going by the shown sprites'
+    /// bounds and layers, or of the stage if there isn't one.
+    pub fn target_at(&self, x: f64, y: f64) -> Option<u32> {
+        self.targets
+            .iter()
+            .filter(|target| {
+                !target.is_stage && target.looks.visible && target.motion.contains(x, y)
+            })
+            .max_by_key(|target| target.looks.layer)
+            .or_else(|| self.targets.iter().find(|target| target.is_stage))
+            .map(|target| target.id)
+    }
+
+    /// Takes in something the host did between ticks, recording it.
+    fn post_input(&mut self, input: Input) -> Result<(), &'static str> {
+        if self.replaying() {
+            return Err("inputs come from the recording while replaying");
+        }
+        self.apply_input(input.clone())?;
+        if let Some(tape) = &self.tape {
+            tape.borrow_mut().record(input);
+        }
+        Ok(())
+    }
+
+    /// Acts on an input from the host, whether it's happening now or being
+    /// replayed.
+    fn apply_input(&mut self, input: Input) -> Result<(), &'static str> {
+        match input {
+            Input::HostPromise { thread, value } => self.wake_from_host_promise(thread, value)?,
+            Input::Key { key, down } => {
+                if let Some(key) = self.io.keyboard.post(&key, down) {
+                    self.start_hats(
+                        |hat| matches!(hat, Hat::KeyPressed(hat_key) if key_matches(hat_key, &key)),
+                        None,
+                    );
+                }
+            }
+            Input::MouseMove { x, y } => self.io.mouse.move_to(x, y),
+            Input::Answer(answer) => {
+                let question = self.finish_question().ok_or("no question is being asked")?;
+                self.io.answer = answer;
+                if let Some(thread) = self
+                    .threads
+                    .iter_mut()
+                    .find(|thread| thread.id == question.thread)
+                {
+                    thread.state = ThreadState::Running;
+                }
+            }
+            Input::MouseButton { down } => {
+                // Like scratch-vm, only a change of state counts
+                if down == self.io.mouse.down {
+                    return Ok(());
+                }
+                self.io.mouse.down = down;
+                if down {
+                    let (x, y) = (self.io.mouse.x, self.io.mouse.y);
+                    if let Some(target) = self.target_at(x, y) {
+                        self.start_hats(|hat| *hat == Hat::Clicked, Some(target));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn thread(&self, id: u32) -> Option<&Thread> {
+        self.threads.iter().find(|thread| thread.id == id)
+    }
+
+    /// Resumes a thread parked on a host promise, handling the resolved value
+    /// like the awaited block would have if it didn't return a promise: it's
+    /// dropped for commands, pushed for reporters and cast for Booleans. A
+    /// missing value is treated as an empty string.
+    pub fn resolve_host_promise(
+        &mut self,
+        id: u32,
+        value: Option<ScratchValue>,
+    ) -> Result<(), &'static str> {
+        if self.replaying() {
+            return Err("host promises come from the recording while replaying");
+        }
+        self.wake_from_host_promise(id, value.clone())?;
+        if let Some(tape) = &self.tape {
+            tape.borrow_mut()
+                .record(Input::HostPromise { thread: id, value });
+        }
+        Ok(())
+    }
+
+    fn wake_from_host_promise(
+        &mut self,
+        id: u32,
+        value: Option<ScratchValue>,
+    ) -> Result<(), &'static str> {
+        let thread = self
+            .threads
+            .iter_mut()
+            .find(|thread| thread.id == id)
+            .ok_or("no thread with that id")?;
+        let block_type = match thread.state {
+            ThreadState::WaitingOnHostPromise { block_type } => block_type,
+            _ => return Err("thread isn't waiting on a host promise"),
+        };
+        let value = value.unwrap_or(ScratchValue::EMPTY);
+        match block_type {
+            BlockType::Command => {}
+            BlockType::Reporter => thread.stack.push(value),
+            BlockType::Boolean => thread.stack.push(ScratchValue::Boolean(value.into())),
+        }
+        thread.state = ThreadState::Running;
+        Ok(())
+    }
+
+    /// Carries on after the debugger paused, letting the paused thread run
+    /// past the instruction it stopped at.
+    pub fn resume(&mut self) {
+        if let Some(id) = self.paused_thread.take() {
+            if let Some(thread) = self.threads.iter_mut().find(|thread| thread.id == id) {
+                thread.state = ThreadState::Running;
+                self.resuming_thread = Some(id);
+            }
+        }
+    }
+
+    /// Stops every thread and deletes every clone, like the stop sign.
+    pub fn stop_all(&mut self) {
+        for thread in self.threads.iter_mut() {
+            thread.state = ThreadState::Finished;
+        }
+        self.targets.retain(|target| !target.is_clone());
+        self.sound_player.stop(None);
+        // Bubbles and questions go away too, like in scratch-vm
+        for target in self.targets.iter_mut() {
+            if !target.bubble.text.is_empty() {
+                target.bubble.update(BubbleKind::Say, &ScratchValue::EMPTY);
+            }
+        }
+        if !self.questions.queue.is_empty() {
+            self.questions.queue.clear();
+            self.questions.changed = true;
+        }
+    }
+
+    /// Plays the sound `sound` names (see `SoundState::sound_index`) for
+    /// `target`, returning which sound it is and when it'll be done, or
+    /// `None` if there's no such sound.
+    fn play_sound(&mut self, target: u32, sound: &ScratchValue, now: f64) -> Option<(usize, f64)> {
+        let target = self.targets.iter().find(|other| other.id == target)?;
+        let index = target.sound.sound_index(sound)?;
+        // Starting a sound over ends the waits for it, like in scratch-vm
+        end_sound_waits(&mut self.threads, Some((target.id, index)));
+        let duration = self.sound_player.play(target.id, index, &target.sound, now);
+        Some((index, now + duration))
+    }
+
+    /// Stops every sound, ending every "play sound until done".
+    pub fn stop_all_sounds(&mut self) {
+        self.sound_player.stop(None);
+        end_sound_waits(&mut self.threads, None);
+    }
+
+    /// Tells the sound player about the targets whose volume or effects
+    /// changed.
+    fn update_sounds(&mut self) {
+        for target in self.targets.iter_mut() {
+            if std::mem::take(&mut target.sound.changed) {
+                self.sound_player.update(target.id, &target.sound);
+            }
+        }
+    }
+
+    /// Stops every thread of `target` except `except`.
+    pub fn stop_other_threads(&mut self, target: u32, except: u32) {
+        for thread in self.threads.iter_mut() {
+            if thread.target == target && thread.id != except {
+                thread.state = ThreadState::Finished;
+            }
+        }
+    }
+
+    /// Saves the stores, threads, targets, monitors and random number
+    /// generator, so that running can carry on from here later (e.g. after a
+    /// `restore` into a freshly loaded runtime).
+    pub fn snapshot(&self) -> Snapshot {
+        let now = self.clock.now();
+        let threads = self
+            .threads
+            .iter()
+            .map(|thread| {
+                let mut thread = thread.clone();
+                match &mut thread.state {
+                    ThreadState::WaitingOnTimer { until } => *until -= now,
+                    ThreadState::Speaking { until, .. } => *until -= now,
+                    ThreadState::WaitingOnSound { until, .. } => *until -= now,
+                    ThreadState::Gliding(glide) => glide.start -= now,
+                    _ => {}
+                }
+                thread
+            })
+            .collect();
+        Snapshot {
+            program_hash: program_hash(&self.instructions, &self.constants),
+            variables: self.variables.clone(),
+            lists: self.lists.clone(),
+            threads,
+            targets: self.targets.clone(),
+            monitors: self.monitors.clone(),
+            io: self.io.clone(),
+            questions: self.questions.clone(),
+            pen_layer: self.pen_layer.clone(),
+            random_state: self.random.state(),
+            next_thread_id: self.next_thread_id,
+            next_target_id: self.next_target_id,
+            paused_thread: self.paused_thread,
+        }
+    }
+
+    /// Puts back everything saved by `snapshot`, replacing whatever the
+    /// runtime was doing (and ending any recording or replay). Fails if the
+    /// snapshot was taken of a different program.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), &'static str> {
+        let now = self.clock.now();
+        self.restore_at(snapshot, now)?;
+        self.tape = None;
+        Ok(())
+    }
+
+    /// Restores `snapshot` with its timers carrying on from `now`.
+    fn restore_at(&mut self, snapshot: Snapshot, now: f64) -> Result<(), &'static str> {
+        if snapshot.program_hash != program_hash(&self.instructions, &self.constants) {
+            return Err("snapshot is of a different program");
+        }
+        self.variables = snapshot.variables;
+        self.lists = snapshot.lists;
+        self.list_size = lists_size(&self.lists);
+        self.threads = snapshot.threads;
+        for thread in self.threads.iter_mut() {
+            match &mut thread.state {
+                ThreadState::WaitingOnTimer { until } => *until += now,
+                ThreadState::Speaking { until, .. } => *until += now,
+                ThreadState::WaitingOnSound { until, .. } => *until += now,
+                ThreadState::Gliding(glide) => glide.start += now,
+                _ => {}
+            }
+        }
+        self.targets = snapshot.targets;
+        for target in self.targets.iter_mut() {
+            target.motion.transform_changed = true;
+            target.looks.render_changed = true;
+            // Targets without a bubble have nothing to draw, like new ones
+            target.bubble.changed = !target.bubble.text.is_empty();
+        }
+        // The sounds that were playing aren't started again, but the threads
+        // waiting for them still wait as long as they would have
+        self.sound_player.stop(None);
+        self.monitors = snapshot.monitors;
+        // The host should redraw all of them
+        self.monitors.changed = true;
+        self.io = snapshot.io;
+        // The host only needs telling if there's a different question
+        let changed =
+            self.questions.changed || self.questions.prompt() != snapshot.questions.prompt();
+        self.questions = snapshot.questions;
+        self.questions.changed = changed;
+        let changed = self.pen_layer.changed || self.pen_layer != snapshot.pen_layer;
+        self.pen_layer = snapshot.pen_layer;
+        self.pen_layer.changed = changed;
+        if let Some(state) = snapshot.random_state {
+            self.random.set_state(state);
+        }
+        self.next_thread_id = snapshot.next_thread_id;
+        self.next_target_id = snapshot.next_target_id;
+        self.paused_thread = snapshot.paused_thread;
+        self.stepping_thread = None;
+        self.resuming_thread = None;
+        self.cut_off_thread = None;
+        self.redraw_requested = true;
+        self.budget_exhausted = false;
+        Ok(())
+    }
+
+    /// Hashes the stores, the threads and the sprites, which is what replays compare to
+    /// find where they diverged.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        for value in self.variables.iter() {
+            hash.write_value(value);
+        }
+        for list in self.lists.iter() {
+            hash.write(&(list.len() as u64).to_le_bytes());
+            for value in list {
+                hash.write_value(value);
+            }
+        }
+        for thread in self.threads.iter() {
+            hash.write(&thread.id.to_le_bytes());
+            hash.write(&(thread.program_counter as u64).to_le_bytes());
+            hash.write(&(thread.stack.len() as u64).to_le_bytes());
+            for value in thread.stack.iter() {
+                hash.write_value(value);
+            }
+            let until = match thread.state {
+                ThreadState::WaitingOnTimer { until }
+                | ThreadState::Speaking { until, .. }
+                | ThreadState::WaitingOnSound { until, .. } => until,
+                ThreadState::Gliding(glide) => glide.start,
+                _ => 0.0,
+            };
+            hash.write(&[thread_state_tag(thread.state)]);
+            hash.write(&until.to_bits().to_le_bytes());
+        }
+        for target in self.targets.iter() {
+            let motion = &target.motion;
+            hash.write(&target.id.to_le_bytes());
+            for value in [motion.x, motion.y, motion.direction] {
+                hash.write(&value.to_bits().to_le_bytes());
+            }
+            hash.write(&[motion.rotation_style as u8]);
+            let looks = &target.looks;
+            hash.write(&(looks.costume as u64).to_le_bytes());
+            for value in [looks.size].iter().chain(looks.effects.iter()) {
+                hash.write(&value.to_bits().to_le_bytes());
+            }
+            hash.write(&[looks.visible as u8]);
+            hash.write(&looks.layer.to_le_bytes());
+            let sound = &target.sound;
+            for value in [sound.volume].iter().chain(sound.effects.iter()) {
+                hash.write(&value.to_bits().to_le_bytes());
+            }
+            hash.write_value(&ScratchValue::String(target.bubble.text.clone()));
+        }
+        hash.finish()
+    }
+
+    /// Starts logging every nondeterministic input (see replay.rs) from the
+    /// current state, throwing away any recording or replay in progress.
+    pub fn start_recording(&mut self) {
+        let start_time = self.clock.now();
+        let start = self.snapshot();
+        self.tape = Some(Rc::new(RefCell::new(Tape::Recording(Box::new(
+            Recording {
+                start,
+                start_time,
+                inputs: Vec::new(),
+            },
+        )))));
+    }
+
+    /// Stops recording and returns what was recorded, if anything.
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let recording = self
+            .tape
+            .as_ref()
+            .is_some_and(|tape| matches!(*tape.borrow(), Tape::Recording(_)));
+        if !recording {
+            return None;
+        }
+        // Nothing else holds on to the tape between ticks
+        match Rc::try_unwrap(self.tape.take()?).ok()?.into_inner() {
+            Tape::Recording(recording) => Some(*recording),
+            Tape::Replaying { .. } => None,
+        }
+    }
+
+    /// Goes back to where `recording` started and replays it on the following
+    /// ticks. The host isn't called while replaying. Once the recording runs
+    /// out, a tick that needs more inputs fails like a divergence would.
+    pub fn start_replay(&mut self, recording: Recording) -> Result<(), &'static str> {
+        self.restore_at(recording.start, recording.start_time)?;
+        self.tape = Some(Rc::new(RefCell::new(Tape::Replaying {
+            inputs: recording.inputs.into(),
+            position: 0,
+            divergence: None,
+        })));
+        Ok(())
+    }
+
+    /// Stops replaying, so the clock, random number generator and host are
+    /// used again.
+    pub fn stop_replay(&mut self) {
+        if self.replaying() {
+            self.tape = None;
+        }
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.tape
+            .as_ref()
+            .is_some_and(|tape| tape.borrow().is_replaying())
+    }
+
+    /// The number of inputs left to replay, if replaying.
+    pub fn replay_remaining(&self) -> Option<usize> {
+        self.tape
+            .as_ref()
+            .and_then(|tape| tape.borrow().remaining())
+    }
+
+    /// Records the state hash when a thread yields, or checks it against the
+    /// recording.
+    fn checkpoint(&self) {
+        if let Some(tape) = &self.tape {
+            let hash = self.state_hash();
+            tape.borrow_mut().checkpoint(hash);
+        }
+    }
+
+    /// Runs one tick: every thread that isn't parked runs until it yields,
+    /// parks or finishes. Parked threads are skipped until the clock or the
+    /// host wakes them up. If the budget runs out, the tick ends early and
+    /// the next one starts from the thread it cut off.
+    pub fn step(&mut self) -> Result<(), String> {
+        if self.paused_thread.is_some() {
+            return Ok(());
+        }
+        self.redraw_requested = false;
+        self.budget_exhausted = false;
+        // Promises resolved and keys pressed etc. between the recorded ticks
+        while let Some(input) = self
+            .tape
+            .as_ref()
+            .and_then(|tape| tape.borrow_mut().replay_between_ticks())
+        {
+            self.apply_input(input)?;
+        }
+        let now = TapedClock {
+            inner: &*self.clock,
+            tape: self.tape.as_deref(),
+        }
+        .now();
+        // The budget reads the clock apart from the scripts, since how often
+        // it does isn't up to them
+        let (clock, tape) = (Rc::clone(&self.clock), self.tape.clone());
+        let deadline = self.time_budget.map(|time_budget| {
+            TapedDeadline::new(&*clock, clock.now() + time_budget, tape.as_deref())
+        });
+        let mut budget = Budget::new(
+            self.instruction_budget,
+            deadline
+                .as_ref()
+                .map(|deadline| (deadline.deadline, deadline as &dyn Clock)),
+        );
+        let mut result = self.step_threads(now, &mut budget);
+        // Without a budget, going round again could go on forever
+        if self.turbo_mode && (self.instruction_budget.is_some() || self.time_budget.is_some()) {
+            while result.is_ok()
+                && !self.budget_exhausted
+                && self.paused_thread.is_none()
+                && self.threads.iter().any(|thread| {
+                    matches!(
+                        thread.state,
+                        ThreadState::Running
+                            | ThreadState::Yielded
+                            | ThreadState::WaitingOnCondition
+                    )
+                })
+            {
+                result = self.step_threads(now, &mut budget);
+            }
+        }
+        self.forget_stopped_questions();
+        self.update_sounds();
+        self.threads
+            .retain(|thread| thread.state != ThreadState::Finished);
+        if self
+            .stepping_thread
+            .is_some_and(|id| self.thread(id).is_none())
+        {
+            self.stepping_thread = None;
+        }
+        let divergence = self
+            .tape
+            .as_ref()
+            .and_then(|tape| tape.borrow().divergence().map(String::from));
+        if let Some(divergence) = divergence {
+            // Leave the runtime where it diverged so it can be looked at
+            self.tape = None;
+            return Err(divergence);
+        }
+        result
+    }
+
+    fn step_threads(&mut self, now: f64, budget: &mut Budget) -> Result<(), String> {
+        // Threads started during the tick wait until the next one
+        let thread_count = self.threads.len();
+        let first = self
+            .cut_off_thread
+            .take()
+            .and_then(|id| self.threads.iter().position(|thread| thread.id == id))
+            .unwrap_or(0);
+        for index in (first..thread_count).chain(0..first) {
+            let target = self.threads[index].target;
+            match self.threads[index].state {
+                ThreadState::Gliding(glide) => {
+                    let arrived = match self.targets.iter_mut().find(|other| other.id == target) {
+                        Some(target) => {
+                            let from = (target.motion.x, target.motion.y);
+                            let arrived = glide.update(&mut target.motion, now);
+                            if target.pen.down {
+                                let to = (target.motion.x, target.motion.y);
+                                self.pen_layer.draw_line(&target.pen, from, to);
+                            }
+                            arrived
+                        }
+                        None => true,
+                    };
+                    if !arrived {
+                        continue;
+                    }
+                    // Carry on with the rest of the script this tick
+                    self.threads[index].state = ThreadState::Running;
+                }
+                ThreadState::Speaking { until, usage } => {
+                    if until > now {
+                        continue;
+                    }
+                    if let Some(target) = self.target_mut(target) {
+                        target.bubble.clear_if_unchanged(usage);
+                    }
+                    self.threads[index].state = ThreadState::Running;
+                }
+                _ => {}
+            }
+            if !self.threads[index].wake(now) {
+                continue;
+            }
+            self.threads[index].state = ThreadState::Running;
+            while self.threads[index].state == ThreadState::Running {
+                let thread = &mut self.threads[index];
+                let current_target = self
+                    .targets
+                    .iter()
+                    .position(|target| target.id == thread.target);
+                if let Some(debugger) = self.debugger.as_mut() {
+                    if debugger.single_step && self.stepping_thread.is_none() {
+                        self.stepping_thread = Some(thread.id);
+                    }
+                    debugger.ignore_single_step = self.stepping_thread != Some(thread.id);
+                    if self.resuming_thread == Some(thread.id) {
+                        self.resuming_thread = None;
+                        debugger.resume();
+                    }
+                }
+                let return_reason = run_instructions(
+                    &mut thread.program_counter,
+                    &mut thread.stack,
+                    &self.instructions,
+                    &self.constants,
+                    &mut self.variables,
+                    &mut self.lists,
+                    &mut TapedHost {
+                        inner: &mut ExtendedHost {
+                            host: &mut *self.host,
+                            extensions: &mut self.extensions,
+                        },
+                        tape: self.tape.as_deref(),
+                    },
+                    budget,
+                    &mut Context {
+                        list_limits: &self.list_limits,
+                        list_size: &mut self.list_size,
+                        monitors: &mut self.monitors,
+                        targets: &mut self.targets,
+                        current_target,
+                        io: &self.io,
+                        collision: &*self.collision,
+                        pen_layer: &mut self.pen_layer,
+                        clock: &TapedClock {
+                            inner: &*self.clock,
+                            tape: self.tape.as_deref(),
+                        },
+                        random: &mut TapedRandom {
+                            inner: &mut *self.random,
+                            tape: self.tape.as_deref(),
+                        },
+                    },
+                    self.debugger.as_mut(),
+                );
+                let return_reason = match return_reason {
+                    Ok(return_reason) => return_reason,
+                    Err(err) => {
+                        thread.state = ThreadState::Finished;
+                        return Err(err);
+                    }
+                };
+                let state = match return_reason.map(ReturnReason::try_from) {
+                    Some(Ok(ReturnReason::LoopYield)) => ThreadState::Yielded,
+                    Some(Ok(ReturnReason::Repaint)) => {
+                        self.redraw_requested = true;
+                        ThreadState::Yielded
+                    }
+                    Some(Ok(ReturnReason::DebugPause)) => {
+                        self.paused_thread = Some(thread.id);
+                        self.threads[index].state = ThreadState::Paused;
+                        return Ok(());
+                    }
+                    Some(Ok(ReturnReason::BudgetExhausted)) => {
+                        // The program counter is already where it should
+                        // resume from
+                        self.threads[index].state = ThreadState::Yielded;
+                        self.cut_off_thread = Some(self.threads[index].id);
+                        self.budget_exhausted = true;
+                        self.checkpoint();
+                        return Ok(());
+                    }
+                    Some(Ok(ReturnReason::HostPromise)) => {
+                        TapedHost {
+                            inner: &mut ExtendedHost {
+                                host: &mut *self.host,
+                                extensions: &mut self.extensions,
+                            },
+                            tape: self.tape.as_deref(),
+                        }
+                        .thread_parked(thread.id);
+                        ThreadState::WaitingOnHostPromise {
+                            block_type: awaited_block_type(
+                                &self.instructions,
+                                &self.extensions,
+                                thread.program_counter,
+                            ),
+                        }
+                    }
+                    Some(Ok(ReturnReason::WaitTimer)) => {
+                        let seconds: f64 = thread
+                            .stack
+                            .pop()
+                            .ok_or("nothing on the stack to pop")?
+                            .into();
+                        ThreadState::WaitingOnTimer {
+                            until: now + seconds * 1000.0,
+                        }
+                    }
+                    Some(Ok(ReturnReason::Glide)) => {
+                        let mut pop = || -> Result<f64, &'static str> {
+                            Ok(thread
+                                .stack
+                                .pop()
+                                .ok_or("nothing on the stack to pop")?
+                                .into())
+                        };
+                        let (y, x, seconds) = (pop()?, pop()?, pop()?);
+                        let targets = &mut self.targets;
+                        match current_target.map(|index| &mut targets[index]) {
+                            Some(target) if !target.is_stage => {
+                                let from = (target.motion.x, target.motion.y);
+                                if seconds > 0.0 {
+                                    // Like scratch-vm, the sprite doesn't
+                                    // move until the next tick
+                                    ThreadState::Gliding(Glide {
+                                        start: now,
+                                        duration: seconds * 1000.0,
+                                        from,
+                                        to: (x, y),
+                                    })
+                                } else {
+                                    target.motion.set_xy(x, y);
+                                    if target.pen.down {
+                                        let to = (target.motion.x, target.motion.y);
+                                        self.pen_layer.draw_line(&target.pen, from, to);
+                                    }
+                                    ThreadState::Running
+                                }
+                            }
+                            _ => ThreadState::Running,
+                        }
+                    }
+                    Some(Ok(ReturnReason::SpeechTimer)) => {
+                        let seconds: f64 = thread
+                            .stack
+                            .pop()
+                            .ok_or("nothing on the stack to pop")?
+                            .into();
+                        let until = now + seconds * 1000.0;
+                        match current_target.map(|index| &self.targets[index]) {
+                            Some(target) if !target.is_stage => ThreadState::Speaking {
+                                until,
+                                usage: target.bubble.usage,
+                            },
+                            // The stage has no bubble, so it only waits
+                            _ => ThreadState::WaitingOnTimer { until },
+                        }
+                    }
+                    Some(Ok(ReturnReason::Ask)) => {
+                        let question = thread.stack.pop().ok_or("nothing on the stack to pop")?;
+                        let (thread, target) = (thread.id, thread.target);
+                        self.ask(thread, target, &question);
+                        ThreadState::WaitingOnAnswer
+                    }
+                    Some(Ok(
+                        reason @ (ReturnReason::StartSound | ReturnReason::PlaySoundUntilDone),
+                    )) => {
+                        let sound = thread.stack.pop().ok_or("nothing on the stack to pop")?;
+                        let target = thread.target;
+                        match self.play_sound(target, &sound, now) {
+                            Some((sound, until))
+                                if matches!(reason, ReturnReason::PlaySoundUntilDone) =>
+                            {
+                                ThreadState::WaitingOnSound { until, sound }
+                            }
+                            // Sounds that don't exist are done right away
+                            _ => ThreadState::Running,
+                        }
+                    }
+                    Some(Ok(ReturnReason::StopAllSounds)) => {
+                        self.stop_all_sounds();
+                        ThreadState::Running
+                    }
+                    Some(Ok(ReturnReason::WaitCondition)) => ThreadState::WaitingOnCondition,
+                    Some(Ok(ReturnReason::StopAll)) => {
+                        self.stop_all();
+                        return Ok(());
+                    }
+                    Some(Ok(ReturnReason::StopOtherScripts)) => {
+                        let (target, id) = (thread.target, thread.id);
+                        self.stop_other_threads(target, id);
+                        // Carry on running this thread
+                        ThreadState::Running
+                    }
+                    // Anything else (including running off the end) ends the
+                    // thread
+                    _ => ThreadState::Finished,
+                };
+                self.threads[index].state = state;
+                if state == ThreadState::Yielded {
+                    self.checkpoint();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wakes the threads waiting for the sound `(target, sound)` to finish, or
+/// for any sound if `None`.
+fn end_sound_waits(threads: &mut [Thread], which: Option<(u32, usize)>) {
+    for thread in threads.iter_mut() {
+        if let ThreadState::WaitingOnSound { sound, .. } = thread.state {
+            if which.is_none_or(|which| which == (thread.target, sound)) {
+                thread.state = ThreadState::Running;
+            }
+        }
+    }
+}
+
+/// What kind of block a thread that parked on a host promise just before
+/// `program_counter` is waiting on: an awaited `CALL_HOST` (whose `EXTRA_ARG`
+/// is right before it) or an extension block.
+fn awaited_block_type(
+    instructions: &[Instruction],
+    extensions: &Extensions,
+    program_counter: usize,
+) -> BlockType {
+    let instruction = program_counter
+        .checked_sub(1)
+        .and_then(|index| instructions.get(index));
+    match instruction {
+        Some(Instruction {
+            name: InstructionType::ExtraArg,
+            argument,
+            ..
+        }) if argument & HOST_CALL_REPORTER > 0 => BlockType::Reporter,
+        Some(Instruction {
+            name: InstructionType::Extension,
+            argument,
+            ..
+        }) => extensions
+            .signatures()
+            .get(*argument as usize)
+            .map_or(BlockType::Command, |signature| signature.block_type),
+        _ => BlockType::Command,
+    }
+}
+
+/// Tells the thread states apart for `Runtime::state_hash`.
+fn thread_state_tag(state: ThreadState) -> u8 {
+    match state {
+        ThreadState::Running => 0,
+        ThreadState::Yielded => 1,
+        ThreadState::WaitingOnTimer { .. } => 2,
+        ThreadState::WaitingOnCondition => 3,
+        ThreadState::WaitingOnHostPromise { .. } => 4,
+        ThreadState::Paused => 5,
+        ThreadState::Finished => 6,
+        ThreadState::Gliding(_) => 7,
+        ThreadState::Speaking { .. } => 8,
+        ThreadState::WaitingOnAnswer => 9,
+        ThreadState::WaitingOnSound { .. } => 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::clock::ManualClock;
+    use crate::extension::BlockType;
+    use crate::host::HostCallResult;
+    use crate::instruction::decode;
+    use crate::motion::Bounds;
+    use crate::sound::Sound;
+    use crate::transmute_instructions;
+
+    use super::*;
+
+    #[test]
+    fn test_runtime_wait() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000040u64, // WAIT
+                0x000000050000003cu64, // LOAD_CONST_INT 5
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let clock = ManualClock::new(0.0);
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        runtime.set_clock(Box::new(clock.clone()));
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        assert_eq!(
+            runtime.thread(id).unwrap().state,
+            ThreadState::WaitingOnTimer { until: 1000.0 }
+        );
+        clock.advance(500.0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(0.0)]);
+        clock.advance(500.0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(5.0)]);
+        assert!(runtime.thread(id).is_none());
+    }
+
+    #[test]
+    fn test_runtime_wait_until() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000003u64, // LOAD 0
+                0xfffffffe00000041u64, // WAIT_UNTIL -2
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000100000004u64, // STORE 1
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Boolean(false), ScratchValue::Number(0.0)],
+            vec![],
+        );
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        let thread = runtime.thread(id).unwrap();
+        assert_eq!(thread.state, ThreadState::WaitingOnCondition);
+        assert_eq!(thread.program_counter, 1);
+        runtime.variables[0] = ScratchValue::Boolean(true);
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables[1], ScratchValue::Number(1.0));
+        assert!(runtime.thread(id).is_none());
+    }
+
+    #[test]
+    fn test_runtime_host_promise() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000000000003fu64, // CALL_HOST 0
+                0x0003000000000001u64, // EXTRA_ARG REPORTER | AWAIT
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::EMPTY],
+            vec![],
+        );
+        runtime.set_host(Box::new(|_, _| -> Result<HostCallResult, &'static str> {
+            Ok(HostCallResult::Pending)
+        }));
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        assert_eq!(
+            runtime.thread(id).unwrap().state,
+            ThreadState::WaitingOnHostPromise {
+                block_type: BlockType::Reporter
+            }
+        );
+        runtime
+            .resolve_host_promise(id, Some(ScratchValue::Number(42.0)))
+            .unwrap();
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(42.0)]);
+    }
+
+    #[test]
+    fn test_runtime_extension_promise() {
+        /// Waits forever, remembering which thread is waiting.
+        struct Wait(Rc<Cell<Option<u32>>>);
+        impl ExtensionHandler for Wait {
+            fn call(&mut self, _: Vec<ScratchValue>) -> Result<HostCallResult, &'static str> {
+                Ok(HostCallResult::Pending)
+            }
+            fn thread_parked(&mut self, thread: u32) {
+                self.0.set(Some(thread));
+            }
+        }
+
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000008000u64, // EXTENSION 0
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::EMPTY],
+            vec![],
+        );
+        let parked = Rc::new(Cell::new(None));
+        let signature = BlockSignature {
+            extension: "test".into(),
+            block: "wait".into(),
+            arity: 0,
+            block_type: BlockType::Reporter,
+        };
+        let opcode = runtime.register_extension_block(signature, Box::new(Wait(parked.clone())));
+        assert_eq!(opcode, Ok(0x8000));
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        assert_eq!(
+            runtime.thread(id).unwrap().state,
+            ThreadState::WaitingOnHostPromise {
+                block_type: BlockType::Reporter
+            }
+        );
+        assert_eq!(parked.get(), Some(id));
+        runtime
+            .resolve_host_promise(id, Some(ScratchValue::Number(42.0)))
+            .unwrap();
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(42.0)]);
+    }
+
+    #[test]
+    fn test_runtime_extension_promise_block_types() {
+        // Decoded, since that's what gives extension blocks their index
+        let instructions = decode(&[
+            0x0000000000008000u64, // EXTENSION 0 (command)
+            0x0000000000008001u64, // EXTENSION 1 (Boolean)
+            0x0000000000000004u64, // STORE 0
+        ])
+        .unwrap();
+        let mut runtime = Runtime::new(instructions, vec![], vec![ScratchValue::EMPTY], vec![]);
+        for (block, block_type) in [("wait", BlockType::Command), ("ready", BlockType::Boolean)] {
+            let signature = BlockSignature {
+                extension: "test".into(),
+                block: block.into(),
+                arity: 0,
+                block_type,
+            };
+            let handler = |_: Vec<ScratchValue>| Ok(HostCallResult::Pending);
+            runtime
+                .register_extension_block(signature, Box::new(handler))
+                .unwrap();
+        }
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        // The command's value is dropped, so the stack stays as it was
+        runtime
+            .resolve_host_promise(id, Some(ScratchValue::Number(42.0)))
+            .unwrap();
+        assert!(runtime.thread(id).unwrap().stack.is_empty());
+        runtime.step().unwrap();
+        assert_eq!(
+            runtime.thread(id).unwrap().state,
+            ThreadState::WaitingOnHostPromise {
+                block_type: BlockType::Boolean
+            }
+        );
+        runtime
+            .resolve_host_promise(id, Some(ScratchValue::String("yes".into())))
+            .unwrap();
+        runtime.step().unwrap();
+        assert!(runtime.thread(id).is_none());
+        assert_eq!(runtime.variables, [ScratchValue::Boolean(true)]);
+    }
+
+    #[test]
+    fn test_runtime_stop_other_scripts() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000042u64, // YIELD
+                0xfffffffe00000005u64, // JUMP -2
+                0x0000000000000045u64, // STOP_OTHER_SCRIPTS_IN_SPRITE
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        let other_sprite = runtime.add_target("Sprite2".into(), false);
+        let loop_thread = runtime.start_thread(sprite, 1);
+        let other_loop_thread = runtime.start_thread(other_sprite, 1);
+        let stopping_thread = runtime.start_thread(sprite, 3);
+        runtime.step().unwrap();
+        assert!(runtime.thread(loop_thread).is_none());
+        assert!(runtime.thread(other_loop_thread).is_some());
+        assert!(runtime.thread(stopping_thread).is_none());
+        // The stopping thread should have carried on after stopping the others
+        assert_eq!(runtime.variables, [ScratchValue::Number(1.0)]);
+    }
+
+    #[test]
+    fn test_runtime_stop_all() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000042u64, // YIELD
+                0xfffffffe00000005u64, // JUMP -2
+                0x0000000000000043u64, // STOP_ALL
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        let stage = runtime.add_target("Stage".into(), true);
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        let clone = runtime.clone_target(sprite).unwrap();
+        runtime.start_thread(clone, 1);
+        runtime.start_thread(stage, 3);
+        // Shouldn't get to run, since it's after the thread stopping everything
+        runtime.start_thread(sprite, 4);
+        runtime.step().unwrap();
+        assert!(runtime.threads.is_empty());
+        assert!(runtime.target(clone).is_none());
+        assert!(runtime.target(sprite).is_some());
+        assert_eq!(runtime.variables, [ScratchValue::Number(0.0)]);
+    }
+
+    #[test]
+    fn test_runtime_turbo_mode() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x000000020000002du64, // RETURN 2 (repaint)
+                0xfffffffe00000005u64, // JUMP -2
+            ])
+        };
+        let mut runtime = Runtime::new(instructions.to_vec(), vec![], vec![], vec![]);
+        let id = runtime.start_thread(0, 0);
+        runtime.instruction_budget = Some(100);
+        runtime.step().unwrap();
+        assert!(runtime.redraw_requested);
+        assert!(!runtime.budget_exhausted);
+        // In turbo mode, the repaint shouldn't end the tick, so the thread
+        // keeps going round until the budget runs out
+        runtime.turbo_mode = true;
+        runtime.step().unwrap();
+        assert!(runtime.budget_exhausted);
+        let thread = runtime.thread(id).unwrap();
+        assert_eq!(thread.state, ThreadState::Yielded);
+        assert_eq!(thread.program_counter, 2);
+    }
+
+    #[test]
+    fn test_runtime_turbo_mode_without_budget() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000000000004cu64, // MOTION_CHANGE_X
+                0x000000020000002du64, // RETURN 2 (repaint)
+                0xfffffffc00000005u64, // JUMP -4
+            ])
+        };
+        let mut runtime = Runtime::new(instructions.to_vec(), vec![], vec![], vec![]);
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        let id = runtime.start_thread(sprite, 1);
+        runtime.turbo_mode = true;
+        // Each thread gets one turn, rather than the tick never ending
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        assert!(runtime.redraw_requested);
+        assert!(!runtime.budget_exhausted);
+        assert_eq!(runtime.thread(id).unwrap().state, ThreadState::Yielded);
+        assert_eq!(runtime.target(sprite).unwrap().motion.x, 2.0);
+    }
+
+    #[test]
+    fn test_runtime_budget_resumes_cut_off_thread() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000000u64, // NOOP
+                0x000000010000002du64, // RETURN 1 (yield)
+                0xfffffffd00000005u64, // JUMP -3
+                0x000000050000003cu64, // LOAD_CONST_INT 5
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        runtime.start_thread(0, 0);
+        let second = runtime.start_thread(0, 4);
+        // Enough for the first thread's turn, but not the second's too
+        runtime.instruction_budget = Some(3);
+        runtime.step().unwrap();
+        assert!(runtime.budget_exhausted);
+        assert_eq!(runtime.variables, [ScratchValue::Number(0.0)]);
+        // The second thread goes first, rather than waiting forever behind
+        // the first
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(5.0)]);
+        assert!(runtime.thread(second).is_none());
+    }
+
+    #[test]
+    fn test_runtime_single_step() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000004u64, // STORE 0
+                0x000000020000003cu64, // LOAD_CONST_INT 2
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        let mut debugger = Debugger::new();
+        debugger.single_step = true;
+        runtime.debugger = Some(debugger);
+        let id = runtime.start_thread(0, 0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.paused_thread, Some(id));
+        assert_eq!(runtime.thread(id).unwrap().program_counter, 0);
+        // Stepping while paused shouldn't do anything
+        runtime.step().unwrap();
+        assert_eq!(runtime.thread(id).unwrap().program_counter, 0);
+        for _ in 0..2 {
+            runtime.resume();
+            runtime.step().unwrap();
+        }
+        assert_eq!(runtime.thread(id).unwrap().program_counter, 2);
+        assert_eq!(runtime.variables, [ScratchValue::Number(1.0)]);
+    }
+
+    #[test]
+    fn test_runtime_single_step_one_thread() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000004u64, // STORE 0
+                0x000000020000003cu64, // LOAD_CONST_INT 2
+                0x0000000100000004u64, // STORE 1
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0), ScratchValue::Number(0.0)],
+            vec![],
+        );
+        let mut debugger = Debugger::new();
+        debugger.single_step = true;
+        runtime.debugger = Some(debugger);
+        let first = runtime.start_thread(0, 0);
+        let second = runtime.start_thread(0, 2);
+        runtime.stepping_thread = Some(second);
+        // The first thread isn't being stepped, so it runs to the end
+        runtime.step().unwrap();
+        assert_eq!(runtime.paused_thread, Some(second));
+        assert!(runtime.thread(first).is_none());
+        for _ in 0..2 {
+            runtime.resume();
+            runtime.step().unwrap();
+        }
+        assert!(runtime.thread(second).is_none());
+        assert_eq!(
+            runtime.variables,
+            [ScratchValue::Number(1.0), ScratchValue::Number(2.0)]
+        );
+        assert_eq!(runtime.stepping_thread, None);
+    }
+
+    #[test]
+    fn test_runtime_glide() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000640000003cu64, // LOAD_CONST_INT 100
+                0x000000320000003cu64, // LOAD_CONST_INT 50
+                0x000000000000004bu64, // MOTION_GLIDE
+                0x0000000000000053u64, // MOTION_X
+                0x0000000000000004u64, // STORE 0
+            ])
+        };
+        let clock = ManualClock::new(0.0);
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        runtime.set_clock(Box::new(clock.clone()));
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        runtime.start_thread(sprite, 0);
+        // New sprites are reported so the renderer can place them
+        assert_eq!(runtime.take_transform_changes(), [sprite]);
+
+        // The sprite doesn't move on the first tick
+        runtime.step().unwrap();
+        assert!(runtime.take_transform_changes().is_empty());
+        clock.advance(500.0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.take_transform_changes(), [sprite]);
+        let motion = &runtime.target(sprite).unwrap().motion;
+        assert_eq!((motion.x, motion.y), (50.0, 25.0));
+
+        // Once there, the script carries on in the same tick
+        clock.advance(500.0);
+        runtime.step().unwrap();
+        assert!(runtime.threads.is_empty());
+        assert_eq!(runtime.variables, [ScratchValue::Number(100.0)]);
+    }
+
+    #[test]
+    fn test_runtime_input_hats() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000003u64, // LOAD 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000008u64, // OP_ADD
+                0x0000000000000004u64, // STORE 0
+                0x0000000000000042u64, // YIELD
+                0x000000000000002du64, // RETURN 0
+                0x000000000000006au64, // SENSING_MOUSE_X
+                0x0000000100000004u64, // STORE 1
+                0x000000000000002du64, // RETURN 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000200000004u64, // STORE 2
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0); 3],
+            vec![],
+        );
+        let stage = runtime.add_target("Stage".into(), true);
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        runtime.target_mut(sprite).unwrap().motion.bounds = Bounds {
+            left: -10.0,
+            right: 10.0,
+            bottom: -10.0,
+            top: 10.0,
+        };
+        runtime
+            .add_hat(sprite, Hat::KeyPressed("space".into()), 0)
+            .unwrap();
+        runtime.add_hat(sprite, Hat::Clicked, 6).unwrap();
+        runtime.add_hat(stage, Hat::Clicked, 9).unwrap();
+
+        // A held key repeats, but doesn't start the script again while it's
+        // still running
+        runtime.key_down(" ").unwrap();
+        runtime.key_down(" ").unwrap();
+        assert_eq!(runtime.threads.len(), 1);
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        assert!(runtime.threads.is_empty());
+        runtime.key_up(" ").unwrap();
+        runtime.key_down("a").unwrap();
+        assert!(runtime.threads.is_empty());
+        assert_eq!(runtime.io.keyboard.pressed, ["A"]);
+
+        runtime.mouse_move(5.0, -5.0).unwrap();
+        runtime.mouse_down().unwrap();
+        runtime.mouse_down().unwrap();
+        runtime.step().unwrap();
+        runtime.mouse_up().unwrap();
+        runtime.mouse_move(100.0, 0.0).unwrap();
+        runtime.mouse_down().unwrap();
+        runtime.step().unwrap();
+        assert_eq!(
+            runtime.variables,
+            [
+                ScratchValue::Number(1.0),
+                ScratchValue::Number(5.0),
+                ScratchValue::Number(1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_runtime_ask_and_wait() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000002u64, // LOAD_CONST 0
+                0x000000000000006cu64, // SENSING_ASK_AND_WAIT
+                0x000000000000006du64, // SENSING_ANSWER
+                0x0000000000000004u64, // STORE 0
+                0x000000000000002du64, // RETURN 0
+                0x0000000100000002u64, // LOAD_CONST 1
+                0x000000000000006cu64, // SENSING_ASK_AND_WAIT
+                0x000000000000006du64, // SENSING_ANSWER
+                0x0000000100000004u64, // STORE 1
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![
+                ScratchValue::String("Name?".into()),
+                ScratchValue::String("Age?".into()),
+            ],
+            vec![ScratchValue::EMPTY; 2],
+            vec![],
+        );
+        let stage = runtime.add_target("Stage".into(), true);
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        runtime.start_thread(sprite, 0);
+        runtime.start_thread(stage, 5);
+        runtime.step().unwrap();
+        // The sprite asks in its bubble, and the stage waits its turn
+        assert_eq!(runtime.questions.prompt(), Some(""));
+        assert_eq!(runtime.target(sprite).unwrap().bubble.text, "Name?");
+        assert_eq!(runtime.questions.queue.len(), 2);
+
+        runtime.answer("Ann").unwrap();
+        assert_eq!(runtime.target(sprite).unwrap().bubble.text, "");
+        assert_eq!(runtime.questions.prompt(), Some("Age?"));
+        runtime.step().unwrap();
+        runtime.answer("7").unwrap();
+        runtime.step().unwrap();
+        assert!(runtime.threads.is_empty());
+        assert_eq!(
+            runtime.variables,
+            [
+                ScratchValue::String("Ann".into()),
+                ScratchValue::String("7".into())
+            ]
+        );
+        assert!(runtime.answer("again").is_err());
+
+        // Stopping the thread takes its question down
+        runtime.start_thread(sprite, 0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.questions.prompt(), Some(""));
+        runtime.stop_other_threads(sprite, u32::MAX);
+        runtime.step().unwrap();
+        assert_eq!(runtime.questions.prompt(), None);
+        assert_eq!(runtime.target(sprite).unwrap().bubble.text, "");
+    }
+
+    #[test]
+    fn test_runtime_timed_bubbles() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000002u64, // LOAD_CONST 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000066u64, // LOOKS_SAY_FOR_SECS
+                0x000000000000002du64, // RETURN 0
+                0x0000000100000002u64, // LOAD_CONST 1
+                0x0000000000000065u64, // LOOKS_THINK
+            ])
+        };
+        let clock = ManualClock::new(0.0);
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![
+                ScratchValue::String("Hello!".into()),
+                ScratchValue::String("Hmm".into()),
+            ],
+            vec![],
+            vec![],
+        );
+        runtime.set_clock(Box::new(clock.clone()));
+        let a = runtime.add_target("a".into(), false);
+        let b = runtime.add_target("b".into(), false);
+        runtime.start_thread(a, 0);
+        runtime.start_thread(b, 0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.take_bubble_changes().len(), 2);
+
+        // b's bubble is replaced before its timer runs out, so only a's goes
+        runtime.start_thread(b, 4);
+        runtime.step().unwrap();
+        clock.advance(1000.0);
+        runtime.step().unwrap();
+        assert!(runtime.threads.is_empty());
+        assert_eq!(
+            runtime.take_bubble_changes(),
+            [
+                BubbleEvent {
+                    target: a,
+                    kind: BubbleKind::Say,
+                    text: "".into(),
+                },
+                BubbleEvent {
+                    target: b,
+                    kind: BubbleKind::Think,
+                    text: "Hmm".into(),
+                },
+            ]
+        );
+
+        runtime.stop_all();
+        assert_eq!(
+            runtime.take_bubble_changes(),
+            [BubbleEvent {
+                target: b,
+                kind: BubbleKind::Say,
+                text: "".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_runtime_play_sound_until_done() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000780000003cu64, // LOAD_CONST_INT 120
+                0x0000000000000080u64, // SOUND_SET_EFFECT 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000000000007du64, // SOUND_PLAY_UNTIL_DONE
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000004u64, // STORE 0
+                0x000000000000002du64, // RETURN 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000000000007cu64, // SOUND_PLAY
+                0x000000000000002du64, // RETURN 0
+                0x000000000000007eu64, // SOUND_STOP_ALL
+            ])
+        };
+        let clock = ManualClock::new(0.0);
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![],
+            vec![ScratchValue::Number(0.0)],
+            vec![],
+        );
+        runtime.set_clock(Box::new(clock.clone()));
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        runtime.target_mut(sprite).unwrap().sound.sounds = vec![Sound {
+            name: "meow".into(),
+            sample_count: 22050,
+            rate: 44100,
+        }];
+
+        // Half a second of sound an octave up plays for a quarter of one
+        runtime.start_thread(sprite, 0);
+        runtime.step().unwrap();
+        clock.advance(249.0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(0.0)]);
+        clock.advance(1.0);
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(1.0)]);
+        assert!(runtime.threads.is_empty());
+
+        // Starting the sound over is the end of it for whoever was waiting
+        runtime.variables[0] = ScratchValue::Number(0.0);
+        runtime.start_thread(sprite, 0);
+        runtime.step().unwrap();
+        runtime.start_thread(sprite, 7);
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(1.0)]);
+
+        // And so is stopping all sounds
+        runtime.variables[0] = ScratchValue::Number(0.0);
+        runtime.start_thread(sprite, 0);
+        runtime.step().unwrap();
+        runtime.start_thread(sprite, 10);
+        runtime.step().unwrap();
+        runtime.step().unwrap();
+        assert_eq!(runtime.variables, [ScratchValue::Number(1.0)]);
+        assert!(runtime.threads.is_empty());
+    }
+}
@@ -1,51 +1,312 @@
-use std::{convert::TryFrom, ops};
+use std::{borrow::Cow, cmp::Ordering, convert::TryFrom, ops};
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use wasm_bindgen::JsValue;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ScratchValue {
     /// Represented as 0/1 in the value
     Boolean(bool),
     /// Represented as a pointer
     String(String),
     /// Represented as a 64-bit float (same as JS)
-    Number(f64),
+    Number(#[serde(with = "js_number")] f64),
+}
+
+/// Serializes numbers so they survive formats like JSON that can't express
+/// NaN and the infinities, by writing those as JS would print them.
+mod js_number {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() && !value.is_finite() {
+            serializer.serialize_str(&js_number_to_string(*value))
+        } else {
+            serializer.serialize_f64(*value)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Number {
+            Number(f64),
+            String(String),
+        }
+        if deserializer.is_human_readable() {
+            Ok(match Number::deserialize(deserializer)? {
+                Number::Number(value) => value,
+                Number::String(value) => js_string_to_number(&value),
+            })
+        } else {
+            f64::deserialize(deserializer)
+        }
+    }
+}
+
+/// Implements JS's `Number(string)`, which Scratch's casts are built on.
+/// Returns NaN for anything JS wouldn't accept.
+pub fn js_string_to_number(value: &str) -> f64 {
+    let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+    if value.is_empty() {
+        return 0.0;
+    }
+    match value {
+        "Infinity" | "+Infinity" => return f64::INFINITY,
+        "-Infinity" => return f64::NEG_INFINITY,
+        _ => {}
+    }
+    let radix = match value.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0o") | Some("0O") => 8,
+        Some("0b") | Some("0B") => 2,
+        _ => 10,
+    };
+    if radix != 10 {
+        let digits = &value[2..];
+        if digits.is_empty() {
+            return f64::NAN;
+        }
+        return digits
+            .chars()
+            .try_fold(0.0, |number, digit| {
+                digit
+                    .to_digit(radix)
+                    .map(|digit| number * radix as f64 + digit as f64)
+            })
+            .unwrap_or(f64::NAN);
+    }
+    // Rust accepts things like "inf" and "NaN" that JS doesn't
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'))
+    {
+        return f64::NAN;
+    }
+    value.parse().unwrap_or(f64::NAN)
+}
+
+/// Implements JS's `Number.prototype.toString()`, which doesn't format
+/// numbers the same way as Rust (e.g. `1e21` instead of
+/// `1000000000000000000000`, and `Infinity` instead of `inf`).
+pub fn js_number_to_string(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".into();
+    }
+    if value == 0.0 {
+        // Including -0
+        return "0".into();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.into();
+    }
+    // Rust's exponential formatting gives the shortest digits that round-trip,
+    // like JS does, so only their placement needs changing
+    let exponential = format!("{:e}", value.abs());
+    let (mantissa, exponent) = exponential
+        .split_once('e')
+        .expect("exponential format always has an exponent");
+    let digits = mantissa.replace('.', "");
+    let digit_count = digits.len() as i32;
+    // The position of the decimal point relative to the start of the digits
+    let point = exponent.parse::<i32>().expect("exponent is an integer") + 1;
+    let mut result = String::new();
+    if value < 0.0 {
+        result.push('-');
+    }
+    if digit_count <= point && point <= 21 {
+        result.push_str(&digits);
+        result.push_str(&"0".repeat((point - digit_count) as usize));
+    } else if 0 < point && point <= 21 {
+        result.push_str(&digits[..point as usize]);
+        result.push('.');
+        result.push_str(&digits[point as usize..]);
+    } else if -6 < point && point <= 0 {
+        result.push_str("0.");
+        result.push_str(&"0".repeat(-point as usize));
+        result.push_str(&digits);
+    } else {
+        result.push_str(&digits[..1]);
+        if digit_count > 1 {
+            result.push('.');
+            result.push_str(&digits[1..]);
+        }
+        result.push('e');
+        result.push(if point - 1 < 0 { '-' } else { '+' });
+        result.push_str(&(point - 1).abs().to_string());
+    }
+    result
+}
+
+/// Implements scratch-vm's `Color.hexToRgb` for a color without its `#`,
+/// which can be shortened to one digit per channel.
+fn hex_to_rgb(hex: &str) -> Option<[u8; 4]> {
+    if !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits = match hex.len() {
+        3 => hex.chars().flat_map(|digit| [digit, digit]).collect(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+    let channel = |index: usize| u8::from_str_radix(&digits[index * 2..][..2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?, 255])
+}
+
+/// Where an index into a list points, after resolving it like scratch-vm's
+/// `Cast.toListIndex`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListIndex {
+    /// A zero-based index that's in bounds.
+    Index(usize),
+    /// `"all"`, for the instructions that accept it.
+    All,
+    /// Out of bounds, or not an index at all. The instruction does nothing.
+    Invalid,
 }
 
 impl ScratchValue {
     pub const EMPTY: ScratchValue = ScratchValue::String(String::new());
     pub const EMPTY_REF: &ScratchValue = &Self::EMPTY;
-}
 
-impl Into<bool> for ScratchValue {
-    fn into(self) -> bool {
+    /// What JS's `Number(value)` would give, without Scratch turning NaN into
+    /// 0.
+    fn to_js_number(&self) -> f64 {
         match self {
-            Self::Boolean(value) => value,
-            Self::Number(value) => value != 0f64,
-            Self::String(value) => value != "" && value != "false",
+            Self::Number(value) => *value,
+            Self::Boolean(value) => *value as u8 as f64,
+            Self::String(value) => js_string_to_number(value),
         }
     }
-}
 
-impl Into<String> for ScratchValue {
-    fn into(self) -> String {
+    /// The value as a string, only allocating if it's a number.
+    pub fn as_str(&self) -> Cow<'_, str> {
         match self {
-            Self::String(value) => value,
-            Self::Boolean(true) => "true".into(),
-            Self::Boolean(false) => "false".into(),
-            // May be source of incompatibilities; 1.0.to_string() != "1"
-            Self::Number(value) => value.to_string(),
+            Self::String(value) => Cow::Borrowed(value),
+            Self::Boolean(true) => Cow::Borrowed("true"),
+            Self::Boolean(false) => Cow::Borrowed("false"),
+            Self::Number(value) => Cow::Owned(js_number_to_string(*value)),
         }
     }
-}
 
-impl Into<f64> for ScratchValue {
-    fn into(self) -> f64 {
+    fn is_whitespace(&self) -> bool {
         match self {
-            Self::Number(value) => value,
-            Self::Boolean(true) => 1f64,
-            Self::Boolean(false) => 0f64,
-            Self::String(value) => value.parse().unwrap_or(0f64),
+            Self::String(value) => value.trim().is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Resolves a one-based list index for a list of `length` items. Besides
+    /// numbers (which are floored), strings can be `"last"`, `"random"` or
+    /// `"any"` (using `random`, which should be in `[0, 1)`), or `"all"` if
+    /// `accept_all` is set.
+    pub fn to_list_index(
+        &self,
+        length: usize,
+        accept_all: bool,
+        random: impl FnOnce() -> f64,
+    ) -> ListIndex {
+        if let Self::String(value) = self {
+            match value.as_str() {
+                "all" if accept_all => return ListIndex::All,
+                "all" => return ListIndex::Invalid,
+                "last" if length > 0 => return ListIndex::Index(length - 1),
+                "random" | "any" if length > 0 => {
+                    let index = (random() * length as f64).floor() as usize;
+                    // Guard against a source that returns 1
+                    return ListIndex::Index(index.min(length - 1));
+                }
+                "last" | "random" | "any" => return ListIndex::Invalid,
+                _ => {}
+            }
+        }
+        // NaN isn't in any range, so it's invalid like 0 would be
+        let index = self.to_js_number().floor();
+        if (1.0..=length as f64).contains(&index) {
+            ListIndex::Index(index as usize - 1)
+        } else {
+            ListIndex::Invalid
+        }
+    }
+
+    /// Implements scratch-vm's `Cast.toRgbColorObject` as `[r, g, b, a]`:
+    /// strings starting with `#` are `#rrggbb` or `#rgb` (and black if they
+    /// aren't either), and anything else is a number like `0xaarrggbb` where
+    /// an alpha of 0 means opaque.
+    pub fn to_rgba_color(&self) -> [u8; 4] {
+        if let Self::String(value) = self {
+            if let Some(hex) = value.strip_prefix('#') {
+                return hex_to_rgb(hex).unwrap_or([0, 0, 0, 255]);
+            }
+        }
+        let number = self.to_js_number();
+        // JS's `>>` works on the number as a 32-bit integer, wrapping around
+        let decimal = if number.is_finite() {
+            number.trunc().rem_euclid(4294967296.0) as u32
+        } else {
+            0
+        };
+        let [alpha, red, green, blue] = decimal.to_be_bytes();
+        [red, green, blue, if alpha > 0 { alpha } else { 255 }]
+    }
+
+    /// Implements scratch-vm's `Cast.compare`: numerically if both sides look
+    /// like numbers, otherwise as case-insensitive strings.
+    pub fn compare(&self, other: &ScratchValue) -> Ordering {
+        let mut lhs = self.to_js_number();
+        let mut rhs = other.to_js_number();
+        // Whitespace is 0 as a number, but shouldn't be equal to 0
+        if lhs == 0.0 && self.is_whitespace() {
+            lhs = f64::NAN;
+        } else if rhs == 0.0 && other.is_whitespace() {
+            rhs = f64::NAN;
+        }
+        if lhs.is_nan() || rhs.is_nan() {
+            // Compare lowercased characters as they come, so that comparing
+            // strings (e.g. finding an item in a list) doesn't allocate
+            let lhs = self.as_str();
+            let rhs = other.as_str();
+            return lhs
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(rhs.chars().flat_map(char::to_lowercase));
+        }
+        // Also covers Infinity == Infinity
+        lhs.partial_cmp(&rhs).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl From<ScratchValue> for bool {
+    fn from(value: ScratchValue) -> Self {
+        match value {
+            ScratchValue::Boolean(value) => value,
+            ScratchValue::Number(value) => value != 0f64 && !value.is_nan(),
+            ScratchValue::String(value) => {
+                !value.is_empty() && value != "0" && value.to_lowercase() != "false"
+            }
+        }
+    }
+}
+
+impl From<ScratchValue> for String {
+    fn from(value: ScratchValue) -> Self {
+        match value {
+            ScratchValue::String(value) => value,
+            ScratchValue::Boolean(true) => "true".into(),
+            ScratchValue::Boolean(false) => "false".into(),
+            ScratchValue::Number(value) => js_number_to_string(value),
+        }
+    }
+}
+
+impl From<ScratchValue> for f64 {
+    fn from(value: ScratchValue) -> Self {
+        // Scratch treats NaN as 0 everywhere
+        let number = value.to_js_number();
+        if number.is_nan() {
+            0f64
+        } else {
+            number
         }
     }
 }
@@ -108,7 +369,15 @@ impl ops::Div for ScratchValue {
 impl ops::Rem for ScratchValue {
     type Output = ScratchValue;
     fn rem(self, rhs: ScratchValue) -> Self::Output {
-        Self::Number(Into::<f64>::into(self) % Into::<f64>::into(rhs))
+        // Scratch's modulo takes the sign of the divisor, unlike JS's and
+        // Rust's
+        let rhs = Into::<f64>::into(rhs);
+        let result = Into::<f64>::into(self) % rhs;
+        Self::Number(if result / rhs < 0.0 {
+            result + rhs
+        } else {
+            result
+        })
     }
 }
 
@@ -141,12 +410,31 @@ impl TryFrom<JsValue> for ScratchValue {
     }
 }
 
-impl Into<JsValue> for ScratchValue {
-    fn into(self) -> JsValue {
-        match self {
-            Self::Boolean(value) => JsValue::from_bool(value),
-            Self::String(value) => JsValue::from_str(&value),
-            Self::Number(value) => JsValue::from_f64(value),
+impl From<ScratchValue> for JsValue {
+    fn from(value: ScratchValue) -> Self {
+        match value {
+            ScratchValue::Boolean(value) => JsValue::from_bool(value),
+            ScratchValue::String(value) => JsValue::from_str(&value),
+            ScratchValue::Number(value) => JsValue::from_f64(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_read_from_hex_and_numbers() {
+        for (value, color) in [
+            (ScratchValue::String("#ff8000".into()), [255, 128, 0, 255]),
+            (ScratchValue::String("#F80".into()), [255, 136, 0, 255]),
+            (ScratchValue::String("#nope".into()), [0, 0, 0, 255]),
+            (ScratchValue::Number(16744448.0), [255, 128, 0, 255]),
+            (ScratchValue::Number(-1.0), [255, 255, 255, 255]),
+            (ScratchValue::String("2130771712".into()), [0, 255, 0, 127]),
+        ] {
+            assert_eq!(value.to_rgba_color(), color, "{:?}", value);
         }
     }
 }
@@ -0,0 +1,225 @@
+use crate::collision::CollisionProvider;
+use crate::io::Mouse;
+use crate::pen::PenLayer;
+use crate::scratch_value::ScratchValue;
+use crate::target::Target;
+
+/// How far away the stage and sprites that don't exist are, according to
+/// "distance to ()".
+const FAR_AWAY: f64 = 10000.0;
+
+/// Finds the sprite called `name`, like scratch-vm's `getSpriteTargetByName`:
+/// the original sprite, never one of its clones or the stage.
+pub fn sprite_by_name<'a>(targets: &'a [Target], name: &str) -> Option<&'a Target> {
+    targets
+        .iter()
+        .find(|target| !target.is_stage && !target.is_clone() && target.name == name)
+}
+
+/// The "distance to ()" block, from the target at `index` to the mouse
+/// pointer (`_mouse_`) or the sprite called `object`.
+pub fn distance_to(targets: &[Target], index: usize, mouse: &Mouse, object: &ScratchValue) -> f64 {
+    let target = &targets[index];
+    if target.is_stage {
+        return FAR_AWAY;
+    }
+    let (x, y) = match object {
+        ScratchValue::String(object) if object == "_mouse_" => (mouse.x, mouse.y),
+        _ => match sprite_by_name(targets, &object.as_str()) {
+            Some(sprite) => (sprite.motion.x, sprite.motion.y),
+            None => return FAR_AWAY,
+        },
+    };
+    let dx = target.motion.x - x;
+    let dy = target.motion.y - y;
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// The "(property) of (object)" block, like scratch-vm's `getAttributeOf`:
+/// `object` is `_stage_` or a sprite's name, and properties the block doesn't
+/// know are the target's own variables. Anything that doesn't exist is 0.
+pub fn attribute_of(
+    targets: &[Target],
+    variables: &[ScratchValue],
+    property: &str,
+    object: &ScratchValue,
+) -> ScratchValue {
+    let target = match object {
+        ScratchValue::String(object) if object == "_stage_" => {
+            targets.iter().find(|target| target.is_stage)
+        }
+        _ => sprite_by_name(targets, &object.as_str()),
+    };
+    let target = match target {
+        Some(target) => target,
+        None => return ScratchValue::Number(0.0),
+    };
+    let looks = &target.looks;
+    let value = match (target.is_stage, property) {
+        (true, "background #" | "backdrop #") | (false, "costume #") => {
+            Some(ScratchValue::Number(looks.costume as f64 + 1.0))
+        }
+        (true, "backdrop name") | (false, "costume name") => {
+            Some(ScratchValue::String(looks.costume_name().to_string()))
+        }
+        (false, "x position") => Some(ScratchValue::Number(target.motion.x)),
+        (false, "y position") => Some(ScratchValue::Number(target.motion.y)),
+        (false, "direction") => Some(ScratchValue::Number(target.motion.direction)),
+        (false, "size") => Some(ScratchValue::Number(looks.size)),
+        (_, "volume") => Some(ScratchValue::Number(target.sound.volume)),
+        _ => target
+            .variable(property)
+            .and_then(|index| variables.get(index as usize))
+            .cloned(),
+    };
+    value.unwrap_or(ScratchValue::Number(0.0))
+}
+
+/// The "touching ()?" block, like scratch-vm's `isTouchingObject`: `object`
+/// is `_mouse_`, `_edge_` or a sprite's name, in which case touching any of
+/// its clones counts too. Hidden sprites don't touch other sprites, and hidden
+/// sprites aren't touched.
+pub fn touching_object(
+    targets: &[Target],
+    index: usize,
+    mouse: &Mouse,
+    collision: &dyn CollisionProvider,
+    object: &ScratchValue,
+) -> bool {
+    let target = &targets[index];
+    if target.is_stage {
+        return false;
+    }
+    match object {
+        ScratchValue::String(object) if object == "_mouse_" => {
+            collision.touching_point(targets, index, mouse.x, mouse.y)
+        }
+        ScratchValue::String(object) if object == "_edge_" => target.motion.is_touching_edge(),
+        _ => {
+            let sprite = match sprite_by_name(targets, &object.as_str()) {
+                Some(sprite) if target.looks.visible => sprite.id,
+                _ => return false,
+            };
+            let others = targets
+                .iter()
+                .enumerate()
+                .filter(|(other, other_target)| {
+                    *other != index
+                        && other_target.looks.visible
+                        && (other_target.id == sprite || other_target.clone_of == Some(sprite))
+                })
+                .map(|(other, _)| other)
+                .collect::<Vec<_>>();
+            collision.touching_targets(targets, index, &others)
+        }
+    }
+}
+
+/// The "touching color ()?" block, which checks the current sprite against
+/// everything else that's showing, including the pen layer.
+pub fn touching_color(
+    targets: &[Target],
+    index: usize,
+    collision: &dyn CollisionProvider,
+    pen_layer: &PenLayer,
+    color: &ScratchValue,
+) -> bool {
+    let target = &targets[index];
+    if target.is_stage || !target.looks.visible {
+        return false;
+    }
+    let mut others = (0..targets.len())
+        .filter(|other| *other != index && targets[*other].looks.visible)
+        .collect::<Vec<_>>();
+    // From the front to the back
+    others.sort_by_key(|other| std::cmp::Reverse(targets[*other].looks.layer));
+    let [red, green, blue, _] = color.to_rgba_color();
+    collision.touching_color(targets, index, &others, pen_layer, [red, green, blue])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::BoundingBoxCollision;
+    use crate::motion::Bounds;
+
+    #[test]
+    fn sprites_are_found_by_name() {
+        let mut stage = Target::new(0, "Stage".into(), true);
+        stage.variables.push(("score".into(), 0));
+        let mut sprite = Target::new(1, "Cat".into(), false);
+        sprite.motion.x = 30.0;
+        sprite.motion.y = 40.0;
+        sprite.variables.push(("speed".into(), 1));
+        let mut clone = sprite.clone();
+        clone.id = 2;
+        clone.clone_of = Some(1);
+        clone.motion.x = -100.0;
+        let targets = [stage, sprite, clone];
+        let variables = [ScratchValue::Number(7.0), ScratchValue::Number(3.0)];
+
+        // The original sprite counts, not the clone
+        assert_eq!(sprite_by_name(&targets, "Cat").map(|cat| cat.id), Some(1));
+        let cat = ScratchValue::String("Cat".into());
+        let mouse = Mouse::default();
+        assert_eq!(distance_to(&targets, 2, &mouse, &cat), 130.0);
+        let pointer = ScratchValue::String("_mouse_".into());
+        assert_eq!(distance_to(&targets, 1, &mouse, &pointer), 50.0);
+        let dog = ScratchValue::String("Dog".into());
+        assert_eq!(distance_to(&targets, 1, &mouse, &dog), 10000.0);
+        assert_eq!(distance_to(&targets, 0, &mouse, &cat), 10000.0);
+
+        let of = |property: &str, object: &ScratchValue| {
+            attribute_of(&targets, &variables, property, object)
+        };
+        let stage = ScratchValue::String("_stage_".into());
+        assert_eq!(of("x position", &cat), ScratchValue::Number(30.0));
+        assert_eq!(of("speed", &cat), ScratchValue::Number(3.0));
+        assert_eq!(of("score", &stage), ScratchValue::Number(7.0));
+        assert_eq!(of("backdrop #", &stage), ScratchValue::Number(1.0));
+        // Sprites don't see the stage's variables, and the stage has no
+        // position
+        assert_eq!(of("score", &cat), ScratchValue::Number(0.0));
+        assert_eq!(of("x position", &stage), ScratchValue::Number(0.0));
+        assert_eq!(of("x position", &dog), ScratchValue::Number(0.0));
+    }
+
+    #[test]
+    fn touching_counts_clones_but_not_hidden_sprites() {
+        let square = |id: u32, x: f64| {
+            let mut target = Target::new(id, format!("Sprite{}", id), false);
+            target.motion.x = x;
+            target.motion.bounds = Bounds {
+                left: -10.0,
+                right: 10.0,
+                bottom: -10.0,
+                top: 10.0,
+            };
+            target
+        };
+        let mut clone = square(3, 15.0);
+        clone.clone_of = Some(2);
+        let mut targets = [square(1, 0.0), square(2, 100.0), clone];
+        let sprite2 = ScratchValue::String("Sprite2".into());
+        let mouse = Mouse::default();
+        let touching = |targets: &[Target], index: usize| {
+            touching_object(targets, index, &mouse, &BoundingBoxCollision, &sprite2)
+        };
+        assert!(touching(&targets, 0));
+        // Clones don't touch themselves, only the others
+        assert!(!touching(&targets, 2));
+        targets[2].looks.visible = false;
+        assert!(!touching(&targets, 0));
+        targets[2].looks.visible = true;
+        targets[0].looks.visible = false;
+        assert!(!touching(&targets, 0));
+        let pointer = ScratchValue::String("_mouse_".into());
+        assert!(touching_object(
+            &targets,
+            0,
+            &mouse,
+            &BoundingBoxCollision,
+            &pointer
+        ));
+    }
+}
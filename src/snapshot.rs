@@ -0,0 +1,248 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::instruction::Instruction;
+use crate::io::IoDevices;
+use crate::monitor::Monitors;
+use crate::pen::PenLayer;
+use crate::question::Questions;
+use crate::scratch_value::ScratchValue;
+use crate::target::Target;
+use crate::thread::Thread;
+
+/// Starts every binary snapshot, so that other data isn't mistaken for one.
+const MAGIC: &[u8; 4] = b"SVMS";
+
+/// Bumped whenever the layout of `Snapshot` (or anything saved along with
+/// one, like a `Recording`) changes, since the binary format can't be read
+/// with a different layout.
+pub const SNAPSHOT_VERSION: u8 = 10;
+
+/// Encodes `value` in the binary format: `magic`, the version, then the value
+/// as postcard.
+pub(crate) fn encode<T: Serialize>(magic: &[u8; 4], value: &T) -> Vec<u8> {
+    let mut bytes = magic.to_vec();
+    bytes.push(SNAPSHOT_VERSION);
+    // Everything saved is a plain value, so this can't fail
+    postcard::to_extend(value, bytes).expect("snapshots should always serialize")
+}
+
+/// Decodes something written by `encode`, failing with one of `errors` (for
+/// the wrong magic bytes, a corrupt value and a different version).
+pub(crate) fn decode<T: DeserializeOwned>(
+    magic: &[u8; 4],
+    bytes: &[u8],
+    errors: [&'static str; 3],
+) -> Result<T, &'static str> {
+    let [not_this, corrupt, other_version] = errors;
+    let payload = bytes.strip_prefix(&magic[..]).ok_or(not_this)?;
+    match payload.split_first() {
+        Some((&SNAPSHOT_VERSION, payload)) => postcard::from_bytes(payload).map_err(|_| corrupt),
+        Some(_) => Err(other_version),
+        None => Err(corrupt),
+    }
+}
+
+/// FNV-1a, which (unlike `std`'s hasher) gives the same result on every
+/// platform and Rust version, so saved hashes stay valid.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn write_value(&mut self, value: &ScratchValue) {
+        // Tagged so that e.g. the string "1" and the number 1 differ
+        match value {
+            ScratchValue::Boolean(value) => self.write(&[0, *value as u8]),
+            ScratchValue::Number(value) => {
+                self.write(&[1]);
+                self.write(&value.to_bits().to_le_bytes());
+            }
+            ScratchValue::String(value) => {
+                self.write(&[2]);
+                self.write(&(value.len() as u64).to_le_bytes());
+                self.write(value.as_bytes());
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Everything a `Runtime` is doing, apart from the program itself and things
+/// that belong to the host (the clock, the debugger and the budgets). Taken
+/// by `Runtime::snapshot` and put back by `Runtime::restore`.
+///
+/// Timers in the threads' states are stored as the milliseconds left rather
+/// than the time on the clock, because the clock is usually somewhere else by
+/// the time a snapshot is restored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// A hash of the instructions and constants, so that a snapshot can't be
+    /// restored into a different program. See `program_hash`.
+    pub program_hash: u64,
+    pub variables: Vec<ScratchValue>,
+    pub lists: Vec<Vec<ScratchValue>>,
+    pub threads: Vec<Thread>,
+    pub targets: Vec<Target>,
+    pub monitors: Monitors,
+    /// The keys held down and the mouse, as the host last said, and the last
+    /// answer.
+    pub io: IoDevices,
+    pub questions: Questions,
+    pub pen_layer: PenLayer,
+    /// The random number generator's state, if it has one that can be saved.
+    pub random_state: Option<u64>,
+    pub next_thread_id: u32,
+    pub next_target_id: u32,
+    pub paused_thread: Option<u32>,
+}
+
+impl Snapshot {
+    /// Encodes the snapshot in the compact binary format: the magic bytes,
+    /// the version, then the fields in order as postcard.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(MAGIC, self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        decode(
+            MAGIC,
+            bytes,
+            [
+                "not a snapshot",
+                "snapshot is corrupt",
+                "snapshot is from a different version",
+            ],
+        )
+    }
+}
+
+/// Hashes a program, so that a snapshot of one isn't restored into another.
+pub fn program_hash(instructions: &[Instruction], constants: &[ScratchValue]) -> u64 {
+    let mut hash = Fnv1a::new();
+    for instruction in instructions {
+        hash.write(&(instruction.name as u16).to_le_bytes());
+        hash.write(&instruction.argument.to_le_bytes());
+    }
+    for constant in constants {
+        hash.write_value(constant);
+    }
+    hash.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+    use crate::random::SeededRandom;
+    use crate::runtime::Runtime;
+    use crate::thread::ThreadState;
+    use crate::transmute_instructions;
+
+    use super::*;
+
+    fn runtime(clock: &ManualClock) -> Runtime {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000029u64, // MONITOR_SHOWVAR 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000040u64, // WAIT
+                0x0000000000000002u64, // LOAD_CONST 0
+                0x0000000100000002u64, // LOAD_CONST 1
+                0x0000000000000033u64, // DATA_RAND 0
+                0x0000000000000024u64, // LIST_PUSH 0
+                0x0000000000000003u64, // LOAD 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000008u64, // OP_ADD
+                0x0000000000000004u64, // STORE 0
+                0xfffffff500000005u64, // JUMP -11
+            ])
+        };
+        let mut runtime = Runtime::new(
+            instructions.to_vec(),
+            vec![ScratchValue::Number(1.0), ScratchValue::Number(100.0)],
+            vec![ScratchValue::Number(0.0)],
+            vec![vec![]],
+        );
+        runtime.set_clock(Box::new(clock.clone()));
+        runtime.set_random(Box::new(SeededRandom::new(7)));
+        let sprite = runtime.add_target("Sprite1".into(), false);
+        runtime.start_thread(sprite, 1);
+        runtime
+    }
+
+    #[test]
+    fn restore_carries_on_the_same_way() {
+        let clock = ManualClock::new(0.0);
+        let mut original = runtime(&clock);
+        for _ in 0..3 {
+            original.step().unwrap();
+            clock.advance(1000.0);
+        }
+        let bytes = original.snapshot().to_bytes();
+
+        // Restored into a runtime whose clock is somewhere else entirely
+        let other_clock = ManualClock::new(50_000.0);
+        let mut restored = runtime(&other_clock);
+        restored
+            .restore(Snapshot::from_bytes(&bytes).unwrap())
+            .unwrap();
+        assert_eq!(restored.snapshot(), original.snapshot());
+        assert_eq!(
+            restored.threads[0].state,
+            ThreadState::WaitingOnTimer { until: 50_000.0 }
+        );
+        assert!(restored.monitors.variables.contains(&0));
+        for _ in 0..3 {
+            original.step().unwrap();
+            clock.advance(1000.0);
+            restored.step().unwrap();
+            other_clock.advance(1000.0);
+        }
+        assert_eq!(restored.lists, original.lists);
+        assert_eq!(restored.variables, [ScratchValue::Number(5.0)]);
+    }
+
+    #[test]
+    fn restore_rejects_other_programs_and_garbage() {
+        let clock = ManualClock::new(0.0);
+        let snapshot = runtime(&clock).snapshot();
+        let mut other = Runtime::new(vec![], vec![], vec![], vec![]);
+        assert!(other.restore(snapshot.clone()).is_err());
+
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Snapshot::from_bytes(b"nope"), Err("not a snapshot"));
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err("snapshot is corrupt")
+        );
+        let mut future = bytes.clone();
+        future[MAGIC.len()] += 1;
+        assert!(Snapshot::from_bytes(&future).is_err());
+    }
+
+    #[test]
+    fn json_keeps_special_numbers() {
+        let clock = ManualClock::new(0.0);
+        let mut snapshot = runtime(&clock).snapshot();
+        snapshot.variables = vec![
+            ScratchValue::Number(f64::NAN),
+            ScratchValue::Number(f64::NEG_INFINITY),
+            ScratchValue::String("NaN".into()),
+        ];
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored: Snapshot = serde_json::from_str(&json).unwrap();
+        assert!(matches!(restored.variables[0], ScratchValue::Number(value) if value.is_nan()));
+        assert_eq!(restored.variables[1..], snapshot.variables[1..]);
+    }
+}
@@ -0,0 +1,346 @@
+use std::convert::TryFrom;
+
+use js_sys::{Array, Function, Reflect};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::console;
+
+use crate::motion::wrap_clamp;
+use crate::scratch_value::ScratchValue;
+
+/// The sound effects, in the order they're stored in `SoundState::effects`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SoundEffect {
+    Pitch = 0,
+    Pan = 1,
+}
+
+impl TryFrom<u32> for SoundEffect {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Pitch,
+            1 => Self::Pan,
+            _ => return Err("unknown sound effect"),
+        })
+    }
+}
+
+impl SoundEffect {
+    pub const COUNT: usize = 2;
+
+    /// Keeps the effect's value in range like scratch-vm's `_updateEffect`.
+    fn clamp(self, value: f64) -> f64 {
+        match self {
+            SoundEffect::Pitch => value.clamp(-360.0, 360.0),
+            SoundEffect::Pan => value.clamp(-100.0, 100.0),
+        }
+    }
+}
+
+/// A sound, with as much of its audio as it takes to know how long it plays.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sound {
+    pub name: String,
+    pub sample_count: u32,
+    /// In samples per second.
+    pub rate: u32,
+}
+
+impl Sound {
+    /// How long the sound plays for (in milliseconds) with the pitch effect
+    /// at `pitch`, which speeds it up by an octave every 120.
+    pub fn duration(&self, pitch: f64) -> f64 {
+        if self.rate == 0 {
+            return 0.0;
+        }
+        let seconds = self.sample_count as f64 / self.rate as f64;
+        seconds * 1000.0 / 2f64.powf(pitch / 120.0)
+    }
+}
+
+/// A target's sounds, and how loud and with what effects it plays them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SoundState {
+    pub sounds: Vec<Sound>,
+    /// In percent, from 0 to 100.
+    pub volume: f64,
+    /// Indexed by `SoundEffect`.
+    pub effects: [f64; SoundEffect::COUNT],
+    /// Set whenever the volume or effects change, so the sound player can
+    /// update the sounds that are playing at the end of a tick.
+    #[serde(skip)]
+    pub changed: bool,
+}
+
+impl Default for SoundState {
+    fn default() -> Self {
+        Self {
+            sounds: Vec::new(),
+            volume: 100.0,
+            effects: [0.0; SoundEffect::COUNT],
+            changed: false,
+        }
+    }
+}
+
+impl SoundState {
+    /// Implements scratch-vm's `_getSoundIndex`: names first, then one-based
+    /// indices wrapped around the sounds if the value starts with a number,
+    /// like `parseInt`.
+    pub fn sound_index(&self, requested: &ScratchValue) -> Option<usize> {
+        if self.sounds.is_empty() {
+            return None;
+        }
+        let name = requested.as_str();
+        if let ScratchValue::String(_) = requested {
+            if let Some(index) = self.sounds.iter().position(|sound| sound.name == *name) {
+                return Some(index);
+            }
+        }
+        let index = parse_int(&name)?;
+        Some(wrap_clamp(index, 1.0, self.sounds.len() as f64) as usize - 1)
+    }
+
+    pub fn set_volume(&mut self, volume: f64) {
+        let volume = volume.clamp(0.0, 100.0);
+        if volume != self.volume {
+            self.volume = volume;
+            self.changed = true;
+        }
+    }
+
+    pub fn set_effect(&mut self, effect: SoundEffect, value: f64) {
+        let value = effect.clamp(value);
+        let current = &mut self.effects[effect as usize];
+        if value != *current {
+            *current = value;
+            self.changed = true;
+        }
+    }
+
+    pub fn clear_effects(&mut self) {
+        if self.effects.iter().any(|value| *value != 0.0) {
+            self.effects = [0.0; SoundEffect::COUNT];
+            self.changed = true;
+        }
+    }
+
+    /// How long the sound at `index` plays for with the current pitch, in
+    /// milliseconds.
+    pub fn duration(&self, index: usize) -> f64 {
+        self.sounds.get(index).map_or(0.0, |sound| {
+            sound.duration(self.effects[SoundEffect::Pitch as usize])
+        })
+    }
+}
+
+/// JS's `parseInt(text, 10)`, which reads as many digits as it can.
+fn parse_int(text: &str) -> Option<f64> {
+    let text = text.trim_start();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    digits[..end].parse::<f64>().ok().map(|value| sign * value)
+}
+
+/// Plays the targets' sounds. The runtime tells it what to start and stop,
+/// and waits for "play sound until done" by the duration it hands back.
+pub trait SoundPlayer {
+    /// Starts the sound at `sound` of `target` at the time `now`, with the
+    /// volume and effects in `state`. If `target` is already playing it, it
+    /// starts over. Returns how long it will play for, in milliseconds.
+    fn play(&mut self, target: u32, sound: usize, state: &SoundState, now: f64) -> f64;
+
+    /// Stops every sound `target` is playing, or every sound at all if it's
+    /// `None`.
+    fn stop(&mut self, target: Option<u32>);
+
+    /// Called at the end of a tick when `target`'s volume or effects changed,
+    /// so the sounds it's playing can change with them.
+    fn update(&mut self, _target: u32, _state: &SoundState) {}
+}
+
+/// A sound that's playing, until the time `until` (in milliseconds).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playback {
+    pub target: u32,
+    pub sound: usize,
+    pub until: f64,
+}
+
+/// A sound player that plays nothing, but keeps track of which sounds would
+/// be playing from their sample counts and rates.
+#[derive(Default)]
+pub struct HeadlessSoundPlayer {
+    pub playing: Vec<Playback>,
+}
+
+impl HeadlessSoundPlayer {
+    /// Whether `target` is playing the sound at `sound` at the time `now`.
+    pub fn is_playing(&self, target: u32, sound: usize, now: f64) -> bool {
+        self.playing.iter().any(|playback| {
+            playback.target == target && playback.sound == sound && playback.until > now
+        })
+    }
+}
+
+impl SoundPlayer for HeadlessSoundPlayer {
+    fn play(&mut self, target: u32, sound: usize, state: &SoundState, now: f64) -> f64 {
+        // Forget the sounds that have finished, and this one if it's playing
+        self.playing.retain(|playback| {
+            playback.until > now && (playback.target, playback.sound) != (target, sound)
+        });
+        let duration = state.duration(sound);
+        self.playing.push(Playback {
+            target,
+            sound,
+            until: now + duration,
+        });
+        duration
+    }
+
+    fn stop(&mut self, target: Option<u32>) {
+        self.playing
+            .retain(|playback| target.is_some_and(|target| playback.target != target));
+    }
+}
+
+/// Plays sounds by calling the methods of a JS object: `play(target, sound,
+/// volume, pitch, pan)`, `stop(target)` (with `undefined` for every target)
+/// and `update(target, volume, pitch, pan)`. Methods it doesn't have are
+/// skipped. How long sounds play for comes from their sample counts, like
+/// `HeadlessSoundPlayer`.
+pub struct JsSoundPlayer {
+    pub player: JsValue,
+}
+
+impl JsSoundPlayer {
+    fn call(&self, method: &str, arguments: &[JsValue]) {
+        let method = match Reflect::get(&self.player, &JsValue::from_str(method))
+            .ok()
+            .and_then(|method| method.dyn_into::<Function>().ok())
+        {
+            Some(method) => method,
+            None => return,
+        };
+        let arguments = arguments.iter().collect::<Array>();
+        if let Err(exception) = method.apply(&self.player, &arguments) {
+            console::error_1(&exception);
+        }
+    }
+}
+
+/// The volume and effects, as arguments for `JsSoundPlayer`'s methods.
+fn state_arguments(state: &SoundState) -> [JsValue; 3] {
+    [
+        JsValue::from_f64(state.volume),
+        JsValue::from_f64(state.effects[SoundEffect::Pitch as usize]),
+        JsValue::from_f64(state.effects[SoundEffect::Pan as usize]),
+    ]
+}
+
+impl SoundPlayer for JsSoundPlayer {
+    fn play(&mut self, target: u32, sound: usize, state: &SoundState, _now: f64) -> f64 {
+        let [volume, pitch, pan] = state_arguments(state);
+        self.call(
+            "play",
+            &[
+                JsValue::from_f64(target as f64),
+                JsValue::from_f64(sound as f64),
+                volume,
+                pitch,
+                pan,
+            ],
+        );
+        state.duration(sound)
+    }
+
+    fn stop(&mut self, target: Option<u32>) {
+        let target = target.map_or(JsValue::UNDEFINED, |target| {
+            JsValue::from_f64(target as f64)
+        });
+        self.call("stop", &[target]);
+    }
+
+    fn update(&mut self, target: u32, state: &SoundState) {
+        let [volume, pitch, pan] = state_arguments(state);
+        self.call(
+            "update",
+            &[JsValue::from_f64(target as f64), volume, pitch, pan],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sounds(names: &[&str]) -> SoundState {
+        SoundState {
+            sounds: names
+                .iter()
+                .map(|name| Sound {
+                    name: name.to_string(),
+                    sample_count: 22050,
+                    rate: 44100,
+                })
+                .collect(),
+            ..SoundState::default()
+        }
+    }
+
+    #[test]
+    fn sound_index_fallbacks() {
+        let state = sounds(&["meow", "2", "pop"]);
+        let index = |value: ScratchValue| state.sound_index(&value);
+        assert_eq!(index(ScratchValue::String("pop".into())), Some(2));
+        // Names win over indices, but only for strings
+        assert_eq!(index(ScratchValue::String("2".into())), Some(1));
+        assert_eq!(index(ScratchValue::Number(2.0)), Some(1));
+        assert_eq!(index(ScratchValue::Number(3.9)), Some(2));
+        assert_eq!(index(ScratchValue::String(" 4 sounds".into())), Some(0));
+        assert_eq!(index(ScratchValue::Number(0.0)), Some(2));
+        assert_eq!(index(ScratchValue::String("woof".into())), None);
+        assert_eq!(index(ScratchValue::Boolean(true)), None);
+        assert_eq!(
+            SoundState::default().sound_index(&ScratchValue::Number(1.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn durations_follow_the_pitch() {
+        let mut state = sounds(&["meow"]);
+        assert_eq!(state.duration(0), 500.0);
+        state.set_effect(SoundEffect::Pitch, 120.0);
+        assert_eq!(state.duration(0), 250.0);
+        state.set_effect(SoundEffect::Pitch, -1000.0);
+        assert_eq!(state.effects, [-360.0, 0.0]);
+        assert_eq!(state.duration(0), 4000.0);
+        assert_eq!(state.duration(1), 0.0);
+    }
+
+    #[test]
+    fn headless_player_tracks_playing_sounds() {
+        let state = sounds(&["meow", "pop"]);
+        let mut player = HeadlessSoundPlayer::default();
+        assert_eq!(player.play(1, 0, &state, 0.0), 500.0);
+        player.play(2, 1, &state, 100.0);
+        assert!(player.is_playing(1, 0, 499.0));
+        assert!(!player.is_playing(1, 0, 500.0));
+        // Playing a sound again starts it over
+        player.play(1, 0, &state, 400.0);
+        assert_eq!(player.playing.len(), 2);
+        assert!(player.is_playing(1, 0, 800.0));
+        player.stop(Some(1));
+        assert!(!player.is_playing(1, 0, 450.0));
+        assert!(player.is_playing(2, 1, 450.0));
+        player.stop(None);
+        assert!(player.playing.is_empty());
+    }
+}
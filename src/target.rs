@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+use crate::bubble::Bubble;
+use crate::hat::HatScript;
+use crate::looks::Looks;
+use crate::motion::Motion;
+use crate::pen::PenState;
+use crate::sound::SoundState;
+
+/// A sprite, a clone of one, or the stage. Threads belong to a target, which
+/// is what "stop other scripts in sprite" and clone deletion act on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub id: u32,
+    pub name: String,
+    pub is_stage: bool,
+    /// The ID of the sprite this was cloned from, if it is a clone.
+    pub clone_of: Option<u32>,
+    /// Where the sprite is. The stage has one too, but it never moves.
+    pub motion: Motion,
+    pub looks: Looks,
+    pub bubble: Bubble,
+    /// The pen, which clones start with a copy of.
+    pub pen: PenState,
+    /// The sounds and how they play, which clones start with a copy of.
+    pub sound: SoundState,
+    /// The scripts that start when something happens, like a key being
+    /// pressed. Clones have the same ones.
+    pub hats: Vec<HatScript>,
+    /// The target's own variables (the global ones, for the stage) by name,
+    /// as indices into the variables, so other sprites can read them with the
+    /// "of" block.
+    pub variables: Vec<(String, u32)>,
+}
+
+impl Target {
+    pub fn new(id: u32, name: String, is_stage: bool) -> Self {
+        Self {
+            id,
+            name,
+            is_stage,
+            clone_of: None,
+            motion: Motion::default(),
+            looks: Looks::default(),
+            bubble: Bubble::default(),
+            pen: PenState::default(),
+            sound: SoundState::default(),
+            hats: Vec::new(),
+            variables: Vec::new(),
+        }
+    }
+
+    pub fn is_clone(&self) -> bool {
+        self.clone_of.is_some()
+    }
+
+    /// The index of the target's variable called `name`, if it has one.
+    pub fn variable(&self, name: &str) -> Option<u32> {
+        self.variables
+            .iter()
+            .find(|(variable, _)| variable == name)
+            .map(|(_, index)| *index)
+    }
+}
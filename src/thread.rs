@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::extension::BlockType;
+use crate::motion::Glide;
+use crate::scratch_value::ScratchValue;
+
+/// What a thread is doing between ticks of the scheduler.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ThreadState {
+    /// Will run on the next tick.
+    Running,
+    /// Gave up the rest of this tick (e.g. at the end of a loop) and will run
+    /// again on the next one.
+    Yielded,
+    /// Parked by `WAIT` until the clock reaches `until` (in milliseconds).
+    WaitingOnTimer { until: f64 },
+    /// Parked by a timed "say" or "think" until the clock reaches `until`,
+    /// when the bubble is cleared if it's still the one with `usage`.
+    Speaking { until: f64, usage: u32 },
+    /// Gliding the sprite somewhere with `MOTION_GLIDE`.
+    Gliding(Glide),
+    /// Parked by `WAIT_UNTIL`; the condition is re-evaluated every tick.
+    WaitingOnCondition,
+    /// Parked by an awaited `CALL_HOST` or an extension block until the host
+    /// resolves the promise. What happens to the value depends on what kind
+    /// of block it was, like when there's no promise.
+    WaitingOnHostPromise { block_type: BlockType },
+    /// Parked by "ask and wait" until the host answers the thread's question.
+    WaitingOnAnswer,
+    /// Parked by "play sound until done" until the clock reaches `until`, or
+    /// the target's sound at `sound` is stopped or started over.
+    WaitingOnSound { until: f64, sound: usize },
+    /// Stopped by the debugger until the runtime is resumed.
+    Paused,
+    /// Done, and will be removed at the end of the tick.
+    Finished,
+}
+
+/// A running script: its own program counter and stack, sharing the stores
+/// with every other thread.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: u32,
+    /// The ID of the target this thread belongs to.
+    pub target: u32,
+    /// Where the thread's script starts, which hats use to tell whether the
+    /// script is already running.
+    pub script: usize,
+    pub program_counter: usize,
+    pub stack: Vec<ScratchValue>,
+    pub state: ThreadState,
+}
+
+impl Thread {
+    pub fn new(id: u32, target: u32, program_counter: usize) -> Self {
+        Self {
+            id,
+            target,
+            script: program_counter,
+            program_counter,
+            stack: Vec::new(),
+            state: ThreadState::Running,
+        }
+    }
+
+    /// Starts the thread's script over, like when a hat fires again.
+    pub fn restart(&mut self) {
+        self.program_counter = self.script;
+        self.stack.clear();
+        self.state = ThreadState::Running;
+    }
+
+    /// Checks whether the scheduler should run this thread at the time `now`,
+    /// waking it up if it was waiting on a timer that has run out.
+    pub fn wake(&mut self, now: f64) -> bool {
+        match self.state {
+            ThreadState::Running | ThreadState::Yielded | ThreadState::WaitingOnCondition => true,
+            ThreadState::WaitingOnTimer { until } | ThreadState::WaitingOnSound { until, .. } => {
+                until <= now
+            }
+            // The scheduler moves these along itself
+            ThreadState::Gliding(_)
+            | ThreadState::Speaking { .. }
+            | ThreadState::WaitingOnHostPromise { .. }
+            | ThreadState::WaitingOnAnswer
+            | ThreadState::Paused
+            | ThreadState::Finished => false,
+        }
+    }
+}
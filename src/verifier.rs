@@ -0,0 +1,353 @@
+use std::fmt;
+
+use crate::extension::BlockSignature;
+use crate::host::{HOST_CALL_ARGUMENT_COUNT, HOST_CALL_REPORTER};
+use crate::instruction::{Instruction, InstructionType, ReturnReason};
+
+/// Why some bytecode was rejected, and where.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub program_counter: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Verification failed (@{}): {}",
+            self.program_counter, self.message
+        )
+    }
+}
+
+/// The sizes of the stores, so that the arguments of instructions that use
+/// them can be bounds-checked ahead of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreSizes {
+    pub constants: usize,
+    pub variables: usize,
+    pub lists: usize,
+}
+
+/// Where a thread can go after running the instruction at a program counter.
+enum Successors {
+    /// Nowhere; the thread ends.
+    None,
+    One(usize),
+    Two(usize, usize),
+}
+
+/// Works out where a relative jump from `program_counter` lands, the same way
+/// the runner does.
+fn jump_target(
+    instructions: &[Instruction],
+    program_counter: usize,
+) -> Result<usize, &'static str> {
+    let offset = instructions[program_counter].argument as i32 as isize;
+    let target = (program_counter as isize)
+        .checked_add(offset)
+        .filter(|target| *target >= 0)
+        .ok_or("jump target is before the start of the program")? as usize
+        + 1;
+    if target > instructions.len() {
+        return Err("jump target is past the end of the program");
+    }
+    Ok(target)
+}
+
+fn check_index(index: u32, size: Option<usize>, message: &'static str) -> Result<(), &'static str> {
+    match size {
+        Some(size) if index as usize >= size => Err(message),
+        _ => Ok(()),
+    }
+}
+
+/// Statically checks bytecode before it's run, by following every path
+/// through it from each of `entry_points` (which start with an empty stack),
+/// including `JUMP`/`JUMP_IF`/`WAIT_UNTIL` targets. It proves that:
+///
+/// - every instruction has enough operands on the stack,
+/// - the stack height is the same however an instruction is reached, so loops
+///   can't leave garbage on the stack,
+/// - every jump lands on an instruction, not on an `EXTRA_ARG` or outside the
+///   program,
+/// - `EXTRA_ARG`s only appear (and always appear) after instructions that
+///   need them,
+/// - the constant/variable/list IDs are in bounds, if `store_sizes` is given.
+///
+/// Extension blocks are checked against `extensions`, indexed like
+/// `Extensions::signatures`, so they have to be registered first.
+///
+/// Returns the maximum stack height any thread can reach.
+pub fn verify(
+    instructions: &[Instruction],
+    entry_points: &[usize],
+    store_sizes: Option<StoreSizes>,
+    extensions: &[BlockSignature],
+) -> Result<usize, VerifyError> {
+    // The stack height on entry to each instruction, once it's been reached
+    let mut heights: Vec<Option<usize>> = vec![None; instructions.len()];
+    // Which words are `EXTRA_ARG`s belonging to the instruction before them
+    let mut is_extra_arg = vec![false; instructions.len()];
+    for (program_counter, instruction) in instructions.iter().enumerate() {
+        if instruction.name == InstructionType::ExtraArg {
+            let owner = program_counter
+                .checked_sub(1)
+                .map(|owner| instructions[owner].name);
+            if !owner.is_some_and(InstructionType::takes_extra_arg) {
+                return Err(VerifyError {
+                    program_counter,
+                    message: "EXTRA_ARG doesn't follow an instruction that needs one",
+                });
+            }
+            is_extra_arg[program_counter] = true;
+        } else if instruction.name.takes_extra_arg()
+            && instructions.get(program_counter + 1).map(|next| next.name)
+                != Some(InstructionType::ExtraArg)
+        {
+            return Err(VerifyError {
+                program_counter,
+                message: "instruction is missing its EXTRA_ARG",
+            });
+        }
+    }
+
+    let mut max_height = 0;
+    let mut worklist: Vec<(usize, usize)> = Vec::new();
+    for &entry_point in entry_points {
+        worklist.push((entry_point, 0));
+    }
+    while let Some((program_counter, height)) = worklist.pop() {
+        if program_counter == instructions.len() {
+            // Running off the end finishes the thread
+            continue;
+        }
+        let error = |message| VerifyError {
+            program_counter,
+            message,
+        };
+        if program_counter > instructions.len() {
+            return Err(error("entry point is past the end of the program"));
+        }
+        if is_extra_arg[program_counter] {
+            return Err(error("jump lands on an EXTRA_ARG"));
+        }
+        match heights[program_counter] {
+            Some(expected) if expected == height => continue,
+            Some(_) => {
+                return Err(error(
+                    "stack height differs depending on how the instruction is reached",
+                ))
+            }
+            None => heights[program_counter] = Some(height),
+        }
+
+        let instruction = &instructions[program_counter];
+        let (pops, pushes) = match instruction.name {
+            InstructionType::CallHost => {
+                let extra_arg = instructions[program_counter + 1].argument;
+                (
+                    (extra_arg & HOST_CALL_ARGUMENT_COUNT) as usize,
+                    (extra_arg & HOST_CALL_REPORTER > 0) as usize,
+                )
+            }
+            InstructionType::Extension => extensions
+                .get(instruction.argument as usize)
+                .map(BlockSignature::stack_effect)
+                .ok_or_else(|| error("no extension block registered with that opcode"))?,
+            name => name
+                .stack_effect()
+                .ok_or_else(|| error("instruction can't be run"))?,
+        };
+        if pops > height {
+            return Err(error("not enough values on the stack"));
+        }
+        let new_height = height - pops + pushes;
+        max_height = max_height.max(new_height);
+
+        let sizes = store_sizes;
+        let argument = instruction.argument;
+        match instruction.name {
+            InstructionType::LoadConst => check_index(
+                argument,
+                sizes.map(|sizes| sizes.constants),
+                "constant ID out of bounds",
+            ),
+            InstructionType::Load | InstructionType::Store => check_index(
+                argument,
+                sizes.map(|sizes| sizes.variables),
+                "variable ID out of bounds",
+            ),
+            InstructionType::AllocList
+            | InstructionType::ListDel
+            | InstructionType::ListIns
+            | InstructionType::ListDelAll
+            | InstructionType::ListReplace
+            | InstructionType::ListPush
+            | InstructionType::ListLoad
+            | InstructionType::ListLen
+            | InstructionType::ListIFind
+            | InstructionType::ListIIncludes
+            | InstructionType::ListContents => check_index(
+                argument,
+                sizes.map(|sizes| sizes.lists),
+                "list ID out of bounds",
+            ),
+            _ => Ok(()),
+        }
+        .map_err(error)?;
+
+        let next = program_counter
+            + if instruction.name.takes_extra_arg() {
+                2
+            } else {
+                1
+            };
+        let successors = match instruction.name {
+            InstructionType::Jump => {
+                Successors::One(jump_target(instructions, program_counter).map_err(error)?)
+            }
+            InstructionType::JumpIf | InstructionType::WaitUntil => Successors::Two(
+                next,
+                jump_target(instructions, program_counter).map_err(error)?,
+            ),
+            InstructionType::StopAll | InstructionType::StopThisScript => Successors::None,
+            InstructionType::Return
+                if argument == ReturnReason::Finished as u32
+                    || argument == ReturnReason::VisualReport as u32 =>
+            {
+                Successors::None
+            }
+            _ => Successors::One(next),
+        };
+        match successors {
+            Successors::None => {}
+            Successors::One(a) => worklist.push((a, new_height)),
+            Successors::Two(a, b) => {
+                worklist.push((a, new_height));
+                worklist.push((b, new_height));
+            }
+        }
+    }
+    Ok(max_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transmute_instructions;
+
+    use super::*;
+
+    #[test]
+    fn test_verify_loop() {
+        // repeat until (my variable) { change (my variable) by 1 }
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000000000003u64, // LOAD 0
+                0x0000000500000006u64, // JUMP_IF 5
+                0x0000000000000003u64, // LOAD 0
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000008u64, // OP_ADD
+                0x0000000000000004u64, // STORE 0
+                0xfffffff900000005u64, // JUMP -7
+            ])
+        };
+        let sizes = StoreSizes {
+            constants: 0,
+            variables: 1,
+            lists: 0,
+        };
+        assert_eq!(verify(instructions, &[0], Some(sizes), &[]), Ok(2));
+    }
+
+    #[test]
+    fn test_verify_garbage_in_loop() {
+        // The loop body leaves a value on the stack every iteration
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0xfffffffe00000005u64, // JUMP -2
+            ])
+        };
+        assert_eq!(
+            verify(instructions, &[0], None, &[])
+                .unwrap_err()
+                .program_counter,
+            1
+        );
+    }
+
+    #[test]
+    fn test_verify_underflow() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x0000000000000008u64, // OP_ADD
+            ])
+        };
+        assert_eq!(
+            verify(instructions, &[0], None, &[]),
+            Err(VerifyError {
+                program_counter: 1,
+                message: "not enough values on the stack"
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_jump_into_extra_arg() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000100000005u64, // JUMP 1
+                0x0000000000000007u64, // ALLOC_LIST 0
+                0x0000000a00000001u64, // EXTRA_ARG 10
+            ])
+        };
+        assert_eq!(
+            verify(instructions, &[0], None, &[]),
+            Err(VerifyError {
+                program_counter: 2,
+                message: "jump lands on an EXTRA_ARG"
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_jump_out_of_bounds() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x0000000000000000u64, // NOOP
+                0x0000000500000005u64, // JUMP 5
+            ])
+        };
+        assert_eq!(
+            verify(instructions, &[0], None, &[]).unwrap_err().message,
+            "jump target is past the end of the program"
+        );
+    }
+
+    #[test]
+    fn test_verify_extension_blocks() {
+        let instructions = unsafe {
+            transmute_instructions(&[
+                0x000000010000003cu64, // LOAD_CONST_INT 1
+                0x000000020000003cu64, // LOAD_CONST_INT 2
+                0x0000000000008000u64, // EXTENSION 0
+            ])
+        };
+        let join = BlockSignature {
+            extension: "test".into(),
+            block: "join".into(),
+            arity: 2,
+            block_type: crate::extension::BlockType::Reporter,
+        };
+        assert_eq!(verify(instructions, &[0], None, &[join]), Ok(2));
+        assert_eq!(
+            verify(instructions, &[0], None, &[]).unwrap_err().message,
+            "no extension block registered with that opcode"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Runs the `scratch-vm-run` binary on the programs in `tests/cli/`.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::process::Command;
+
+fn run(arguments: &[&str]) -> (bool, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_scratch-vm-run"))
+        .args(arguments)
+        .output()
+        .expect("the binary should run");
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn prints_bubbles_monitors_and_final_state() {
+    let (success, stdout, stderr) = run(&["tests/cli/count.json"]);
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "say: Hello!",
+            "monitor score: 1",
+            "monitor score: 2",
+            "monitor score: 3",
+            "think: Done",
+            r#"{"lists":{"scores":[1,2,3]},"variables":{"score":3}}"#,
+        ]
+    );
+}
+
+#[test]
+fn prints_bubbles_from_the_looks_blocks() {
+    let (success, stdout, stderr) = run(&["tests/cli/bubbles.json"]);
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "say: Hi!",
+            "think: Hmm...",
+            r#"{"lists":{},"variables":{}}"#,
+        ]
+    );
+}
+
+#[test]
+fn stops_after_max_ticks() {
+    let (success, stdout, stderr) = run(&["--max-ticks", "2", "tests/cli/count.json"]);
+    assert!(success);
+    assert!(stdout.ends_with("{\"lists\":{\"scores\":[1,2]},\"variables\":{\"score\":2}}\n"));
+    assert!(stderr.contains("still running"), "{}", stderr);
+}
+
+#[test]
+fn turbo_mode_runs_more_per_tick() {
+    let (success, stdout, stderr) = run(&["--turbo", "--max-ticks", "1", "tests/cli/count.json"]);
+    assert!(success, "{}", stderr);
+    // Every turn of the loop happens in the first tick, and the monitors
+    // are printed at the end of it
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [
+            "say: Hello!",
+            "think: Done",
+            "monitor score: 3",
+            r#"{"lists":{"scores":[1,2,3]},"variables":{"score":3}}"#,
+        ]
+    );
+    assert!(!stderr.contains("still running"), "{}", stderr);
+}
+
+#[test]
+fn fails_without_a_compiler_for_projects() {
+    let (success, _, stderr) = run(&["project.sb3"]);
+    assert!(!success);
+    assert!(stderr.contains("SCRATCH_VM_COMPILER"), "{}", stderr);
+}
@@ -0,0 +1,661 @@
+//! Runs the cases in `tests/conformance/` and checks that the interpreter ends
+//! up in the same state as scratch-vm would. Each file is a JSON array of
+//! cases:
+//!
+//! ```json
+//! {
+//!   "name": "what the case checks",
+//!   "program": ["LOAD_CONST_INT 1", "STORE 0"],
+//!   "constants": [], "variables": [0], "lists": [], "stack": [],
+//!   "expect": { "variables": [1] }
+//! }
+//! ```
+//!
+//! The program is assembled with `assembler::assemble`. Only the keys given
+//! in `expect` are checked:
+//!
+//! - `stack`, `variables` and `lists`: the stores afterwards.
+//! - `return_reason` and `program_counter`: where the runner stopped.
+//! - `error`: a substring of the error message the program fails with.
+//! - `monitors`: the indices of the `variables` and `lists` whose monitors
+//!   are visible.
+//! - `sprite`: any of the sprite's `x`, `y`, `direction`, `rotation_style`,
+//!   `costume`, `size`, `visible`, `layer`, `effects` and `bubble`.
+//! - `pen`: any of the sprite's pen's `down`, `color`, `saturation`,
+//!   `brightness`, `transparency` and `size`, and `pixels` on the pen layer
+//!   as `[x, y, [r, g, b, a]]`.
+//! - `sound`: any of the sprite's `volume`, `pitch` and `pan`.
+//!
+//! Values are JSON booleans, numbers and strings, `{"number": "NaN"}` (or
+//! `"Infinity"`/`"-Infinity"`) for numbers JSON can't express, and
+//! `{"between": [min, max]}` for numbers that depend on the time zone.
+//!
+//! The program runs as a sprite at (0, 0) pointing right, unless a case
+//! gives its own `sprite` with any of `x`, `y`, `direction`,
+//! `rotation_style`, `bounds` (`[left, right, bottom, top]` relative to its
+//! position), `costumes` (names), `costume_size` (`[width, height]`),
+//! `costume`, `size`, `visible`, `is_stage` and `variable_names` (the names
+//! of the first few `variables`, which are its own). No keys are held down and
+//! the mouse is at (0, 0) and up, unless a case gives its own `io` like
+//! `{"keyboard": {"pressed": ["space"]}, "mouse": {"x": 10, "y": 0, "down": true}}`.
+//!
+//! The clock reads `clock` (in milliseconds since 1970, 0 if not given), and
+//! random numbers come from a generator with a fixed seed. The host used for
+//! `CALL_HOST` reports its arguments joined by spaces for id 0, and returns a
+//! pending promise for id 1. The extension blocks are:
+//!
+//! - `EXTENSION 0`, a reporter that joins its two arguments the same way.
+//! - `EXTENSION 1`, a Boolean that hands back its one argument.
+//! - `EXTENSION 2`, a command that takes one argument (and gives back
+//!   something that's dropped).
+//! - `EXTENSION 3`, a command that returns a pending promise.
+
+use std::convert::TryFrom;
+
+use scratch_vm_wasm_runtime::{
+    assembler::assemble,
+    clock::ManualClock,
+    extension::{BlockSignature, BlockType, ExtendedHost, ExtensionHandler, Extensions},
+    host::HostCallResult,
+    instruction::{InstructionType, ReturnReason},
+    io::IoDevices,
+    limits::lists_size,
+    looks::{Costume, GraphicEffect},
+    motion::{Bounds, RotationStyle},
+    runner::{run_instructions, Budget, StandaloneContext},
+    scratch_value::ScratchValue,
+    sound::SoundEffect,
+    target::Target,
+};
+use serde::Deserialize;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::*;
+
+#[cfg(target_arch = "wasm32")]
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// Enough for every case; anything that runs out is stuck in a loop.
+const INSTRUCTION_LIMIT: u64 = 100_000;
+
+const SUITES: &[(&str, &str)] = &[
+    ("casts", include_str!("conformance/casts.json")),
+    ("control", include_str!("conformance/control.json")),
+    ("data", include_str!("conformance/data.json")),
+    ("lists", include_str!("conformance/lists.json")),
+    ("looks", include_str!("conformance/looks.json")),
+    ("operators", include_str!("conformance/operators.json")),
+    ("motion", include_str!("conformance/motion.json")),
+    ("pen", include_str!("conformance/pen.json")),
+    ("sensing", include_str!("conformance/sensing.json")),
+    ("sound", include_str!("conformance/sound.json")),
+    ("strings", include_str!("conformance/strings.json")),
+    ("time", include_str!("conformance/time.json")),
+];
+
+/// Instructions that no case has to cover, because they can't be run yet.
+const UNCOVERED: &[InstructionType] = &[InstructionType::Reserved];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Value {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Special { number: String },
+    Between { between: [f64; 2] },
+}
+
+impl Value {
+    fn to_scratch(&self) -> ScratchValue {
+        match self {
+            Value::Boolean(value) => ScratchValue::Boolean(*value),
+            Value::Number(value) => ScratchValue::Number(*value),
+            Value::String(value) => ScratchValue::String(value.clone()),
+            Value::Special { number } => ScratchValue::Number(match number.as_str() {
+                "NaN" => f64::NAN,
+                "Infinity" => f64::INFINITY,
+                "-Infinity" => f64::NEG_INFINITY,
+                _ => panic!("unknown special number {}", number),
+            }),
+            Value::Between { .. } => panic!("`between` can only be used in `expect`"),
+        }
+    }
+
+    fn matches(&self, actual: &ScratchValue) -> bool {
+        match (self, actual) {
+            (
+                Value::Between {
+                    between: [min, max],
+                },
+                ScratchValue::Number(actual),
+            ) => min <= actual && actual <= max,
+            (Value::Between { .. }, _) => false,
+            (_, ScratchValue::Number(actual)) if actual.is_nan() => {
+                matches!(self.to_scratch(), ScratchValue::Number(expected) if expected.is_nan())
+            }
+            _ => self.to_scratch() == *actual,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Expect {
+    stack: Option<Vec<Value>>,
+    variables: Option<Vec<Value>>,
+    lists: Option<Vec<Vec<Value>>>,
+    return_reason: Option<u32>,
+    program_counter: Option<usize>,
+    error: Option<String>,
+    monitors: Option<ExpectMonitors>,
+    sprite: Option<ExpectSprite>,
+    pen: Option<ExpectPen>,
+    sound: Option<ExpectSound>,
+}
+
+/// The indices of the variables and lists whose monitors should be visible.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ExpectMonitors {
+    #[serde(default)]
+    variables: Vec<u32>,
+    #[serde(default)]
+    lists: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ExpectSprite {
+    x: Option<f64>,
+    y: Option<f64>,
+    direction: Option<f64>,
+    rotation_style: Option<u32>,
+    costume: Option<usize>,
+    size: Option<f64>,
+    visible: Option<bool>,
+    layer: Option<u32>,
+    effects: Option<[f64; GraphicEffect::COUNT]>,
+    /// `["say" or "think", text]`
+    bubble: Option<[String; 2]>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ExpectPen {
+    down: Option<bool>,
+    color: Option<f64>,
+    saturation: Option<f64>,
+    brightness: Option<f64>,
+    transparency: Option<f64>,
+    size: Option<f64>,
+    /// Points on the stage and the pixels under them.
+    #[serde(default)]
+    pixels: Vec<(f64, f64, [u8; 4])>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ExpectSound {
+    volume: Option<f64>,
+    pitch: Option<f64>,
+    pan: Option<f64>,
+}
+
+/// The target the program runs as.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Sprite {
+    x: f64,
+    y: f64,
+    direction: f64,
+    rotation_style: u32,
+    bounds: [f64; 4],
+    /// The costumes' names, which are all `costume_size` big.
+    costumes: Vec<String>,
+    costume_size: [f64; 2],
+    costume: usize,
+    size: f64,
+    visible: bool,
+    is_stage: bool,
+    variable_names: Vec<String>,
+}
+
+impl Default for Sprite {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            direction: 90.0,
+            rotation_style: 0,
+            bounds: [0.0; 4],
+            costumes: Vec::new(),
+            costume_size: [0.0; 2],
+            costume: 0,
+            size: 100.0,
+            visible: true,
+            is_stage: false,
+            variable_names: Vec::new(),
+        }
+    }
+}
+
+impl Sprite {
+    fn to_target(&self) -> Target {
+        let mut target = Target::new(0, "Sprite1".into(), self.is_stage);
+        let motion = &mut target.motion;
+        motion.x = self.x;
+        motion.y = self.y;
+        motion.direction = self.direction;
+        motion.rotation_style =
+            RotationStyle::try_from(self.rotation_style).expect("rotation style should be valid");
+        let [left, right, bottom, top] = self.bounds;
+        motion.bounds = Bounds {
+            left,
+            right,
+            bottom,
+            top,
+        };
+        let looks = &mut target.looks;
+        let [width, height] = self.costume_size;
+        looks.costumes = self
+            .costumes
+            .iter()
+            .map(|name| Costume {
+                name: name.clone(),
+                width,
+                height,
+            })
+            .collect();
+        looks.costume = self.costume;
+        looks.size = self.size;
+        looks.visible = self.visible;
+        target.variables = (0..)
+            .zip(&self.variable_names)
+            .map(|(index, name)| (name.clone(), index))
+            .collect();
+        target
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    name: String,
+    program: Vec<String>,
+    #[serde(default)]
+    constants: Vec<Value>,
+    #[serde(default)]
+    variables: Vec<Value>,
+    #[serde(default)]
+    lists: Vec<Vec<Value>>,
+    #[serde(default)]
+    stack: Vec<Value>,
+    #[serde(default)]
+    program_counter: usize,
+    #[serde(default)]
+    sprite: Sprite,
+    #[serde(default)]
+    io: IoDevices,
+    #[serde(default)]
+    clock: f64,
+    expect: Expect,
+}
+
+fn load_suite(source: &str) -> Vec<Case> {
+    serde_json::from_str(source).expect("conformance cases should be valid JSON")
+}
+
+fn check_values(what: &str, expected: &[Value], actual: &[ScratchValue]) -> Result<(), String> {
+    if expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| expected.matches(actual))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}: expected {:?}, got {:?}",
+            what, expected, actual
+        ))
+    }
+}
+
+/// The blocks of the `test` extension, in the order they're registered.
+fn test_extensions() -> Extensions {
+    let join = |arguments: Vec<ScratchValue>| {
+        Ok(HostCallResult::Value(ScratchValue::String(
+            arguments
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+                .join(" "),
+        )))
+    };
+    let echo = |mut arguments: Vec<ScratchValue>| Ok(HostCallResult::Value(arguments.remove(0)));
+    let drop = |_| Ok(HostCallResult::Value(ScratchValue::Number(1.0)));
+    let wait = |_| Ok(HostCallResult::Pending);
+    let blocks: [(&str, u16, BlockType, Box<dyn ExtensionHandler>); 4] = [
+        ("join", 2, BlockType::Reporter, Box::new(join)),
+        ("echo", 1, BlockType::Boolean, Box::new(echo)),
+        ("drop", 1, BlockType::Command, Box::new(drop)),
+        ("wait", 0, BlockType::Command, Box::new(wait)),
+    ];
+    let mut extensions = Extensions::default();
+    for (block, arity, block_type, handler) in blocks {
+        let signature = BlockSignature {
+            extension: "test".into(),
+            block: block.into(),
+            arity,
+            block_type,
+        };
+        extensions
+            .register(signature, handler)
+            .expect("there should be room for the test extension");
+    }
+    extensions
+}
+
+fn run_case(case: &Case) -> Result<(), String> {
+    let scratch = |values: &[Value]| values.iter().map(Value::to_scratch).collect::<Vec<_>>();
+    let instructions = assemble(&case.program.join("\n")).map_err(|err| err.to_string())?;
+    let constants = scratch(&case.constants);
+    let mut variables = scratch(&case.variables);
+    let mut lists = case
+        .lists
+        .iter()
+        .map(|list| scratch(list))
+        .collect::<Vec<_>>();
+    let mut stack = scratch(&case.stack);
+    let mut program_counter = case.program_counter;
+    let mut context = StandaloneContext {
+        list_size: lists_size(&lists),
+        targets: vec![case.sprite.to_target()],
+        current_target: Some(0),
+        io: case.io.clone(),
+        clock: ManualClock::new(case.clock),
+        ..StandaloneContext::default()
+    };
+    let mut host = |id: u32, arguments: Vec<ScratchValue>| match id {
+        0 => Ok(HostCallResult::Value(ScratchValue::String(
+            arguments
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ))),
+        1 => Ok(HostCallResult::Pending),
+        _ => Err("unknown host function"),
+    };
+    let mut extensions = test_extensions();
+    let result = run_instructions(
+        &mut program_counter,
+        &mut stack,
+        &instructions,
+        &constants,
+        &mut variables,
+        &mut lists,
+        &mut ExtendedHost {
+            host: &mut host,
+            extensions: &mut extensions,
+        },
+        &mut Budget::new(Some(INSTRUCTION_LIMIT), None),
+        &mut context.context(),
+        None,
+    );
+    let StandaloneContext {
+        monitors,
+        targets,
+        pen_layer,
+        ..
+    } = context;
+
+    let expect = &case.expect;
+    let return_reason = match (result, &expect.error) {
+        (Err(err), Some(expected)) if err.contains(expected.as_str()) => return Ok(()),
+        (Err(err), _) => return Err(format!("failed: {}", err)),
+        (Ok(_), Some(expected)) => return Err(format!("expected error {:?}", expected)),
+        (Ok(return_reason), None) => return_reason.unwrap_or(ReturnReason::Finished as u32),
+    };
+    if return_reason == ReturnReason::BudgetExhausted as u32 {
+        return Err("ran out of instructions".into());
+    }
+    if let Some(expected) = expect.return_reason {
+        if expected != return_reason {
+            return Err(format!(
+                "return reason: expected {}, got {}",
+                expected, return_reason
+            ));
+        }
+    }
+    if let Some(expected) = expect.program_counter {
+        if expected != program_counter {
+            return Err(format!(
+                "program counter: expected {}, got {}",
+                expected, program_counter
+            ));
+        }
+    }
+    if let Some(expected) = &expect.stack {
+        check_values("stack", expected, &stack)?;
+    }
+    if let Some(expected) = &expect.variables {
+        check_values("variables", expected, &variables)?;
+    }
+    if let Some(expected) = &expect.lists {
+        if expected.len() != lists.len() {
+            return Err(format!("lists: expected {:?}, got {:?}", expected, lists));
+        }
+        for (index, (expected, actual)) in expected.iter().zip(&lists).enumerate() {
+            check_values(&format!("list {}", index), expected, actual)?;
+        }
+    }
+    if let Some(expected) = &expect.monitors {
+        let variables = monitors.variables.iter().copied().collect::<Vec<_>>();
+        let lists = monitors.lists.iter().copied().collect::<Vec<_>>();
+        if expected.variables != variables || expected.lists != lists {
+            return Err(format!(
+                "monitors: expected {:?}, got {:?}",
+                expected, monitors
+            ));
+        }
+    }
+    if let Some(expected) = &expect.sprite {
+        let (motion, looks) = (&targets[0].motion, &targets[0].looks);
+        let actual = [
+            motion.x,
+            motion.y,
+            motion.direction,
+            motion.rotation_style as u32 as f64,
+            looks.costume as f64,
+            looks.size,
+            looks.visible as u8 as f64,
+            looks.layer as f64,
+        ];
+        let expected_values = [
+            expected.x,
+            expected.y,
+            expected.direction,
+            expected.rotation_style.map(f64::from),
+            expected.costume.map(|costume| costume as f64),
+            expected.size,
+            expected.visible.map(|visible| visible as u8 as f64),
+            expected.layer.map(f64::from),
+        ];
+        if expected_values
+            .iter()
+            .zip(actual)
+            .any(|(expected, actual)| expected.is_some_and(|expected| expected != actual))
+            || expected
+                .effects
+                .is_some_and(|effects| effects != looks.effects)
+            || expected.bubble.as_ref().is_some_and(|[kind, text]| {
+                let bubble = &targets[0].bubble;
+                kind != bubble.kind.name() || *text != bubble.text
+            })
+        {
+            return Err(format!(
+                "sprite: expected {:?}, got {:?}",
+                expected, targets[0]
+            ));
+        }
+    }
+    if let Some(expected) = &expect.pen {
+        let pen = &targets[0].pen;
+        let actual = [
+            pen.down as u8 as f64,
+            pen.color,
+            pen.saturation,
+            pen.brightness,
+            pen.transparency,
+            pen.size,
+        ];
+        let expected_values = [
+            expected.down.map(|down| down as u8 as f64),
+            expected.color,
+            expected.saturation,
+            expected.brightness,
+            expected.transparency,
+            expected.size,
+        ];
+        if expected_values
+            .iter()
+            .zip(actual)
+            .any(|(expected, actual)| expected.is_some_and(|expected| expected != actual))
+        {
+            return Err(format!("pen: expected {:?}, got {:?}", expected, pen));
+        }
+        for &(x, y, pixel) in expected.pixels.iter() {
+            let actual = pen_layer.pixel_at(x, y);
+            if actual != Some(pixel) {
+                return Err(format!(
+                    "pen layer at ({}, {}): expected {:?}, got {:?}",
+                    x, y, pixel, actual
+                ));
+            }
+        }
+    }
+    if let Some(expected) = &expect.sound {
+        let sound = &targets[0].sound;
+        let actual = [
+            sound.volume,
+            sound.effects[SoundEffect::Pitch as usize],
+            sound.effects[SoundEffect::Pan as usize],
+        ];
+        let expected_values = [expected.volume, expected.pitch, expected.pan];
+        if expected_values
+            .iter()
+            .zip(actual)
+            .any(|(expected, actual)| expected.is_some_and(|expected| expected != actual))
+        {
+            return Err(format!("sound: expected {:?}, got {:?}", expected, sound));
+        }
+    }
+    Ok(())
+}
+
+fn run_suite(suite: &str) {
+    let (_, source) = SUITES
+        .iter()
+        .find(|(name, _)| *name == suite)
+        .expect("suite should exist");
+    let failures = load_suite(source)
+        .iter()
+        .filter_map(|case| {
+            run_case(case)
+                .err()
+                .map(|err| format!("{}: {}", case.name, err))
+        })
+        .collect::<Vec<_>>();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn casts() {
+    run_suite("casts");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn control() {
+    run_suite("control");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn data() {
+    run_suite("data");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn lists() {
+    run_suite("lists");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn operators() {
+    run_suite("operators");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn looks() {
+    run_suite("looks");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn motion() {
+    run_suite("motion");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn pen() {
+    run_suite("pen");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn sensing() {
+    run_suite("sensing");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn sound() {
+    run_suite("sound");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn strings() {
+    run_suite("strings");
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn time() {
+    run_suite("time");
+}
+
+/// Every instruction that can run should be exercised by at least one case.
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn every_instruction_is_covered() {
+    let covered = SUITES
+        .iter()
+        .flat_map(|(_, source)| load_suite(source))
+        .flat_map(|case| assemble(&case.program.join("\n")).unwrap_or_default())
+        .map(|instruction| instruction.name)
+        .collect::<Vec<_>>();
+    let missing = InstructionType::ALL
+        .iter()
+        .filter(|instruction| !UNCOVERED.contains(instruction) && !covered.contains(instruction))
+        .map(|instruction| instruction.mnemonic())
+        .collect::<Vec<_>>();
+    assert!(missing.is_empty(), "no cases for {:?}", missing);
+}